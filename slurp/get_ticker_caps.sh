@@ -1,14 +1,18 @@
 #!/bin/bash
 
+set -euo pipefail
+
+# Refresh the large-cap universe from companiesmarketcap.com. Symbol renames (BRK-B => BRK B, ...)
+# happen on import; add more with `slurp universe map SYMBOL [TICKER]`.
+
 if [[ ! -f '.raw.csv' ]]; then
   curl https://companiesmarketcap.com/usa/largest-companies-in-the-usa-by-market-cap/?download=csv -o .raw.csv
 fi
 
-< .raw.csv python3 -c '
-import csv, json, sys; 
-print(json.dumps([dict(r) for r in csv.DictReader(sys.stdin)]))' | 
-  jq -r '.[1:] | .[] | select(.marketcap | tonumber > 1000000000) | "\(.Symbol)\t\(.marketcap)"' |
-    sed -r '/^ACC/d;s/^BRK-B/BRK B/;s/TVTY/NLSN/;s/^BF-A/BF A/' | # IKBR-specific filter/renames
-    tee ticker_mkt_cap.tsv |
-    cut -d $'\t' -f1 | sort |
-    comm -23 - unavailable.conf > tickers.list
+# the download's first row has always been left out
+cargo run --release -- universe import large-cap <(sed 2d .raw.csv) --min-cap 1000000000
+
+# for anything still reading the old files
+cargo run --release -- universe show large-cap | tail -n +2 |
+  tee ticker_mkt_cap.tsv |
+  cut -d $'\t' -f1 | sort > tickers.list
@@ -1,20 +1,17 @@
 #!/bin/bash
 
-set -uo pipefail
+set -euo pipefail
 
-# Worrkaround for populating the DB when it seems to silently do nothing
-# Likely bug is TWS client isn't seeing messages from TWS
+# Fetch every ticker in tickers.list that isn't in the DB yet, in a single pass: slurp's request
+# scheduler keeps under IB's pacing limits, so there's no need to loop on a timeout. Transient
+# errors are retried by the scheduler; tickers that still fail are listed by `slurp failures`.
 
-tofetch="$(mktemp -p /tmp tofetch_XXX)"
-todo() {
-  comm -13 <(sqlite3 ~/.local/stonks/db.sqlite3 'SELECT DISTINCT(ticker) FROM daily' | sort) tickers.list
-}
+db="$(cargo run -q --release -- "$@" db path)"
 
-todo > "$tofetch"
-while [[ -s "$tofetch" ]]; do
-  echo "$(wc -l /tmp/tofetch.list) tickers" >&2
-  timeout --foreground 120 </tmp/tofetch.list cargo run --release -- "$@" full
-  exit_code=$?
-  echo "exit code: $exit_code" >&2
-  todo > "$tofetch"
-done
+missing="$(comm -13 <(sqlite3 "$db" 'SELECT DISTINCT(ticker) FROM daily' | sort) tickers.list)"
+if [[ -z "$missing" ]]; then
+  echo "every ticker in tickers.list is in the DB" >&2
+  exit 0
+fi
+printf '%s\n' "$missing" |
+  cargo run --release -- "$@" full --universe -
@@ -8,7 +8,9 @@ SELECT
   LEFT OUTER JOIN sma_50 s50   ON d.id = s50.daily_id
   LEFT OUTER JOIN sma_200 s200 ON d.id = s200.daily_id
   WHERE 
-    timestamp > (strftime('%s', 'now') - 3 * 86400) 
+    -- @as_of (print_candidates.sh sets it from --as-of) or now
+    timestamp > (COALESCE(@as_of, strftime('%s', 'now')) - 3 * 86400)
+    AND timestamp <= COALESCE(@as_of, strftime('%s', 'now'))
     AND ((e8.value < e21.value AND e21.value < e34.value AND e34.value < e89.value) OR
          (e8.value > e21.value AND e21.value > e34.value AND e34.value > e89.value))
   GROUP BY ticker
@@ -2,5 +2,31 @@
 
 set -euo pipefail
 
-sqlite3 "$HOME/.local/stonks/db.sqlite3" < join.sql | 
-  cargo run --release -- trend-candidates
+db="$(cargo run -q --release -- db path)"
+
+# pick the tickers as of the same moment trend-candidates evaluates them at
+as_of=""
+prev=""
+for arg in "$@"; do
+  case "$prev" in
+    --as-of) as_of="$arg" ;;
+  esac
+  case "$arg" in
+    --as-of=*) as_of="${arg#--as-of=}" ;;
+  esac
+  prev="$arg"
+done
+params=()
+if [ -n "$as_of" ]; then
+  # a bare date means after that day's close, as with --as-of
+  [[ "$as_of" =~ ^[0-9]{4}-[0-9]{2}-[0-9]{2}$ ]] && as_of="$as_of 23:59:59"
+  params=(-cmd ".parameter set @as_of $(date -d "TZ=\"America/New_York\" $as_of" +%s)")
+fi
+
+# catch the indicator tables up with anything fetched before they were maintained
+sqlite3 "$db" 'SELECT DISTINCT ticker FROM daily' |
+  cargo run --release -- compute-metrics
+
+# (an empty array trips set -u on bash before 4.4)
+sqlite3 ${params[@]+"${params[@]}"} "$db" < join.sql | 
+  cargo run --release -- trend-candidates --universe - "$@"
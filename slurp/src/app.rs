@@ -1,25 +1,23 @@
 use chrono::prelude::*;
-use chrono::Duration;
-use std::collections::{HashMap, VecDeque};
-use std::time;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{self, Instant};
 
 use ibtwsapi::core::client::EClient;
-use ibtwsapi::core::contract::Contract;
+use ibtwsapi::core::contract::{Contract, ContractDetails};
 use ibtwsapi::core::messages::ServerRspMsg;
 use log::{error, info};
 use std::thread;
 
-use crate::db::Db;
+use crate::bars::BarSize;
+use crate::calendar;
+use crate::corporate::{detect_actions, detect_split};
+use crate::db::{ContractRow, Db, Range};
+use crate::events::Dispatcher;
 use crate::quote::Quote;
-
-const CONCURRENCY_LIMIT: usize = 40;
-const CONCURRENCY_BUFFER: usize = 10;
-
-#[derive(Debug)]
-pub struct TickerQuote {
-    ticker: String,
-    quote: Quote,
-}
+use crate::reconcile::{reconcile, Outcome};
+use crate::scheduler::{Request, RequestKind, Scheduler};
+use crate::source::{QuoteSource, Source};
+use crate::stream::LiveBar;
 
 fn us_stock(stk: &str, primary_exchange: Option<String>) -> Contract {
     let mut contract = Contract::default();
@@ -33,58 +31,181 @@ fn us_stock(stk: &str, primary_exchange: Option<String>) -> Contract {
     contract
 }
 
+/// Where contract lookups record their failures: apart from "ibkr", so a ticker `resolve` couldn't
+/// pin down is still fetched by symbol
+pub const RESOLVE_LEDGER: &str = "ibkr-resolve";
+
+/// IB can list several contracts for a symbol; prefer an exact symbol match
+fn pick_contract<'a>(ticker: &str, details: &'a [ContractDetails]) -> Option<&'a ContractDetails> {
+    details
+        .iter()
+        .find(|d| d.contract.symbol == ticker)
+        .or_else(|| details.first())
+}
+
+fn contract_row(ticker: &str, details: &ContractDetails) -> ContractRow {
+    ContractRow {
+        ticker: ticker.to_string(),
+        con_id: details.contract.con_id,
+        primary_exchange: details.contract.primary_exchange.clone(),
+        long_name: details.long_name.clone(),
+        industry: details.industry.clone(),
+        category: details.category.clone(),
+        subcategory: details.subcategory.clone(),
+        currency: details.contract.currency.clone(),
+    }
+}
+
+/// IB reports pacing violations as a 162 "Historical Market Data Service error"
+fn is_pacing_violation(error_code: i32, error_string: &str) -> bool {
+    error_code == 162 && error_string.to_lowercase().contains("pacing violation")
+}
+
+/// IB's answer to a historical query over a window without any candles
+fn is_no_data(error_code: i32, error_string: &str) -> bool {
+    error_code == 162 && error_string.to_lowercase().contains("no data")
+}
+
+/// "Max number of tickers has been reached": more subscriptions than the account has market data
+/// lines for
+const MAX_TICKERS_REACHED: i32 = 101;
+
+/// What IB's `MarketDataType` event says it's sending
+fn market_data_name(market_data_type: i32) -> &'static str {
+    match market_data_type {
+        1 => "live",
+        2 => "frozen",
+        3 => "delayed",
+        4 => "delayed frozen",
+        _ => "unknown",
+    }
+}
+
+/// Whether a failed request is worth retrying
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Transient,
+    Permanent,
+}
+
+/// Sort an IB error for an outstanding request into something worth retrying or not
+pub fn classify_error(error_code: i32, error_string: &str) -> Failure {
+    let msg = error_string.to_lowercase();
+    match error_code {
+        // No security definition / invalid contract / validation error / not subscribed
+        200 | 203 | 321 | 354 => Failure::Permanent,
+        // Historical Market Data Service error: "no data" for a ticker's whole history won't change
+        // by asking again, but cancelled or timed out queries might
+        162 if msg.contains("no data") || msg.contains("no security") => Failure::Permanent,
+        _ => Failure::Transient,
+    }
+}
+
+/// What a streamed ticker's req_id is subscribed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Subscription {
+    MarketData,
+    RealTimeBars,
+}
+
 pub struct App {
     pub client: EClient,
     pub db: Db,
-    pub req_limit: usize,
     pub force: bool,
-    pub full_ticker_queue: VecDeque<String>,
-    pub incremental_ticker_queue: VecDeque<String>,
-    pub open_requests: HashMap<i32, (bool, String)>,
-    pub quotes: VecDeque<TickerQuote>,
+    /// Follow each daily fetch with one for the adjusted closes (ADJUSTED_LAST)
+    pub adjusted: bool,
+    pub scheduler: Scheduler,
+    /// Candles received so far, by req_id
+    pub quotes: HashMap<i32, Vec<Quote>>,
     pub req_id: i32,
+    /// How long to sleep when there are no events waiting
+    pub poll_interval: time::Duration,
+    /// Subscribers for events `App` doesn't handle itself
+    pub events: Dispatcher,
+    /// `ContractData` received so far, by req_id
+    contract_details: HashMap<i32, Vec<ContractDetails>>,
+    /// Streamed tickers, by the req_id of their market data or real-time bars subscription
+    streams: HashMap<i32, (String, Subscription)>,
+    /// The in-progress daily candle of each streamed ticker
+    live: BTreeMap<String, LiveBar>,
+    /// Tickers whose streams IB refused or cut off, and why
+    dropped: Vec<(String, String)>,
     next_order_id: i32,
 }
 
-/// Avoid requesting daily tickers in the middle of trading day
-fn close_time(mut dt: DateTime<Utc>) -> DateTime<Utc> {
-    // If the hours given is less than 4:30PM, return yesterday's 4:30PM time for query input.
-    let edt_tz = FixedOffset::west(4 * 3600);
-    let edt = dt.with_timezone(&edt_tz);
-    if (edt.hour() > 9 || edt.hour() == 9 && edt.minute() >= 30)
-        && (edt.hour() < 16 || edt.hour() == 16 && edt.minute() < 30)
-    {
-        dt = dt - Duration::days(1);
-        return Utc.ymd(dt.year(), dt.month(), dt.day()).and_hms(11, 59, 0);
-    }
-    dt
+/// Avoid requesting daily tickers in the middle of trading day: end requests at the last
+/// session whose candle has settled
+pub fn close_time(dt: DateTime<Utc>) -> DateTime<Utc> {
+    calendar::last_settled_close(dt)
 }
 
 impl App {
     pub fn new(db: Db, req_limit: usize, force: bool) -> Self {
         App {
             client: EClient::new(),
-            req_limit,
             db,
             force,
-            open_requests: HashMap::new(),
-            full_ticker_queue: VecDeque::new(),
-            incremental_ticker_queue: VecDeque::new(),
-            quotes: VecDeque::with_capacity(2048),
+            adjusted: false,
+            scheduler: Scheduler::new(req_limit),
+            quotes: HashMap::new(),
             next_order_id: -1,
             req_id: 1,
+            poll_interval: time::Duration::new(2, 0),
+            events: Dispatcher::new(),
+            contract_details: HashMap::new(),
+            streams: HashMap::new(),
+            live: BTreeMap::new(),
+            dropped: vec![],
         }
     }
 
     fn error(&mut self, req_id: i32, error_code: i32, error_string: &str) {
-        let ticker = self.open_requests.get(&req_id);
-        eprintln!(
-            "{} => {:?}, {}, {}",
-            req_id, ticker, error_code, error_string
-        );
-        if ticker.is_some() {
-            self.open_requests.remove(&req_id);
-            self.request_next_ticker().ok();
+        let req = self
+            .scheduler
+            .in_flight(req_id)
+            .map(|r| (r.ticker.clone(), r.kind));
+        eprintln!("{} => {:?}, {}, {}", req_id, req, error_code, error_string);
+        if let Some((ticker, kind)) = req {
+            let now = Instant::now();
+            if is_pacing_violation(error_code, error_string) {
+                self.scheduler.pacing_violation(req_id, now);
+                return;
+            }
+            // only a full fetch coming back empty says anything about the ticker: an incremental
+            // window or a chunk of bars can just fall on holidays or a halt
+            if is_no_data(error_code, error_string) && kind != RequestKind::Full {
+                eprintln!("no candles for {} in the window", ticker);
+                self.scheduler.complete(req_id);
+                return;
+            }
+            let failure = classify_error(error_code, error_string);
+            if failure == Failure::Transient && self.scheduler.retry(req_id, now) {
+                return;
+            }
+            self.scheduler.complete(req_id);
+            let ledger = match kind {
+                RequestKind::Contract => RESOLVE_LEDGER,
+                _ => Source::Ibkr.name(),
+            };
+            if let Err(e) = self.db.record_fetch_failure(
+                &ticker,
+                ledger,
+                Some(error_code),
+                error_string,
+                failure == Failure::Permanent,
+            ) {
+                eprintln!("failed to record failure for {}: {}", ticker, e);
+            }
+        } else if let Some((ticker, _)) = self.streams.get(&req_id).cloned() {
+            // the rest are notices, i.e. 10167 for delayed data
+            if error_code == MAX_TICKERS_REACHED
+                || classify_error(error_code, error_string) == Failure::Permanent
+            {
+                eprintln!("no market data for {}, dropping it", ticker);
+                self.drop_stream(&ticker, req_id);
+                self.dropped
+                    .push((ticker, format!("{}: {}", error_code, error_string)));
+            }
         }
         error!(
             "req_id: {} ,error_code: {} , error_string: {}",
@@ -92,18 +213,137 @@ impl App {
         );
     }
 
-    pub fn request_ticker(&mut self, ticker: &str) -> anyhow::Result<()> {
+    /// Pinned to the conId once `resolve` has been run for the ticker, otherwise disambiguated by
+    /// any primary exchange in `ticker_exchange`
+    fn contract_for(&self, ticker: &str) -> anyhow::Result<Contract> {
+        if let Some(row) = self.db.get_contract(ticker)? {
+            let mut contract = us_stock(ticker, Some(row.primary_exchange));
+            contract.con_id = row.con_id;
+            return Ok(contract);
+        }
         let exchange = self.db.get_exchange(ticker)?;
         if let Some(ref e) = exchange {
             eprintln!("{} exchange: {}", ticker, e);
         }
-        let contract = us_stock(ticker, exchange);
+        Ok(us_stock(ticker, exchange))
+    }
+
+    /// Queue the requests for `days` of `bar_size` candles up to now, chunked to what IB serves
+    /// per request
+    pub fn add_bars_request(&mut self, ticker: String, bar_size: BarSize, days: i64) {
+        for end in bar_size.chunk_ends(Utc::now(), days) {
+            self.scheduler
+                .push(Request::bars(ticker.clone(), bar_size, end.timestamp()));
+        }
+    }
+
+    /// Queue the requests for the `bar_size` candles since the last one we have, unless the one
+    /// after it hasn't closed yet (or `force`)
+    pub fn add_incremental_bars(
+        &mut self,
+        ticker: String,
+        bar_size: BarSize,
+    ) -> anyhow::Result<()> {
+        let last = self
+            .db
+            .get_last_bar(&ticker, bar_size)?
+            .ok_or_else(|| anyhow::anyhow!("No {} bars for {}", bar_size, ticker))?;
+        let now = Utc::now().timestamp();
+        let next = last.quote.timestamp + bar_size.seconds();
+        if !self.force && !bar_size.has_closed(next, now) {
+            eprintln!("{} {} bars are up to date", ticker, bar_size);
+            return Ok(());
+        }
+        let days = (now - last.quote.timestamp) / (24 * 3600) + 1;
+        self.add_bars_request(ticker, bar_size, days);
+        Ok(())
+    }
+
+    pub fn request_bars(
+        &mut self,
+        req: Request,
+        bar_size: BarSize,
+        end: i64,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let contract = self.contract_for(&req.ticker)?;
+        let (duration, _) = bar_size.chunk();
+        let query_time = Utc.timestamp(end, 0).format("%Y%m%d-%H:%M:%S").to_string();
+        self.req_id += 1;
+        eprintln!(
+            "requesting {} of {} bars for {} ending {}, req_id: {}",
+            duration, bar_size, req.ticker, query_time, self.req_id
+        );
+        self.scheduler.sent(self.req_id, req, now);
+        // formatDate 2: epoch seconds, so there's no timezone guesswork
+        Ok(self.client.req_historical_data(
+            self.req_id,
+            &contract,
+            query_time.as_str(),
+            duration,
+            bar_size.ib_str(),
+            "TRADES",
+            1,
+            2,
+            false,
+            vec![],
+        )?)
+    }
+
+    /// ADJUSTED_LAST only serves up to now, so there's no end date to pass
+    pub fn request_adjusted(
+        &mut self,
+        req: Request,
+        days: i64,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let contract = self.contract_for(&req.ticker)?;
+        let duration = if days >= 365 {
+            format!("{} Y", (days + 364) / 365)
+        } else {
+            format!("{} D", days)
+        };
+        self.req_id += 1;
+        eprintln!(
+            "requesting '{}' of adjusted closes for {}, req_id: {}",
+            duration, req.ticker, self.req_id
+        );
+        self.scheduler.sent(self.req_id, req, now);
+        Ok(self.client.req_historical_data(
+            self.req_id,
+            &contract,
+            "",
+            &duration,
+            "1 day",
+            "ADJUSTED_LAST",
+            1,
+            1,
+            false,
+            vec![],
+        )?)
+    }
+
+    /// Queue a ticker for a `reqContractDetails` lookup, stored in the `contracts` table
+    pub fn add_ticker_to_resolve(&mut self, ticker: String) {
+        self.scheduler.push(Request::contract(ticker));
+    }
+
+    pub fn request_contract(&mut self, req: Request, now: Instant) -> anyhow::Result<()> {
+        let contract = us_stock(&req.ticker, self.db.get_exchange(&req.ticker)?);
+        self.req_id += 1;
+        eprintln!("resolving {}", req.ticker);
+        self.scheduler.sent(self.req_id, req, now);
+        Ok(self.client.req_contract_details(self.req_id, &contract)?)
+    }
+
+    pub fn request_ticker(&mut self, req: Request, now: Instant) -> anyhow::Result<()> {
+        let ticker = req.ticker.as_str();
+        let contract = self.contract_for(ticker)?;
         let dt = close_time(Utc::now());
         let query_time = dt.format("%Y%m%d-%H:%M:%S").to_string();
         self.req_id += 1;
-        self.open_requests
-            .insert(self.req_id, (false, ticker.to_string()));
         eprintln!("requesting {}", ticker);
+        self.scheduler.sent(self.req_id, req, now);
         Ok(self.client.req_historical_data(
             self.req_id,
             &contract,
@@ -118,102 +358,370 @@ impl App {
         )?)
     }
 
-    pub fn run(&mut self) -> anyhow::Result<()> {
-        let mut count = 0;
-        while !self.full_ticker_queue.is_empty() && count < self.req_limit {
-            self.request_next_ticker()?;
-            count += 1;
-        }
-        while !self.incremental_ticker_queue.is_empty() && count < self.req_limit {
-            self.request_next_incremental_ticker()?;
-            count += 1;
+    /// Send every queued request the scheduler currently allows
+    pub fn request_ready(&mut self) -> anyhow::Result<()> {
+        let now = Instant::now();
+        while let Some(req) = self.scheduler.next_ready(now) {
+            match req.kind {
+                RequestKind::Full => self.request_ticker(req, now)?,
+                RequestKind::Incremental => self.request_incremental_ticker(req, now)?,
+                RequestKind::Contract => self.request_contract(req, now)?,
+                RequestKind::Bars { bar_size, end } => {
+                    self.request_bars(req, bar_size, end, now)?
+                }
+                RequestKind::Adjusted { days } => self.request_adjusted(req, days, now)?,
+            }
         }
-        self.wait_loop();
         Ok(())
     }
 
+    /// Process events until every queued request has been answered
     fn wait_loop(&mut self) {
-        loop {
-            match self.process_ib_response() {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("{}", e.to_string());
-                    break ();
+        while !self.scheduler.is_idle() {
+            if let Err(e) = self
+                .process_ib_response()
+                .and_then(|_| self.request_ready())
+            {
+                eprintln!("{}", e);
+                break;
+            }
+        }
+        for (event, count) in self.events.unhandled_counts() {
+            eprintln!("ignored {} {} event(s)", count, event);
+        }
+    }
+
+    /// Subscribe to market data for `tickers` and build each one's candle for `session`, picking
+    /// up any persisted by an earlier stream. `delayed` asks for the free 15 minute delayed data;
+    /// `real_time_bars` adds live 5 second bars for the price between ticks. Each subscription
+    /// takes one of the account's `max_lines` market data lines.
+    pub fn stream(
+        &mut self,
+        tickers: &[String],
+        session: NaiveDate,
+        delayed: bool,
+        real_time_bars: bool,
+        max_lines: usize,
+    ) -> anyhow::Result<()> {
+        let lines = tickers.len() * if real_time_bars { 2 } else { 1 };
+        if lines > max_lines {
+            anyhow::bail!(
+                "streaming {} tickers takes {} market data lines, over the {} available",
+                tickers.len(),
+                lines,
+                max_lines
+            );
+        }
+        let timestamp = calendar::close_timestamp(session);
+        let stale = self.db.delete_live_daily_before(timestamp)?;
+        if stale > 0 {
+            eprintln!("dropped {} live candles of earlier sessions", stale);
+        }
+        // 1: live, 3: delayed
+        self.client
+            .req_market_data_type(if delayed { 3 } else { 1 })?;
+        // IB falls back to frozen or delayed data without a subscription; say so once per kind
+        let mut seen = HashSet::new();
+        self.events.subscribe("MarketDataType", move |event| {
+            if let ServerRspMsg::MarketDataType {
+                market_data_type, ..
+            } = event
+            {
+                if seen.insert(*market_data_type) {
+                    eprintln!(
+                        "receiving {} market data",
+                        market_data_name(*market_data_type)
+                    );
                 }
+            }
+        });
+        for ticker in tickers {
+            let contract = self.contract_for(ticker)?;
+            let bar = match self.db.get_live_daily(ticker, timestamp)? {
+                Some(quote) => LiveBar::resume(quote),
+                None => LiveBar::new(session),
             };
+            self.live.insert(ticker.clone(), bar);
+            self.req_id += 1;
+            self.streams
+                .insert(self.req_id, (ticker.clone(), Subscription::MarketData));
+            self.client
+                .req_mkt_data(self.req_id, &contract, "", false, false, vec![])?;
+            if real_time_bars {
+                self.req_id += 1;
+                self.streams
+                    .insert(self.req_id, (ticker.clone(), Subscription::RealTimeBars));
+                self.client.req_real_time_bars(
+                    self.req_id,
+                    &contract,
+                    5,
+                    "TRADES",
+                    true,
+                    vec![],
+                )?;
+            }
         }
+        Ok(())
+    }
+
+    /// Whether any market data subscription is still alive
+    pub fn is_streaming(&self) -> bool {
+        !self.streams.is_empty()
+    }
+
+    /// Tickers IB stopped streaming, and the error it gave
+    pub fn dropped_streams(&self) -> &[(String, String)] {
+        &self.dropped
     }
 
-    pub fn request_next_incremental_ticker(&mut self) -> anyhow::Result<bool> {
-        if let Some(ticker) = self.incremental_ticker_queue.pop_front() {
-            let last_quote = self.db.get_last_quote(&ticker)?;
-            let dt = close_time(Utc::now());
-            // eprintln!("dt: {:?}", dt);
-            let query_time = dt.format("%Y%m%d-%H:%M:%S").to_string();
-            let last_quote = Utc.timestamp(last_quote.quote.timestamp, 0);
-            let num_days = (dt - last_quote).num_days();
-            if num_days == 0 && !self.force {
-                eprintln!("skipping up-to-date {}", &ticker);
-                self.request_next_incremental_ticker()?;
-                return Ok(true);
+    fn cancel(&mut self, req_id: i32, subscription: Subscription) -> anyhow::Result<()> {
+        match subscription {
+            Subscription::MarketData => self.client.cancel_mkt_data(req_id)?,
+            Subscription::RealTimeBars => self.client.cancel_real_time_bars(req_id)?,
+        }
+        Ok(())
+    }
+
+    /// Forget all of the ticker's subscriptions after `failed` was refused, cancelling the others
+    fn drop_stream(&mut self, ticker: &str, failed: i32) {
+        let req_ids: Vec<(i32, Subscription)> = self
+            .streams
+            .iter()
+            .filter(|(_, (t, _))| t == ticker)
+            .map(|(req_id, (_, subscription))| (*req_id, *subscription))
+            .collect();
+        for (req_id, subscription) in req_ids {
+            self.streams.remove(&req_id);
+            if req_id != failed {
+                if let Err(e) = self.cancel(req_id, subscription) {
+                    eprintln!("failed to cancel {}'s {:?}: {}", ticker, subscription, e);
+                }
             }
-            let day_str = format!("{} D", num_days + 2);
-            self.req_id += 1;
-            let contract = us_stock(&ticker, self.db.get_exchange(&ticker)?);
-            eprintln!(
-                "requesting '{}' for {}, req_id: {}",
-                &day_str, &ticker, self.req_id
-            );
-            self.open_requests
-                .insert(self.req_id, (true, ticker.to_string()));
-            self.client.req_historical_data(
-                self.req_id,
-                &contract,
-                query_time.as_str(),
-                &day_str,
-                "1 day",
-                "TRADES",
-                1,
-                1,
-                false,
-                vec![],
-            )?;
-            return Ok(true);
         }
-        Ok(false)
     }
 
-    pub fn add_incremental_ticker(&mut self, ticker: String) {
-        self.incremental_ticker_queue.push_back(ticker);
+    /// Cancel every subscription `stream` made
+    pub fn stop_streaming(&mut self) -> anyhow::Result<()> {
+        let streams: Vec<(i32, Subscription)> = self
+            .streams
+            .drain()
+            .map(|(req_id, (_, subscription))| (req_id, subscription))
+            .collect();
+        for (req_id, subscription) in streams {
+            self.cancel(req_id, subscription)?;
+        }
+        Ok(())
     }
 
-    pub fn add_ticker_to_request_queue(&mut self, ticker: String) {
-        self.full_ticker_queue.push_back(ticker);
+    fn live_bar(&mut self, req_id: i32) -> Option<&mut LiveBar> {
+        let (ticker, _) = self.streams.get(&req_id)?;
+        self.live.get_mut(ticker)
     }
 
-    pub fn request_next_ticker(&mut self) -> anyhow::Result<bool> {
-        if let Some(ticker) = self.full_ticker_queue.pop_front() {
-            self.request_ticker(&ticker)?;
-            return Ok(true);
+    /// Persist the live candles that changed since the last call and return them
+    pub fn take_live_updates(&mut self) -> anyhow::Result<Vec<(String, Quote)>> {
+        let mut updates = vec![];
+        for (ticker, bar) in self.live.iter_mut() {
+            if bar.take_changed() && bar.is_ready() {
+                self.db.upsert_live_daily(ticker, &bar.quote)?;
+                updates.push((ticker.clone(), bar.quote.clone()));
+            }
         }
-        Ok(false)
+        Ok(updates)
+    }
+
+    pub fn request_incremental_ticker(&mut self, req: Request, now: Instant) -> anyhow::Result<()> {
+        let ticker = req.ticker.clone();
+        let last_quote = self.db.get_last_quote(&ticker)?;
+        let utc_now = Utc::now();
+        let dt = close_time(utc_now);
+        let query_time = dt.format("%Y%m%d-%H:%M:%S").to_string();
+        let last_date = calendar::ny_date(last_quote.quote.timestamp);
+        let session = calendar::last_completed_session(utc_now);
+        if calendar::sessions_between(last_date, session) == 0 && !self.force {
+            eprintln!("skipping up-to-date {}", &ticker);
+            return Ok(());
+        }
+        // calendar days, so weekends and holidays are covered too
+        let num_days = (session - last_date).num_days();
+        let day_str = format!("{} D", num_days + 2);
+        self.req_id += 1;
+        let contract = self.contract_for(&ticker)?;
+        eprintln!(
+            "requesting '{}' for {}, req_id: {}",
+            &day_str, &ticker, self.req_id
+        );
+        self.scheduler.sent(self.req_id, req, now);
+        self.client.req_historical_data(
+            self.req_id,
+            &contract,
+            query_time.as_str(),
+            &day_str,
+            "1 day",
+            "TRADES",
+            1,
+            1,
+            false,
+            vec![],
+        )?;
+        Ok(())
+    }
+
+    /// TRADES candles are split adjusted, so cached closes that no longer match are usually a
+    /// split since we fetched them. Record it on its ex-date, if the window shows where that is.
+    fn record_split(
+        &mut self,
+        ticker: &str,
+        cached: &[Quote],
+        quotes: &[Quote],
+    ) -> anyhow::Result<()> {
+        if let Some(split) = detect_split(cached, quotes) {
+            eprintln!(
+                "{} looks like a {} on {}",
+                ticker,
+                split,
+                calendar::ny_date(split.timestamp)
+            );
+            self.db
+                .insert_corporate_actions(ticker, "detected", &[split])?;
+        }
+        Ok(())
+    }
+
+    /// Store ADJUSTED_LAST closes as the adjusted closes of the candles we have, and record the
+    /// splits and dividends they imply
+    fn store_adjusted(&mut self, ticker: &str, adjusted: &[Quote]) -> anyhow::Result<()> {
+        self.db.update_adjcloses(ticker, adjusted)?;
+        let rows = self
+            .db
+            .get_candles(ticker, BarSize::Day1, Range::default())?;
+        let quotes: Vec<Quote> = rows.into_iter().map(|row| row.quote).collect();
+        let actions = detect_actions(&quotes);
+        for action in &actions {
+            eprintln!("{}: {} on {}", ticker, action, action.timestamp);
+        }
+        self.db
+            .insert_corporate_actions(ticker, Source::Ibkr.name(), &actions)
+    }
+
+    /// Insert the candles a historical data request returned
+    fn store_quotes(&mut self, req: Request, mut quotes: Vec<Quote>) -> anyhow::Result<()> {
+        let ticker = req.ticker;
+        if quotes.is_empty() {
+            eprintln!("no quotes for {}", ticker);
+            return Ok(());
+        }
+        match req.kind {
+            RequestKind::Bars { bar_size, .. } => {
+                // the newest candle can still be forming; it's fetched again once it closes
+                let now = Utc::now().timestamp();
+                quotes.retain(|q| bar_size.has_closed(q.timestamp, now));
+                self.db.insert_bars(&ticker, bar_size, &quotes)?;
+            }
+            RequestKind::Incremental => {
+                // check the window against what we have, and request new 2-year data if a close
+                // changed or there's nothing to check it against
+                let (first, last) = (quotes[0].timestamp, quotes[quotes.len() - 1].timestamp);
+                let cached: Vec<Quote> = self
+                    .db
+                    .get_candles(&ticker, BarSize::Day1, Range::between(first, last))?
+                    .into_iter()
+                    .map(|row| row.quote)
+                    .collect();
+                let outcome = reconcile(&cached, &quotes);
+                eprintln!("{}: {} ({})", ticker, outcome.name(), outcome.detail());
+                self.db
+                    .record_reconciliation(&ticker, Source::Ibkr.name(), &outcome)?;
+                match outcome {
+                    Outcome::Verified { .. } => {
+                        // keep the adjusted closes we have until ADJUSTED_LAST says otherwise
+                        for quote in quotes.iter_mut() {
+                            if let Some(c) = cached.iter().find(|c| c.timestamp == quote.timestamp)
+                            {
+                                quote.adjclose = c.adjclose;
+                            }
+                        }
+                        self.db.insert_daily_quotes(&ticker, &quotes)?;
+                        if self.adjusted {
+                            let days = quotes.len() as i64 + 1;
+                            self.scheduler.push(Request::adjusted(ticker.clone(), days));
+                        }
+                    }
+                    Outcome::Mismatch { .. } => {
+                        self.record_split(&ticker, &cached, &quotes)?;
+                        self.scheduler.push_front(Request::full(ticker));
+                        return Ok(());
+                    }
+                    Outcome::NoOverlap => {
+                        self.scheduler.push_front(Request::full(ticker));
+                        return Ok(());
+                    }
+                }
+            }
+            RequestKind::Full => {
+                self.db.replace_daily_quotes(&ticker, &quotes)?;
+                self.db.clear_refetch(&ticker)?;
+                if self.adjusted {
+                    let days = quotes.len() as i64 * 7 / 5 + 7;
+                    self.scheduler.push(Request::adjusted(ticker.clone(), days));
+                }
+            }
+            RequestKind::Adjusted { .. } => self.store_adjusted(&ticker, &quotes)?,
+            RequestKind::Contract => anyhow::bail!("unexpected candles for {}", ticker),
+        }
+        self.db.clear_fetch_failure(&ticker, Source::Ibkr.name())
     }
 
     pub fn process_ib_response(&mut self) -> anyhow::Result<()> {
-        match self.client.get_event()? {
-            Some(ServerRspMsg::NextValidId { order_id }) => {
+        let event = match self.client.get_event()? {
+            Some(event) => event,
+            None => {
+                if !self.is_streaming() {
+                    eprintln!(
+                        "waiting... {} in flight, {} queued",
+                        self.scheduler.in_flight_len(),
+                        self.scheduler.pending_len()
+                    );
+                }
+                thread::sleep(self.poll_interval);
+                return Ok(());
+            }
+        };
+        let subscribed = self.events.dispatch(&event);
+        match event {
+            ServerRspMsg::NextValidId { order_id } => {
                 self.next_order_id = order_id;
                 info!("next_valid_id -- order_id: {}", order_id);
             }
-            Some(ServerRspMsg::ErrMsg {
+            ServerRspMsg::ErrMsg {
                 req_id,
                 error_code,
                 error_str,
-            }) => self.error(req_id, error_code, &error_str),
-            // Some(ServerRspMsg::TickPrice { req_id, tick_type, price, tick_attr }) =>
-            //     eprintln!("tick_size -- req_id: {}, tick_type: {}, price: {}, attrib: {}", req_id, tick_type, price, tick_attr),
-            // Some(ServerRspMsg::TickSize { req_id, tick_type, size }) =>
-            //     eprintln!( "tick_size -- req_id: {}, tick_type: {}, size: {}", req_id, tick_type, size),
+            } => self.error(req_id, error_code, &error_str),
+            ServerRspMsg::TickPrice {
+                req_id,
+                tick_type,
+                price,
+                ..
+            } if self.streams.contains_key(&req_id) => {
+                if let Some(bar) = self.live_bar(req_id) {
+                    bar.tick_price(tick_type, price);
+                }
+            }
+            ServerRspMsg::TickSize {
+                req_id,
+                tick_type,
+                size,
+            } if self.streams.contains_key(&req_id) => {
+                if let Some(bar) = self.live_bar(req_id) {
+                    bar.tick_size(tick_type, size);
+                }
+            }
+            ServerRspMsg::RealTimeBars { req_id, bar } if self.streams.contains_key(&req_id) => {
+                if let Some(live) = self.live_bar(req_id) {
+                    live.real_time_bar(&bar);
+                }
+            }
             // Some(ServerRspMsg::CompletedOrder { contract, order, order_state }) =>
             //     eprintln!("completed_order -- contract: [{}], order: [{}], order_state: [{}]", contract, order, order_state),
             // Some(ServerRspMsg::CompletedOrdersEnd)  => info!("completed_orders_end -- (no parameters for this message)"),
@@ -221,7 +729,7 @@ impl App {
             //     eprintln!( "order_bound -- req_id: {}, api_client_id: {}, api_order_id: {}", req_id, api_client_id, api_order_id),
             // Some(ServerRspMsg::MarketDataType {req_id, market_data_type}) =>
             //     eprintln!("market_data_type -- req_id: {}, market_data_type: {}", req_id, market_data_type),
-            Some(ServerRspMsg::ManagedAccts { accounts_list }) => {
+            ServerRspMsg::ManagedAccts { accounts_list } => {
                 eprintln!("managed_accounts -- accounts_list: {}", accounts_list)
             }
             // Some(ServerRspMsg::OpenOrderEnd) => info!("open_order_end. (no parameters passed)"),
@@ -237,85 +745,667 @@ impl App {
             // Some(ServerRspMsg::ExecutionData { req_id, contract, execution }) =>
             //     eprintln!("exec_details -- req_id: {}, contract: {}, execution: {}", req_id, contract, execution),
             // Some(ServerRspMsg::ExecutionDataEnd { req_id }) => info!("exec_details_end -- req_id: {}", req_id),
-            Some(ServerRspMsg::NewsBulletins { .. }) => info!("news bulletin ignored"),
-            Some(ServerRspMsg::HistoricalData { req_id, bar }) => {
+            ServerRspMsg::NewsBulletins { .. } => info!("news bulletin ignored"),
+            ServerRspMsg::HistoricalData { req_id, bar } => {
                 let quote = bar.try_into()?;
-                let (_incremental, ticker) = self
-                    .open_requests
-                    .get(&req_id)
-                    .ok_or_else(|| anyhow::anyhow!("unknown req_id {}", req_id))?;
-                let tq = TickerQuote {
-                    ticker: ticker.clone(),
-                    quote,
-                };
-                // eprintln!("tq: {:?}", tq);
-                self.quotes.push_back(tq);
+                if self.scheduler.in_flight(req_id).is_none() {
+                    anyhow::bail!("unknown req_id {}", req_id);
+                }
+                self.quotes.entry(req_id).or_default().push(quote);
             }
-            Some(ServerRspMsg::HistoricalDataEnd { req_id, start, end }) => {
+            ServerRspMsg::HistoricalDataEnd { req_id, start, end } => {
                 eprintln!("end: {} {} {}", req_id, start, end);
-                let (incremental, ticker) = self
-                    .open_requests
-                    .remove(&req_id)
+                let req = self
+                    .scheduler
+                    .complete(req_id)
                     .ok_or_else(|| anyhow::anyhow!("unexpected {}", req_id))?;
-                eprintln!("{} - {} quotes", ticker, self.quotes.len());
-                if incremental {
-                    self.request_next_incremental_ticker()?;
-                } else {
-                    self.request_next_ticker()?;
+                let quotes = self.quotes.remove(&req_id).unwrap_or_default();
+                eprintln!("{} - {} quotes", req.ticker, quotes.len());
+                let (ticker, kind) = (req.ticker.clone(), req.kind);
+                self.store_quotes(req, quotes)?;
+                if matches!(kind, RequestKind::Full | RequestKind::Incremental) {
+                    let start = time::Instant::now();
+                    if let Err(e) = self.db.calculate_and_insert_metrics(&ticker) {
+                        eprintln!("failed to calculate metrics for {}: {}", ticker, e);
+                    }
+                    eprintln!("calculate & insert metrics in: {:?}", start.elapsed());
                 }
-                let mut tick2quotes: HashMap<String, Vec<Quote>> = HashMap::new();
-                for tq in self.quotes.drain(0..) {
-                    let qs = tick2quotes.entry(tq.ticker).or_insert(vec![]);
-                    qs.push(tq.quote);
+            }
+            ServerRspMsg::ContractData {
+                req_id,
+                contract_details,
+            } => self
+                .contract_details
+                .entry(req_id)
+                .or_default()
+                .push(contract_details),
+            ServerRspMsg::ContractDataEnd { req_id } => {
+                let req = self
+                    .scheduler
+                    .complete(req_id)
+                    .ok_or_else(|| anyhow::anyhow!("unexpected {}", req_id))?;
+                let details = self.contract_details.remove(&req_id).unwrap_or_default();
+                if details.len() > 1 {
+                    eprintln!(
+                        "{} is ambiguous: {:?}",
+                        req.ticker,
+                        details
+                            .iter()
+                            .map(|d| format!(
+                                "{}@{}",
+                                d.contract.symbol, d.contract.primary_exchange
+                            ))
+                            .collect::<Vec<String>>()
+                    );
                 }
-                for (ticker, quotes) in tick2quotes {
-                    if incremental {
-                        // check for updated close numbers and request new 2-year data if so
-                        let first_quote = &quotes[0];
-                        let maybe_cached_quote = self
-                            .db
-                            .get_quote_with_timestamp(&ticker, first_quote.timestamp)?;
-                        if let Some(cached_quote) = maybe_cached_quote {
-                            if cached_quote.quote.close != first_quote.close {
-                                eprintln!(
-                                    "{} != {} for {}",
-                                    cached_quote.quote.close, first_quote.close, ticker
-                                );
-                                self.add_ticker_to_request_queue(ticker);
-                                if self.open_requests.len() < CONCURRENCY_LIMIT + CONCURRENCY_BUFFER
-                                {
-                                    self.request_next_ticker()?;
-                                }
-                                continue;
-                            }
-                        } else {
-                            eprintln!(
-                                "No row for {} with timestamp {}",
-                                ticker, first_quote.timestamp
-                            );
-                            continue;
-                        }
+                match pick_contract(&req.ticker, &details) {
+                    Some(d) => {
+                        let row = contract_row(&req.ticker, d);
+                        eprintln!("{} => {} {}", row.ticker, row.con_id, row.primary_exchange);
+                        self.db.upsert_contract(&row)?;
+                        self.db.clear_fetch_failure(&req.ticker, RESOLVE_LEDGER)?;
                     }
-                    // eprintln!("inserting {:?}", quotes);
-                    self.db.insert_daily_quotes(&ticker, &quotes)?;
+                    None => eprintln!("no contract details for {}", req.ticker),
                 }
-                // let start = time::Instant::now();
-                // self.db.calculate_and_insert_metrics(&ticker)?;
-                // eprintln!("calculate & insert metrics in: {:?}", start.elapsed());
             }
-            Some(ServerRspMsg::CommissionReport { commission_report }) => eprintln!(
+            ServerRspMsg::CommissionReport { commission_report } => eprintln!(
                 "commission_report -- commission_report: {}",
                 commission_report
             ),
-            Some(i) => panic!(
-                "Received unhandled event! Exiting. Event: {}",
-                i.to_string()
-            ),
-            None => {
-                eprintln!("waiting... {:?}", self.open_requests);
-                thread::sleep(time::Duration::new(2, 0));
+            event => {
+                if !subscribed {
+                    self.events.unhandled(&event);
+                }
             }
         }
         Ok(())
     }
 }
+
+impl QuoteSource for App {
+    fn add_ticker_to_request_queue(&mut self, ticker: String) {
+        self.scheduler.push(Request::full(ticker));
+    }
+
+    fn add_incremental_ticker(&mut self, ticker: String) {
+        self.scheduler.push(Request::incremental(ticker));
+    }
+
+    fn run(&mut self) -> anyhow::Result<()> {
+        self.request_ready()?;
+        self.wait_loop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::corporate::ActionKind;
+    use crate::mock_tws::{recorded, ContractInfo, MockTws, Reply};
+    use ibtwsapi::core::common::BarData;
+    use std::cell::Cell;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    fn connect(mock: &MockTws, req_limit: usize) -> App {
+        let db = Db::init(Some(PathBuf::from(":memory:"))).unwrap();
+        let mut app = App::new(db, req_limit, false);
+        app.poll_interval = time::Duration::from_millis(5);
+        app.client.connect("127.0.0.1", mock.port, 1).unwrap();
+        app
+    }
+
+    /// Issue requests and process events until every request has been answered
+    fn drive(app: &mut App) {
+        let deadline = Instant::now() + time::Duration::from_secs(10);
+        app.request_ready().unwrap();
+        while !app.scheduler.is_idle() {
+            assert!(Instant::now() < deadline, "timed out");
+            app.process_ib_response().unwrap();
+            app.request_ready().unwrap();
+        }
+    }
+
+    fn recorded_quotes(name: &str) -> Vec<Quote> {
+        recorded(name)
+            .into_iter()
+            .map(|bar| BarData::from(bar).try_into().unwrap())
+            .collect()
+    }
+
+    fn closes(app: &App, ticker: &str) -> Vec<f64> {
+        app.db
+            .get_candles(ticker, BarSize::Day1, Range::default())
+            .unwrap()
+            .iter()
+            .map(|row| row.quote.close)
+            .collect()
+    }
+
+    #[test]
+    fn test_full_backfill() {
+        let mock = MockTws::start();
+        mock.on("AAPL", vec![Reply::Bars(recorded("AAPL"))])
+            .on("MSFT", vec![Reply::Bars(recorded("MSFT"))]);
+        let mut app = connect(&mock, 40);
+        app.add_ticker_to_request_queue("AAPL".to_string());
+        app.add_ticker_to_request_queue("MSFT".to_string());
+        drive(&mut app);
+
+        assert_eq!(
+            closes(&app, "AAPL"),
+            vec![130.06, 131.56, 135.87, 135.35, 138.27]
+        );
+        assert_eq!(closes(&app, "MSFT").len(), 5);
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].duration, "2 Y");
+        assert_eq!(requests[0].bar_size, "1 day");
+        assert_eq!(requests[0].what_to_show, "TRADES");
+    }
+
+    #[test]
+    fn test_errors_drop_ticker_and_keep_going() {
+        let mock = MockTws::start();
+        mock.on(
+            "CASH",
+            vec![Reply::Error(
+                200,
+                "No security definition has been found for the request".to_string(),
+            )],
+        )
+        .on(
+            "NOPE",
+            vec![Reply::Error(
+                354,
+                "Requested market data is not subscribed.".to_string(),
+            )],
+        )
+        .on(
+            "EMPTY",
+            vec![Reply::Error(
+                162,
+                "Historical Market Data Service error message:HMDS query returned no data"
+                    .to_string(),
+            )],
+        )
+        .on("MSFT", vec![Reply::Bars(recorded("MSFT"))]);
+        // one at a time so each error has to trigger the next request
+        let mut app = connect(&mock, 1);
+        for ticker in ["CASH", "NOPE", "EMPTY", "MSFT"] {
+            app.add_ticker_to_request_queue(ticker.to_string());
+        }
+        drive(&mut app);
+
+        assert_eq!(mock.requests().len(), 4);
+        assert!(closes(&app, "CASH").is_empty());
+        assert_eq!(closes(&app, "MSFT").len(), 5);
+        let failed = app.db.get_permanent_failures("ibkr").unwrap();
+        assert_eq!(failed.len(), 3);
+        assert!(!failed.contains("MSFT"));
+    }
+
+    #[test]
+    fn test_transient_errors_are_retried() {
+        let mock = MockTws::start();
+        let busy = || Reply::Error(322, "Error processing request".to_string());
+        mock.on("AAPL", vec![busy()])
+            .on("AAPL", vec![Reply::Bars(recorded("AAPL"))])
+            .on("MSFT", vec![busy()])
+            .on("MSFT", vec![busy()]);
+        let mut app = connect(&mock, 40);
+        app.scheduler.retry_backoff = time::Duration::from_millis(10);
+        app.scheduler.identical_request_gap = time::Duration::from_millis(0);
+        app.scheduler.max_retries = 1;
+        app.add_ticker_to_request_queue("AAPL".to_string());
+        app.add_ticker_to_request_queue("MSFT".to_string());
+        drive(&mut app);
+
+        assert_eq!(mock.requests().len(), 4);
+        assert_eq!(closes(&app, "AAPL").len(), 5);
+        // gave up on MSFT, but it's worth trying again next run
+        let failures = app.db.get_fetch_failures().unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].ticker, "MSFT");
+        assert_eq!(failures[0].error_code, Some(322));
+        assert!(!failures[0].permanent);
+    }
+
+    #[test]
+    fn test_delayed_replies_respect_req_limit() {
+        let mock = MockTws::start();
+        mock.on(
+            "AAPL",
+            vec![
+                Reply::Delay(time::Duration::from_millis(100)),
+                Reply::Bars(recorded("AAPL")),
+            ],
+        )
+        .on("MSFT", vec![Reply::Bars(recorded("MSFT"))]);
+        let mut app = connect(&mock, 1);
+        app.add_ticker_to_request_queue("AAPL".to_string());
+        app.add_ticker_to_request_queue("MSFT".to_string());
+        app.request_ready().unwrap();
+        thread::sleep(time::Duration::from_millis(50));
+        assert_eq!(mock.requests().len(), 1);
+        drive(&mut app);
+
+        let symbols: Vec<String> = mock.requests().into_iter().map(|r| r.symbol).collect();
+        assert_eq!(symbols, vec!["AAPL", "MSFT"]);
+        assert_eq!(closes(&app, "AAPL").len(), 5);
+        assert_eq!(closes(&app, "MSFT").len(), 5);
+    }
+
+    #[test]
+    fn test_incremental_appends_after_matching_close() {
+        let mock = MockTws::start();
+        mock.on("AAPL", vec![Reply::Bars(recorded("AAPL_incremental"))]);
+        let mut app = connect(&mock, 40);
+        app.db
+            .insert_daily_quotes("AAPL", &recorded_quotes("AAPL"))
+            .unwrap();
+        app.add_incremental_ticker("AAPL".to_string());
+        drive(&mut app);
+
+        assert_eq!(
+            closes(&app, "AAPL"),
+            vec![130.06, 131.56, 135.87, 135.35, 138.27, 141.66]
+        );
+        assert!(mock.requests()[0].duration.ends_with(" D"));
+    }
+
+    #[test]
+    fn test_empty_incremental_window_is_not_a_failure() {
+        let mock = MockTws::start();
+        mock.on(
+            "AAPL",
+            vec![Reply::Error(
+                162,
+                "Historical Market Data Service error message:HMDS query returned no data"
+                    .to_string(),
+            )],
+        );
+        let mut app = connect(&mock, 40);
+        app.db
+            .insert_daily_quotes("AAPL", &recorded_quotes("AAPL"))
+            .unwrap();
+        app.add_incremental_ticker("AAPL".to_string());
+        drive(&mut app);
+
+        assert_eq!(mock.requests().len(), 1);
+        assert_eq!(closes(&app, "AAPL").len(), 5);
+        assert!(app.db.get_fetch_failures().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_incremental_close_mismatch_refetches_full() {
+        let mock = MockTws::start();
+        mock.on("AAPL", vec![Reply::Bars(recorded("AAPL_incremental"))])
+            .on("AAPL", vec![Reply::Bars(recorded("AAPL"))]);
+        let mut app = connect(&mock, 40);
+        // a 2:1 split since we cached this close
+        let seed = Quote {
+            timestamp: recorded_quotes("AAPL_incremental")[0].timestamp,
+            close: 276.54,
+            ..Quote::default()
+        };
+        app.db.insert_daily_quotes("AAPL", &[seed]).unwrap();
+        app.add_incremental_ticker("AAPL".to_string());
+        drive(&mut app);
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].duration, "2 Y");
+        assert_eq!(
+            closes(&app, "AAPL"),
+            vec![130.06, 131.56, 135.87, 135.35, 138.27]
+        );
+        // went ex on the first candle past the cached close that's off
+        let splits = app.db.get_corporate_actions("AAPL").unwrap();
+        assert_eq!(splits.len(), 1);
+        assert_eq!(splits[0].kind, ActionKind::Split);
+        assert_eq!(splits[0].value, 2.0);
+        assert_eq!(
+            splits[0].timestamp,
+            recorded_quotes("AAPL_incremental")[1].timestamp
+        );
+    }
+
+    #[test]
+    fn test_incremental_reconciles_around_gaps() {
+        let mock = MockTws::start();
+        mock.on("AAPL", vec![Reply::Bars(recorded("AAPL_incremental_gap"))])
+            .on("MSFT", vec![Reply::Bars(recorded("AAPL_incremental"))])
+            .on("MSFT", vec![Reply::Bars(recorded("MSFT"))]);
+        let mut app = connect(&mock, 40);
+        // missing the first candle of the update, but the next one still checks out
+        let mut aapl = recorded_quotes("AAPL");
+        aapl.remove(3);
+        app.db.insert_daily_quotes("AAPL", &aapl).unwrap();
+        // nothing cached from the update's window
+        let msft = recorded_quotes("MSFT");
+        app.db.insert_daily_quotes("MSFT", &msft[..2]).unwrap();
+        app.add_incremental_ticker("AAPL".to_string());
+        app.add_incremental_ticker("MSFT".to_string());
+        drive(&mut app);
+
+        assert_eq!(
+            closes(&app, "AAPL"),
+            vec![130.06, 131.56, 135.87, 135.35, 138.27, 141.66]
+        );
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].duration, "2 Y");
+        assert_eq!(closes(&app, "MSFT").len(), 5);
+        let outcomes: Vec<(String, String)> = app
+            .db
+            .get_reconciliations()
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.ticker, r.outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("AAPL".to_string(), "verified".to_string()),
+                ("MSFT".to_string(), "no_overlap".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unexpected_events_are_not_fatal() {
+        let mock = MockTws::start();
+        let market_data_type = vec!["58", "1", "-1", "3"];
+        let tick_size = vec!["2", "1", "-1", "0", "100"];
+        mock.on(
+            "AAPL",
+            vec![
+                Reply::Raw(market_data_type.into_iter().map(String::from).collect()),
+                Reply::Raw(tick_size.into_iter().map(String::from).collect()),
+                Reply::Bars(recorded("AAPL")),
+            ],
+        );
+        let mut app = connect(&mock, 40);
+        let ticks = Rc::new(Cell::new(0));
+        let t = ticks.clone();
+        app.events
+            .subscribe("TickSize", move |_| t.set(t.get() + 1));
+        app.add_ticker_to_request_queue("AAPL".to_string());
+        // returns once the queue is drained rather than waiting on more events
+        app.run().unwrap();
+
+        assert_eq!(ticks.get(), 1);
+        assert_eq!(
+            app.events.unhandled_counts().get("MarketDataType"),
+            Some(&1)
+        );
+        assert_eq!(closes(&app, "AAPL").len(), 5);
+    }
+
+    #[test]
+    fn test_stream_builds_live_bar() {
+        let mock = MockTws::start();
+        // IB falling back to delayed data, then delayed open, high, low, last and volume
+        let market_data_type = ["58", "1", "-1", "3"].map(String::from).to_vec();
+        mock.on(
+            "AAPL",
+            vec![
+                Reply::Raw(market_data_type),
+                Reply::Price(76, 142.0),
+                Reply::Price(72, 143.5),
+                Reply::Price(73, 141.0),
+                Reply::Price(68, 143.0),
+                Reply::Size(74, 1200),
+            ],
+        )
+        .on(
+            "MSFT",
+            vec![Reply::Error(
+                354,
+                "Requested market data is not subscribed".to_string(),
+            )],
+        )
+        .on(
+            "NVDA",
+            vec![Reply::Error(
+                101,
+                "Max number of tickers has been reached".to_string(),
+            )],
+        );
+        let mut app = connect(&mock, 40);
+        let session = NaiveDate::from_ymd(2022, 6, 24);
+        let timestamp = calendar::close_timestamp(session);
+        let yesterday = calendar::close_timestamp(NaiveDate::from_ymd(2022, 6, 23));
+        let stale = Quote {
+            timestamp: yesterday,
+            open: 280.0,
+            close: 281.0,
+            ..Quote::default()
+        };
+        app.db.upsert_live_daily("MSFT", &stale).unwrap();
+        let tickers = ["AAPL".to_string(), "MSFT".to_string(), "NVDA".to_string()];
+        // market data and real-time bars for each
+        assert!(app.stream(&tickers, session, false, true, 5).is_err());
+        assert!(app.streams.is_empty());
+        // AAPL's market data and real-time bars, then MSFT's, then NVDA's
+        let first = app.req_id + 1;
+        app.stream(&tickers, session, false, true, 6).unwrap();
+
+        let deadline = Instant::now() + time::Duration::from_secs(10);
+        while app.streams.len() > 2 || app.live["AAPL"].quote.volume == 0 {
+            assert!(Instant::now() < deadline, "timed out");
+            app.process_ib_response().unwrap();
+        }
+        let updates = app.take_live_updates().unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].0, "AAPL");
+        assert!(app.take_live_updates().unwrap().is_empty());
+
+        let persisted = app.db.get_live_daily("AAPL", timestamp).unwrap().unwrap();
+        assert_eq!(
+            (
+                persisted.open,
+                persisted.high,
+                persisted.low,
+                persisted.close
+            ),
+            (142.0, 143.5, 141.0, 143.0)
+        );
+        assert_eq!(persisted.volume, 120_000);
+        assert!(app.db.get_live_daily("MSFT", yesterday).unwrap().is_none());
+        let dropped: Vec<&str> = app
+            .dropped_streams()
+            .iter()
+            .map(|(ticker, _)| ticker.as_str())
+            .collect();
+        assert_eq!(dropped, vec!["MSFT", "NVDA"]);
+        // stream subscribes to the market data type rather than leaving it unhandled
+        assert!(app
+            .events
+            .unhandled_counts()
+            .get("MarketDataType")
+            .is_none());
+        // their real-time bars went with them
+        let cancelled = |count: usize| -> Vec<i32> {
+            while mock.cancellations().len() < count {
+                assert!(Instant::now() < deadline, "timed out");
+                thread::sleep(time::Duration::from_millis(10));
+            }
+            let mut req_ids = mock.cancellations();
+            req_ids.sort();
+            req_ids
+        };
+        assert_eq!(cancelled(2), vec![first + 3, first + 5]);
+
+        assert!(app.is_streaming());
+        app.stop_streaming().unwrap();
+        assert!(!app.is_streaming());
+        assert_eq!(cancelled(4), vec![first, first + 1, first + 3, first + 5]);
+    }
+
+    #[test]
+    fn test_resolve_pins_con_id() {
+        let mock = MockTws::start();
+        let cash = |con_id: i32, primary_exchange: &str| ContractInfo {
+            symbol: "CASH".to_string(),
+            con_id,
+            primary_exchange: primary_exchange.to_string(),
+            long_name: "PATHWARD FINANCIAL INC".to_string(),
+            industry: "Financial".to_string(),
+            category: "Banks".to_string(),
+            subcategory: "Commercial Banks".to_string(),
+        };
+        mock.on(
+            "CASH",
+            vec![Reply::Contracts(vec![
+                cash(8314, "NASDAQ"),
+                cash(9999, "IBIS"),
+            ])],
+        )
+        .on("CASH", vec![Reply::Bars(recorded("MSFT"))]);
+        let mut app = connect(&mock, 40);
+        app.add_ticker_to_resolve("CASH".to_string());
+        app.add_ticker_to_resolve("GONE".to_string());
+        drive(&mut app);
+        // a failed lookup doesn't keep the ticker from being fetched by symbol
+        assert!(app.db.get_permanent_failures("ibkr").unwrap().is_empty());
+        assert!(app
+            .db
+            .get_permanent_failures(RESOLVE_LEDGER)
+            .unwrap()
+            .contains("GONE"));
+
+        let row = app.db.get_contract("CASH").unwrap().unwrap();
+        assert_eq!(row.con_id, 8314);
+        assert_eq!(row.primary_exchange, "NASDAQ");
+        assert_eq!(row.long_name, "PATHWARD FINANCIAL INC");
+        assert_eq!(row.industry, "Financial");
+        assert_eq!(row.currency, "USD");
+        assert!(mock.requests().is_empty());
+        assert_eq!(mock.contract_requests()[0].symbol, "CASH");
+
+        app.add_ticker_to_request_queue("CASH".to_string());
+        drive(&mut app);
+        let requests = mock.requests();
+        assert_eq!(requests[0].con_id, 8314);
+        assert_eq!(requests[0].primary_exchange, "NASDAQ");
+        assert_eq!(closes(&app, "CASH").len(), 5);
+    }
+
+    #[test]
+    fn test_intraday_bars_are_chunked() {
+        let mock = MockTws::start();
+        // the chunks overlap, so the same bars come back twice
+        mock.on("AAPL", vec![Reply::Bars(recorded("AAPL_1h"))])
+            .on("AAPL", vec![Reply::Bars(recorded("AAPL_1h"))]);
+        let mut app = connect(&mock, 40);
+        app.add_bars_request("AAPL".to_string(), BarSize::Hour1, 40);
+        drive(&mut app);
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].bar_size, "1 hour");
+        assert_eq!(requests[0].duration, "1 M");
+        assert_ne!(requests[0].end_date_time, requests[1].end_date_time);
+        let bars = app
+            .db
+            .get_candles("AAPL", BarSize::Hour1, Range::default())
+            .unwrap();
+        assert_eq!(bars.len(), 7);
+        assert_eq!(bars[0].quote.timestamp, 1656077400);
+        assert_eq!(bars[6].quote.close, 142.0);
+        // nothing leaked into the daily table
+        assert!(closes(&app, "AAPL").is_empty());
+    }
+
+    #[test]
+    fn test_forming_bars_wait_for_their_close() {
+        let mock = MockTws::start();
+        let mut bars = recorded("AAPL_1h");
+        let mut forming = bars[bars.len() - 1].clone();
+        forming.date = (Utc::now().timestamp() - 60).to_string();
+        bars.push(forming);
+        mock.on("AAPL", vec![Reply::Bars(bars)]);
+        let mut app = connect(&mock, 40);
+        app.add_bars_request("AAPL".to_string(), BarSize::Hour1, 1);
+        drive(&mut app);
+        let last = app
+            .db
+            .get_last_bar("AAPL", BarSize::Hour1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(last.quote.timestamp, 1656097200);
+
+        let forming = Quote {
+            timestamp: Utc::now().timestamp() - 60,
+            ..last.quote
+        };
+        app.db
+            .insert_bars("AAPL", BarSize::Hour1, &[forming])
+            .unwrap();
+        app.add_incremental_bars("AAPL".to_string(), BarSize::Hour1)
+            .unwrap();
+        assert!(app.scheduler.is_idle());
+        app.force = true;
+        app.add_incremental_bars("AAPL".to_string(), BarSize::Hour1)
+            .unwrap();
+        assert!(!app.scheduler.is_idle());
+    }
+
+    #[test]
+    fn test_pacing_violation_is_retried() {
+        let mock = MockTws::start();
+        mock.on(
+            "AAPL",
+            vec![Reply::Error(
+                162,
+                "Historical Market Data Service error message:Historical data request pacing violation"
+                    .to_string(),
+            )],
+        )
+        .on("AAPL", vec![Reply::Bars(recorded("AAPL"))]);
+        let mut app = connect(&mock, 40);
+        app.scheduler.pacing_backoff = time::Duration::from_millis(20);
+        app.scheduler.identical_request_gap = time::Duration::from_millis(0);
+        app.add_ticker_to_request_queue("AAPL".to_string());
+        drive(&mut app);
+
+        assert_eq!(mock.requests().len(), 2);
+        assert_eq!(closes(&app, "AAPL").len(), 5);
+    }
+
+    #[test]
+    fn test_adjusted_closes_and_dividends() {
+        let mock = MockTws::start();
+        mock.on("AAPL", vec![Reply::Bars(recorded("AAPL"))])
+            .on("AAPL", vec![Reply::Bars(recorded("AAPL_adjusted"))]);
+        let mut app = connect(&mock, 40);
+        app.adjusted = true;
+        app.add_ticker_to_request_queue("AAPL".to_string());
+        drive(&mut app);
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].what_to_show, "ADJUSTED_LAST");
+        assert_eq!(requests[1].end_date_time, "");
+        // raw closes are untouched
+        assert_eq!(
+            closes(&app, "AAPL"),
+            vec![130.06, 131.56, 135.87, 135.35, 138.27]
+        );
+        let rows = app
+            .db
+            .get_candles("AAPL", BarSize::Day1, Range::default())
+            .unwrap();
+        let adjcloses: Vec<f64> = rows.iter().map(|row| row.quote.adjclose).collect();
+        assert_eq!(adjcloses, vec![129.83, 131.33, 135.87, 135.35, 138.27]);
+
+        let actions = app.db.get_corporate_actions("AAPL").unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].kind, ActionKind::Dividend);
+        assert_eq!(actions[0].timestamp, rows[2].quote.timestamp);
+        assert!((actions[0].value - 0.23).abs() < 0.01);
+    }
+}
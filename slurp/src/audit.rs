@@ -0,0 +1,180 @@
+use chrono::NaiveDate;
+
+use crate::calendar;
+use crate::corporate::{self, ActionKind, CorporateAction};
+use crate::db::QuoteRow;
+
+/// Something wrong with a ticker's daily candles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueKind {
+    /// A session between two candles we have no candle for
+    Missing,
+    /// More than one candle for the same session
+    Duplicate,
+    /// A candle on a weekend or holiday
+    NotASession,
+    ZeroVolume,
+    /// high < low, open/close outside the range or a non-positive price
+    Ohlc,
+    /// The close moved by a split ratio overnight without a split on record
+    SplitJump,
+    /// The last candle is too many sessions old
+    Stale,
+}
+
+impl IssueKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IssueKind::Missing => "missing",
+            IssueKind::Duplicate => "duplicate",
+            IssueKind::NotASession => "not_a_session",
+            IssueKind::ZeroVolume => "zero_volume",
+            IssueKind::Ohlc => "ohlc",
+            IssueKind::SplitJump => "split_jump",
+            IssueKind::Stale => "stale",
+        }
+    }
+
+    /// Whether refetching the full history should fix it
+    pub fn needs_refetch(&self) -> bool {
+        !matches!(self, IssueKind::ZeroVolume | IssueKind::Stale)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    pub date: NaiveDate,
+    pub kind: IssueKind,
+    pub detail: String,
+}
+
+fn issue(date: NaiveDate, kind: IssueKind, detail: String) -> Issue {
+    Issue { date, kind, detail }
+}
+
+/// Check `rows` (oldest first) against the session calendar. Candles more than `stale_sessions`
+/// behind `last_session` are stale.
+pub fn audit(
+    rows: &[QuoteRow],
+    actions: &[CorporateAction],
+    last_session: NaiveDate,
+    stale_sessions: i64,
+) -> Vec<Issue> {
+    let mut issues = vec![];
+    let dates: Vec<NaiveDate> = rows
+        .iter()
+        .map(|row| calendar::ny_date(row.quote.timestamp))
+        .collect();
+    for (row, date) in rows.iter().zip(dates.iter()) {
+        let q = &row.quote;
+        if !calendar::is_trading_day(*date) {
+            issues.push(issue(*date, IssueKind::NotASession, String::new()));
+        }
+        if q.volume == 0 {
+            issues.push(issue(*date, IssueKind::ZeroVolume, String::new()));
+        }
+        let bad_range = q.high < q.low
+            || q.open < q.low
+            || q.open > q.high
+            || q.close < q.low
+            || q.close > q.high;
+        if bad_range || q.low <= 0.0 {
+            let detail = format!("o={} h={} l={} c={}", q.open, q.high, q.low, q.close);
+            issues.push(issue(*date, IssueKind::Ohlc, detail));
+        }
+    }
+    for i in 1..rows.len() {
+        let (prev, cur) = (dates[i - 1], dates[i]);
+        if prev == cur {
+            issues.push(issue(cur, IssueKind::Duplicate, String::new()));
+            continue;
+        }
+        let mut date = prev.succ();
+        while date < cur {
+            if calendar::is_trading_day(date) {
+                issues.push(issue(date, IssueKind::Missing, String::new()));
+            }
+            date = date.succ();
+        }
+        let (before, after) = (&rows[i - 1].quote, &rows[i].quote);
+        if let Some(ratio) = corporate::split_ratio(before.close, after.close) {
+            let recorded = actions
+                .iter()
+                .any(|a| a.kind == ActionKind::Split && a.timestamp == after.timestamp);
+            if !recorded {
+                let detail = format!("{} => {} ({}:1)", before.close, after.close, ratio);
+                issues.push(issue(cur, IssueKind::SplitJump, detail));
+            }
+        }
+    }
+    if let Some(last) = dates.last() {
+        let behind = calendar::sessions_between(*last, last_session);
+        if behind > stale_sessions {
+            let detail = format!("{} sessions behind", behind);
+            issues.push(issue(*last, IssueKind::Stale, detail));
+        }
+    }
+    issues.sort_by_key(|i| i.date);
+    issues
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::quote::Quote;
+
+    fn candle(date: NaiveDate, open: f64, high: f64, low: f64, close: f64) -> QuoteRow {
+        QuoteRow {
+            id: 0,
+            quote: Quote {
+                timestamp: calendar::close_timestamp(date),
+                open,
+                high,
+                low,
+                close,
+                volume: 1000,
+                ..Quote::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_audit() {
+        let ymd = NaiveDate::from_ymd;
+        let mut rows = vec![
+            candle(ymd(2022, 5, 26), 10.0, 11.0, 9.0, 10.0),
+            candle(ymd(2022, 5, 27), 10.0, 11.0, 9.0, 12.0), // close above the high
+            // Memorial Day, then a missing Tuesday
+            candle(ymd(2022, 6, 1), 6.0, 6.5, 5.5, 6.0), // 2:1 jump
+            candle(ymd(2022, 6, 1), 6.0, 6.5, 5.5, 6.0),
+            candle(ymd(2022, 6, 4), 6.0, 6.5, 5.5, 6.0), // Saturday
+        ];
+        rows[0].quote.volume = 0;
+        let issues = audit(&rows, &[], ymd(2022, 6, 10), 3);
+        let kinds: Vec<(NaiveDate, IssueKind)> = issues.iter().map(|i| (i.date, i.kind)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (ymd(2022, 5, 26), IssueKind::ZeroVolume),
+                (ymd(2022, 5, 27), IssueKind::Ohlc),
+                (ymd(2022, 5, 31), IssueKind::Missing),
+                (ymd(2022, 6, 1), IssueKind::SplitJump),
+                (ymd(2022, 6, 1), IssueKind::Duplicate),
+                (ymd(2022, 6, 2), IssueKind::Missing),
+                (ymd(2022, 6, 3), IssueKind::Missing),
+                (ymd(2022, 6, 4), IssueKind::NotASession),
+                (ymd(2022, 6, 4), IssueKind::Stale),
+            ]
+        );
+        assert_eq!(issues[8].detail, "5 sessions behind");
+
+        // a recorded split isn't suspicious
+        let split = CorporateAction {
+            timestamp: rows[2].quote.timestamp,
+            kind: ActionKind::Split,
+            value: 2.0,
+        };
+        let issues = audit(&rows[1..3], &[split], ymd(2022, 6, 1), 3);
+        assert!(issues.iter().all(|i| i.kind != IssueKind::SplitJump));
+    }
+}
@@ -0,0 +1,273 @@
+use crate::db::QuoteRow;
+use crate::indicators::{Indicator, Indicators};
+use crate::screen::{Screen, Side};
+
+/// How trades are entered and exited once a screen signals
+#[derive(Debug, Clone, Copy)]
+pub struct Rules {
+    /// Period of the ATR the stop is sized from
+    pub atr_period: usize,
+    /// Stop distance from the entry, in ATRs
+    pub atr_stop: f64,
+    /// Exit at the close after this many bars in the trade
+    pub max_bars: usize,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            atr_period: 14,
+            atr_stop: 2.0,
+            max_bars: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    Stop,
+    Time,
+    /// Still open when the data ran out, marked at the last close
+    End,
+}
+
+impl ExitReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExitReason::Stop => "stop",
+            ExitReason::Time => "time",
+            ExitReason::End => "end",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Trade {
+    pub ticker: String,
+    pub signal: String,
+    pub side: Side,
+    pub entry_timestamp: i64,
+    pub entry: f64,
+    pub stop: f64,
+    pub exit_timestamp: i64,
+    pub exit: f64,
+    pub reason: ExitReason,
+    pub bars: usize,
+}
+
+impl Trade {
+    /// Fractional return, i.e. 0.05 for +5%
+    pub fn return_pct(&self) -> f64 {
+        let change = (self.exit - self.entry) / self.entry;
+        match self.side {
+            Side::Long => change,
+            Side::Short => -change,
+        }
+    }
+
+    /// Profit in multiples of the initial risk (entry to stop)
+    pub fn r_multiple(&self) -> f64 {
+        let risk = (self.entry - self.stop).abs();
+        if risk == 0.0 {
+            return 0.0;
+        }
+        self.return_pct() * self.entry / risk
+    }
+}
+
+/// Replay `rows` bar by bar: whenever a signal holds at a close, enter at the next open with a
+/// stop `atr_stop` ATRs away, and hold until the stop is hit or `max_bars` have passed. One
+/// trade at a time.
+pub fn backtest(screen: &Screen, rules: &Rules, ticker: &str, rows: &[QuoteRow]) -> Vec<Trade> {
+    let atr = Indicator::Atr(rules.atr_period);
+    let mut wanted = screen.indicators();
+    wanted.push(atr);
+    let indicators = Indicators::compute(rows, &wanted);
+    let mut trades = vec![];
+    let mut idx = 0;
+    while idx + 1 < rows.len() {
+        let signal = match screen.signal_at(&indicators, idx) {
+            Some(signal) => signal,
+            None => {
+                idx += 1;
+                continue;
+            }
+        };
+        let atr = match indicators.value(&atr, idx) {
+            Some(atr) => atr,
+            None => {
+                idx += 1;
+                continue;
+            }
+        };
+        let entry_idx = idx + 1;
+        let entry = rows[entry_idx].quote.open;
+        let stop = match signal.side {
+            Side::Long => entry - atr * rules.atr_stop,
+            Side::Short => entry + atr * rules.atr_stop,
+        };
+        let (exit_idx, exit, reason) = exit(rows, entry_idx, signal.side, stop, rules.max_bars);
+        trades.push(Trade {
+            ticker: ticker.to_string(),
+            signal: signal.name.clone(),
+            side: signal.side,
+            entry_timestamp: rows[entry_idx].quote.timestamp,
+            entry,
+            stop,
+            exit_timestamp: rows[exit_idx].quote.timestamp,
+            exit,
+            reason,
+            bars: exit_idx - entry_idx + 1,
+        });
+        // the exit bar's close may signal the next entry
+        idx = exit_idx;
+    }
+    trades
+}
+
+/// Where and why a trade entered at `entry_idx` gets out
+fn exit(
+    rows: &[QuoteRow],
+    entry_idx: usize,
+    side: Side,
+    stop: f64,
+    max_bars: usize,
+) -> (usize, f64, ExitReason) {
+    for (idx, row) in rows.iter().enumerate().skip(entry_idx) {
+        let q = &row.quote;
+        // gapping through the stop fills at the open rather than the stop
+        match side {
+            Side::Long if q.low <= stop => return (idx, q.open.min(stop), ExitReason::Stop),
+            Side::Short if q.high >= stop => return (idx, q.open.max(stop), ExitReason::Stop),
+            _ => {}
+        }
+        if idx + 1 - entry_idx >= max_bars {
+            return (idx, q.close, ExitReason::Time);
+        }
+    }
+    let last = rows.len() - 1;
+    (last, rows[last].quote.close, ExitReason::End)
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub trades: usize,
+    pub wins: usize,
+    pub win_rate: f64,
+    /// Average return per trade
+    pub expectancy: f64,
+    pub avg_r: f64,
+    /// Largest peak to trough drop of the summed per-trade returns, in exit order
+    pub max_drawdown: f64,
+}
+
+pub fn summarize(trades: &[Trade]) -> Summary {
+    if trades.is_empty() {
+        return Summary::default();
+    }
+    let n = trades.len() as f64;
+    let wins = trades.iter().filter(|t| t.return_pct() > 0.0).count();
+    let mut by_exit: Vec<&Trade> = trades.iter().collect();
+    by_exit.sort_by_key(|t| t.exit_timestamp);
+    let (mut equity, mut peak, mut max_drawdown) = (0.0, 0.0, 0.0);
+    for trade in by_exit {
+        equity += trade.return_pct();
+        peak = f64::max(peak, equity);
+        max_drawdown = f64::max(max_drawdown, peak - equity);
+    }
+    Summary {
+        trades: trades.len(),
+        wins,
+        win_rate: wins as f64 / n,
+        expectancy: trades.iter().map(|t| t.return_pct()).sum::<f64>() / n,
+        avg_r: trades.iter().map(|t| t.r_multiple()).sum::<f64>() / n,
+        max_drawdown,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::quote::Quote;
+
+    fn bar(id: i32, open: f64, high: f64, low: f64, close: f64) -> QuoteRow {
+        QuoteRow {
+            id,
+            quote: Quote {
+                timestamp: id as i64,
+                open,
+                high,
+                low,
+                close,
+                ..Quote::default()
+            },
+        }
+    }
+
+    fn screen(when: &str) -> Screen {
+        let toml = format!("[[signal]]\nname = \"s\"\nwhen = \"{}\"", when);
+        Screen::parse("test", &toml).unwrap()
+    }
+
+    #[test]
+    fn test_stop_and_time_exits() {
+        // true range is 2 everywhere, so atr(2) = 2 and the stop sits 2 below the entry
+        let rows = vec![
+            bar(0, 10.0, 11.0, 9.0, 10.0),
+            bar(1, 10.0, 11.0, 9.0, 10.0),
+            bar(2, 10.0, 11.0, 9.0, 10.0), // signal
+            bar(3, 10.5, 11.5, 9.5, 11.0), // entry at 10.5, stop 8.5
+            bar(4, 11.0, 12.0, 10.0, 11.0),
+            bar(5, 11.0, 12.0, 10.0, 11.5), // time stop, and a new signal
+            bar(6, 11.0, 12.0, 10.0, 11.0), // entry at 11, stop 9
+            bar(7, 9.5, 10.0, 8.0, 9.0),    // stopped out
+        ];
+        let rules = Rules {
+            atr_period: 2,
+            atr_stop: 1.0,
+            max_bars: 3,
+        };
+        let trades = backtest(&screen("close >= 10 and open >= 10"), &rules, "X", &rows);
+        assert_eq!(trades.len(), 2);
+
+        assert_eq!(trades[0].entry_timestamp, 3);
+        assert_eq!(trades[0].entry, 10.5);
+        assert_eq!(trades[0].stop, 8.5);
+        assert_eq!((trades[0].exit_timestamp, trades[0].exit), (5, 11.5));
+        assert_eq!(trades[0].reason, ExitReason::Time);
+        assert_eq!(trades[0].r_multiple(), 0.5);
+
+        assert_eq!(trades[1].entry, 11.0);
+        assert_eq!((trades[1].exit_timestamp, trades[1].exit), (7, 9.0));
+        assert_eq!(trades[1].reason, ExitReason::Stop);
+
+        let summary = summarize(&trades);
+        assert_eq!(summary.trades, 2);
+        assert_eq!(summary.win_rate, 0.5);
+        assert!((summary.expectancy - (1.0 / 10.5 - 2.0 / 11.0) / 2.0).abs() < 1e-12);
+        assert!((summary.max_drawdown - 2.0 / 11.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_short_gaps_through_stop() {
+        let rows = vec![
+            bar(0, 10.0, 11.0, 9.0, 10.0),
+            bar(1, 10.0, 11.0, 9.0, 10.0),
+            bar(2, 10.0, 11.0, 9.0, 10.0),
+            bar(3, 10.0, 10.5, 9.5, 10.0),  // short at 10, stop 12
+            bar(4, 13.0, 14.0, 12.5, 13.5), // gaps over the stop
+        ];
+        let toml = "[[signal]]\nname = \"s\"\nside = \"short\"\nwhen = \"close == 10\"";
+        let screen = Screen::parse("test", toml).unwrap();
+        let rules = Rules {
+            atr_period: 2,
+            atr_stop: 1.0,
+            max_bars: 10,
+        };
+        let trades = backtest(&screen, &rules, "X", &rows);
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].exit, 13.0);
+        assert_eq!(trades[0].reason, ExitReason::Stop);
+        assert!((trades[0].return_pct() + 0.3).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,157 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::calendar;
+
+/// Candle sizes we can fetch and store. Daily candles live in the `daily` table, everything else in
+/// `bars` keyed by `name()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BarSize {
+    Min1,
+    Min5,
+    Min15,
+    Min30,
+    Hour1,
+    Hour4,
+    Day1,
+}
+
+impl BarSize {
+    /// As stored in `bars.bar_size` and accepted on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            BarSize::Min1 => "1m",
+            BarSize::Min5 => "5m",
+            BarSize::Min15 => "15m",
+            BarSize::Min30 => "30m",
+            BarSize::Hour1 => "1h",
+            BarSize::Hour4 => "4h",
+            BarSize::Day1 => "1d",
+        }
+    }
+
+    /// IB's `barSizeSetting`
+    pub fn ib_str(&self) -> &'static str {
+        match self {
+            BarSize::Min1 => "1 min",
+            BarSize::Min5 => "5 mins",
+            BarSize::Min15 => "15 mins",
+            BarSize::Min30 => "30 mins",
+            BarSize::Hour1 => "1 hour",
+            BarSize::Hour4 => "4 hours",
+            BarSize::Day1 => "1 day",
+        }
+    }
+
+    pub fn is_intraday(&self) -> bool {
+        *self != BarSize::Day1
+    }
+
+    pub fn seconds(&self) -> i64 {
+        match self {
+            BarSize::Min1 => 60,
+            BarSize::Min5 => 5 * 60,
+            BarSize::Min15 => 15 * 60,
+            BarSize::Min30 => 30 * 60,
+            BarSize::Hour1 => 3600,
+            BarSize::Hour4 => 4 * 3600,
+            BarSize::Day1 => 24 * 3600,
+        }
+    }
+
+    /// Whether the intraday candle starting at `timestamp` had closed by `now`. The session's close
+    /// cuts the last one of the day short, i.e. the 13:30 4h candle closes at 16:00.
+    pub fn has_closed(&self, timestamp: i64, now: i64) -> bool {
+        let mut end = timestamp + self.seconds();
+        let close = calendar::session_close(calendar::ny_date(timestamp)).timestamp();
+        if timestamp < close {
+            end = end.min(close);
+        }
+        end <= now
+    }
+
+    /// The longest duration IB will serve in one request for this bar size, and how many calendar
+    /// days to step back between requests so consecutive chunks overlap rather than leave gaps
+    pub fn chunk(&self) -> (&'static str, i64) {
+        match self {
+            BarSize::Min1 => ("1 D", 1),
+            BarSize::Min5 | BarSize::Min15 => ("1 W", 7),
+            BarSize::Min30 | BarSize::Hour1 | BarSize::Hour4 => ("1 M", 28),
+            BarSize::Day1 => ("1 Y", 365),
+        }
+    }
+
+    /// End times, newest first, of the requests needed to cover `days` of history up to `end`
+    pub fn chunk_ends(&self, end: DateTime<Utc>, days: i64) -> Vec<DateTime<Utc>> {
+        let (_, step) = self.chunk();
+        let count = std::cmp::max(1, (days + step - 1) / step);
+        (0..count).map(|i| end - Duration::days(i * step)).collect()
+    }
+}
+
+impl fmt::Display for BarSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for BarSize {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let sizes = [
+            BarSize::Min1,
+            BarSize::Min5,
+            BarSize::Min15,
+            BarSize::Min30,
+            BarSize::Hour1,
+            BarSize::Hour4,
+            BarSize::Day1,
+        ];
+        let s = s.to_lowercase();
+        sizes
+            .into_iter()
+            .find(|size| size.name() == s || size.ib_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("unknown bar size '{}' (1m|5m|15m|30m|1h|4h|1d)", s))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("1h".parse::<BarSize>().unwrap(), BarSize::Hour1);
+        assert_eq!("5 mins".parse::<BarSize>().unwrap(), BarSize::Min5);
+        assert_eq!("4H".parse::<BarSize>().unwrap(), BarSize::Hour4);
+        assert!("2h".parse::<BarSize>().is_err());
+    }
+
+    #[test]
+    fn test_chunk_ends() {
+        let end = Utc.ymd(2022, 6, 24).and_hms(20, 0, 0);
+        let ends = BarSize::Hour1.chunk_ends(end, 60);
+        assert_eq!(
+            ends,
+            vec![end, end - Duration::days(28), end - Duration::days(56)]
+        );
+        assert_eq!(BarSize::Min1.chunk_ends(end, 5).len(), 5);
+        assert_eq!(BarSize::Min5.chunk_ends(end, 0), vec![end]);
+    }
+
+    #[test]
+    fn test_has_closed() {
+        // 2022-06-24 13:30 New York time
+        let start = Utc.ymd(2022, 6, 24).and_hms(17, 30, 0).timestamp();
+        assert!(!BarSize::Hour1.has_closed(start, start + 3599));
+        assert!(BarSize::Hour1.has_closed(start, start + 3600));
+        // the last 4h candle of the session ends at the close
+        assert!(!BarSize::Hour4.has_closed(start, start + 2 * 3600));
+        assert!(BarSize::Hour4.has_closed(start, start + 5 * 1800));
+        // extended hours candles run their full length
+        let after_hours = start + 3 * 3600;
+        assert!(!BarSize::Hour1.has_closed(after_hours, after_hours + 1800));
+    }
+}
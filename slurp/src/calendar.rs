@@ -0,0 +1,297 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::{America::New_York, Tz};
+
+/// Regular session close (New York time)
+const CLOSE: (u32, u32) = (16, 0);
+/// Early close on the half days around Independence Day, Thanksgiving and Christmas
+const EARLY_CLOSE: (u32, u32) = (13, 0);
+
+/// How long after the close before we trust a session's daily candle to be final
+const SETTLE_MINUTES: i64 = 30;
+
+/// One-off closures that don't follow the usual holiday rules
+const SPECIAL_CLOSURES: [(i32, u32, u32); 4] = [
+    (2012, 10, 29), // Hurricane Sandy
+    (2012, 10, 30),
+    (2018, 12, 5), // President Bush's funeral
+    (2025, 1, 9),  // President Carter's funeral
+];
+
+/// The `n`th (1-based) `weekday` of the month
+fn nth_weekday(year: i32, month: u32, weekday: Weekday, n: u8) -> NaiveDate {
+    NaiveDate::from_weekday_of_month(year, month, weekday, n)
+}
+
+fn last_weekday(year: i32, month: u32, weekday: Weekday) -> NaiveDate {
+    let mut date = nth_weekday(year, month, weekday, 4);
+    while (date + Duration::days(7)).month() == month {
+        date += Duration::days(7);
+    }
+    date
+}
+
+/// Anonymous Gregorian algorithm
+fn easter(year: i32) -> NaiveDate {
+    let a = year % 19;
+    let b = year / 100;
+    let c = year % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = (h + l - 7 * m + 114) / 31;
+    let day = (h + l - 7 * m + 114) % 31 + 1;
+    NaiveDate::from_ymd(year, month as u32, day as u32)
+}
+
+/// Holidays falling on a Saturday are observed the Friday before, Sunday ones the Monday after
+fn observed(date: NaiveDate) -> NaiveDate {
+    match date.weekday() {
+        Weekday::Sat => date - Duration::days(1),
+        Weekday::Sun => date + Duration::days(1),
+        _ => date,
+    }
+}
+
+/// NYSE full-day holidays in `year`
+pub fn holidays(year: i32) -> Vec<NaiveDate> {
+    let mut days = vec![];
+    // New Year's Day isn't moved back into the previous year when it's a Saturday
+    let new_year = NaiveDate::from_ymd(year, 1, 1);
+    if new_year.weekday() != Weekday::Sat {
+        days.push(observed(new_year));
+    }
+    days.push(nth_weekday(year, 1, Weekday::Mon, 3)); // Martin Luther King Jr. Day
+    days.push(nth_weekday(year, 2, Weekday::Mon, 3)); // Washington's Birthday
+    days.push(easter(year) - Duration::days(2)); // Good Friday
+    days.push(last_weekday(year, 5, Weekday::Mon)); // Memorial Day
+    if year >= 2022 {
+        days.push(observed(NaiveDate::from_ymd(year, 6, 19))); // Juneteenth
+    }
+    days.push(observed(NaiveDate::from_ymd(year, 7, 4)));
+    days.push(nth_weekday(year, 9, Weekday::Mon, 1)); // Labor Day
+    days.push(nth_weekday(year, 11, Weekday::Thu, 4)); // Thanksgiving
+    days.push(observed(NaiveDate::from_ymd(year, 12, 25)));
+    days.extend(
+        SPECIAL_CLOSURES
+            .iter()
+            .filter(|(y, _, _)| *y == year)
+            .map(|(y, m, d)| NaiveDate::from_ymd(*y, *m, *d)),
+    );
+    days
+}
+
+/// Sessions in `year` that close at 1PM
+pub fn half_days(year: i32) -> Vec<NaiveDate> {
+    let candidates = [
+        NaiveDate::from_ymd(year, 7, 3),
+        nth_weekday(year, 11, Weekday::Thu, 4) + Duration::days(1),
+        NaiveDate::from_ymd(year, 12, 24),
+    ];
+    candidates
+        .into_iter()
+        .filter(|d| is_trading_day(*d))
+        .collect()
+}
+
+pub fn is_holiday(date: NaiveDate) -> bool {
+    holidays(date.year()).contains(&date)
+}
+
+pub fn is_half_day(date: NaiveDate) -> bool {
+    half_days(date.year()).contains(&date)
+}
+
+pub fn is_trading_day(date: NaiveDate) -> bool {
+    !matches!(date.weekday(), Weekday::Sat | Weekday::Sun) && !is_holiday(date)
+}
+
+pub fn prev_trading_day(date: NaiveDate) -> NaiveDate {
+    let mut date = date.pred();
+    while !is_trading_day(date) {
+        date = date.pred();
+    }
+    date
+}
+
+fn ny_time(date: NaiveDate, (hour, min): (u32, u32)) -> DateTime<Tz> {
+    New_York
+        .from_local_datetime(&date.and_hms(hour, min, 0))
+        .earliest()
+        .expect("market hours never fall in a DST gap")
+}
+
+/// The New York calendar date of `timestamp`
+pub fn ny_date(timestamp: i64) -> NaiveDate {
+    New_York.timestamp(timestamp, 0).date().naive_local()
+}
+
+pub fn session_close(date: NaiveDate) -> DateTime<Tz> {
+    ny_time(
+        date,
+        if is_half_day(date) {
+            EARLY_CLOSE
+        } else {
+            CLOSE
+        },
+    )
+}
+
+/// Daily candles are keyed on 4PM New York time of their session, even on half days, so the key
+/// doesn't depend on the calendar being right
+pub fn close_timestamp(date: NaiveDate) -> i64 {
+    ny_time(date, CLOSE).timestamp()
+}
+
+/// The most recent session that had closed by `now`
+fn last_closed_session(now: DateTime<Tz>) -> NaiveDate {
+    let today = now.date().naive_local();
+    if is_trading_day(today) && now >= session_close(today) {
+        return today;
+    }
+    prev_trading_day(today)
+}
+
+/// The most recent session whose daily candle should be final at `now`
+pub fn last_completed_session(now: DateTime<Utc>) -> NaiveDate {
+    last_closed_session((now - Duration::minutes(SETTLE_MINUTES)).with_timezone(&New_York))
+}
+
+/// The daily candle key of the last session closed as of `s`: either a date ("2022-06-10",
+/// meaning after that day's close) or a New York time ("2022-06-10 12:00", before it)
+pub fn parse_as_of(s: &str) -> anyhow::Result<i64> {
+    let s = s.trim();
+    let local = match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(date) => date.and_hms(23, 59, 59),
+        Err(_) => NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M")
+            .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M"))
+            .map_err(|_| {
+                anyhow::anyhow!("bad date '{}', expected YYYY-MM-DD or YYYY-MM-DD HH:MM", s)
+            })?,
+    };
+    let now = New_York
+        .from_local_datetime(&local)
+        .earliest()
+        .ok_or_else(|| anyhow::anyhow!("'{}' doesn't exist in New York", s))?;
+    Ok(close_timestamp(last_closed_session(now)))
+}
+
+/// A YYYY-MM-DD date
+pub fn parse_date(s: &str) -> anyhow::Result<NaiveDate> {
+    NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d")
+        .map_err(|_| anyhow::anyhow!("bad date '{}', expected YYYY-MM-DD", s))
+}
+
+/// The first and last second of `date` in New York
+pub fn day_bounds(date: NaiveDate) -> (i64, i64) {
+    let start = ny_time(date, (0, 0)).timestamp();
+    (start, ny_time(date.succ(), (0, 0)).timestamp() - 1)
+}
+
+/// When the most recent completed session's candle settled, i.e. the end time to ask for
+pub fn last_settled_close(now: DateTime<Utc>) -> DateTime<Utc> {
+    let date = last_completed_session(now);
+    (session_close(date) + Duration::minutes(SETTLE_MINUTES)).with_timezone(&Utc)
+}
+
+/// Number of sessions after `after` up to and including `through`
+pub fn sessions_between(after: NaiveDate, through: NaiveDate) -> i64 {
+    let mut count = 0;
+    let mut date = after.succ();
+    while date <= through {
+        if is_trading_day(date) {
+            count += 1;
+        }
+        date = date.succ();
+    }
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ymd(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd(y, m, d)
+    }
+
+    #[test]
+    fn test_holidays() {
+        assert_eq!(
+            holidays(2022),
+            vec![
+                ymd(2022, 1, 17),
+                ymd(2022, 2, 21),
+                ymd(2022, 4, 15),
+                ymd(2022, 5, 30),
+                ymd(2022, 6, 20),
+                ymd(2022, 7, 4),
+                ymd(2022, 9, 5),
+                ymd(2022, 11, 24),
+                ymd(2022, 12, 26),
+            ]
+        );
+        assert_eq!(half_days(2022), vec![ymd(2022, 11, 25)]);
+        assert_eq!(
+            half_days(2024),
+            vec![ymd(2024, 7, 3), ymd(2024, 11, 29), ymd(2024, 12, 24)]
+        );
+        assert!(is_holiday(ymd(2021, 12, 24)));
+        assert!(is_holiday(ymd(2023, 1, 2)));
+        assert!(!is_trading_day(ymd(2022, 6, 25)));
+    }
+
+    #[test]
+    fn test_sessions() {
+        // DST: 4PM is 20:00 UTC in summer and 21:00 in winter
+        assert_eq!(close_timestamp(ymd(2022, 6, 23)), 1656014400);
+        assert_eq!(close_timestamp(ymd(2022, 12, 1)), 1669928400);
+        assert_eq!(
+            session_close(ymd(2022, 11, 25)).with_timezone(&Utc),
+            Utc.ymd(2022, 11, 25).and_hms(18, 0, 0)
+        );
+
+        // Tuesday after Memorial Day, before the close => the Friday before
+        let now = Utc.ymd(2022, 5, 31).and_hms(17, 0, 0);
+        assert_eq!(last_completed_session(now), ymd(2022, 5, 27));
+        // ...and after it's settled
+        let now = Utc.ymd(2022, 5, 31).and_hms(20, 30, 0);
+        assert_eq!(last_completed_session(now), ymd(2022, 5, 31));
+        // Sunday
+        let now = Utc.ymd(2022, 12, 4).and_hms(12, 0, 0);
+        assert_eq!(last_completed_session(now), ymd(2022, 12, 2));
+
+        // Friday's candle is in as of the weekend, but not Friday lunchtime
+        assert_eq!(
+            parse_as_of("2022-06-12").unwrap(),
+            close_timestamp(ymd(2022, 6, 10))
+        );
+        assert_eq!(
+            parse_as_of("2022-06-10 12:00").unwrap(),
+            close_timestamp(ymd(2022, 6, 9))
+        );
+        // the day after Thanksgiving closes at 1PM
+        assert_eq!(
+            parse_as_of("2022-11-25T13:30").unwrap(),
+            close_timestamp(ymd(2022, 11, 25))
+        );
+        assert!(parse_as_of("6/10/2022").is_err());
+
+        assert_eq!(parse_date("2022-06-10").unwrap(), ymd(2022, 6, 10));
+        assert!(parse_date("2022-06-10 12:00").is_err());
+        let (start, end) = day_bounds(ymd(2022, 6, 23));
+        assert_eq!(
+            (start, end),
+            (1656014400 - 16 * 3600, 1656014400 + 8 * 3600 - 1)
+        );
+
+        assert_eq!(sessions_between(ymd(2022, 5, 27), ymd(2022, 5, 30)), 0);
+        assert_eq!(sessions_between(ymd(2022, 5, 27), ymd(2022, 6, 3)), 4);
+    }
+}
@@ -0,0 +1,351 @@
+use std::str::FromStr;
+
+use crate::db::QuoteRow;
+use crate::indicators::{Indicator, Indicators};
+use crate::output::{Field, Record};
+use crate::screen::Condition;
+
+/// The full EMA stack, fastest first
+pub const STACK: [Indicator; 4] = [
+    Indicator::Ema(8),
+    Indicator::Ema(21),
+    Indicator::Ema(34),
+    Indicator::Ema(89),
+];
+/// What `--loose` falls back to when the full stack isn't ordered
+pub const LOOSE_STACK: [Indicator; 2] = [Indicator::Ema(8), Indicator::Ema(34)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Fast EMAs above slow ones: look for long pullbacks
+    Bull,
+    /// Fast EMAs below slow ones: look for short bounces
+    Bear,
+    None,
+}
+
+impl Direction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Direction::Bull => "bull",
+            Direction::Bear => "bear",
+            Direction::None => "none",
+        }
+    }
+}
+
+/// trend-candidates options
+#[derive(Debug, Clone)]
+pub struct Params {
+    /// Candles the EMA stack has to have held for
+    pub ema_period: usize,
+    pub loose: bool,
+    pub slow_stoch: Indicator,
+    pub adxr: Indicator,
+    pub rsi: Indicator,
+    /// Has to hold on the last candle, i.e. a liquidity floor
+    pub filter: Option<Condition>,
+}
+
+impl Params {
+    fn indicators(&self) -> Vec<Indicator> {
+        let mut wanted = STACK.to_vec();
+        wanted.extend([self.slow_stoch, self.adxr, self.rsi]);
+        if let Some(filter) = self.filter.as_ref() {
+            filter.indicators(&mut wanted);
+        }
+        wanted
+    }
+}
+
+/// One ticker's trend and oscillator readings at its last candle
+#[derive(Debug, Clone)]
+pub struct TrendCandidate {
+    pub ticker: String,
+    pub direction: Direction,
+    /// Only the loose stack is ordered
+    pub loose: bool,
+    /// How many candles, up to the last, the (strict or loose) stack has been ordered for
+    pub bars_held: usize,
+    pub close: f64,
+    pub stoch: f64,
+    pub adxr: Option<f64>,
+    pub rsi: Option<f64>,
+    /// `STACK` values at the last candle
+    pub emas: Vec<Option<f64>>,
+}
+
+impl TrendCandidate {
+    /// Evaluate `rows`, oldest first. `None` if there are too few of them to judge or they don't
+    /// pass the filter
+    pub fn evaluate(ticker: &str, rows: &[QuoteRow], params: &Params) -> Option<Self> {
+        if params.ema_period > rows.len() {
+            return None;
+        }
+        let indicators = Indicators::compute(rows, &params.indicators());
+        if let Some(filter) = params.filter.as_ref() {
+            if !filter.eval(&indicators, rows.len() - 1) {
+                return None;
+            }
+        }
+        let stoch = match indicators.last(&params.slow_stoch) {
+            Some(stoch) => stoch,
+            None => {
+                eprintln!("not enough quotes for a slow stochastic: {}", ticker);
+                return None;
+            }
+        };
+        let held = |stack: &[Indicator], descending: bool| {
+            (0..rows.len())
+                .rev()
+                .take_while(|i| indicators.ordered(stack, *i, descending))
+                .count()
+        };
+        let mut stacks = vec![(&STACK[..], false)];
+        if params.loose {
+            stacks.push((&LOOSE_STACK[..], true));
+        }
+        let (direction, loose, bars_held) = stacks
+            .into_iter()
+            .flat_map(|(stack, loose)| {
+                [
+                    (Direction::Bull, loose, held(stack, true)),
+                    (Direction::Bear, loose, held(stack, false)),
+                ]
+            })
+            .find(|(_, _, bars_held)| *bars_held >= params.ema_period)
+            .unwrap_or((Direction::None, false, 0));
+        Some(TrendCandidate {
+            ticker: ticker.to_string(),
+            direction,
+            loose,
+            bars_held,
+            close: rows[rows.len() - 1].quote.close,
+            stoch,
+            adxr: indicators.last(&params.adxr),
+            rsi: indicators.last(&params.rsi),
+            emas: STACK.iter().map(|ema| indicators.last(ema)).collect(),
+        })
+    }
+
+    /// A trend with the stochastic pulled back at least `threshold` past 50 and a trending ADX
+    pub fn is_setup(&self, threshold: f64) -> bool {
+        let pulled_back = match self.direction {
+            Direction::Bull => self.stoch <= 50.0 - threshold,
+            Direction::Bear => self.stoch >= 50.0 + threshold,
+            Direction::None => false,
+        };
+        pulled_back && self.adxr.unwrap_or(-1.0) > 20.0
+    }
+
+    /// How far past 50 the stochastic is, in the direction of the pullback
+    pub fn stoch_extremity(&self) -> f64 {
+        match self.direction {
+            Direction::Bull => 50.0 - self.stoch,
+            Direction::Bear => self.stoch - 50.0,
+            Direction::None => (self.stoch - 50.0).abs(),
+        }
+    }
+
+    /// Close relative to each `STACK` EMA, i.e. 0.02 when 2% above it
+    pub fn ema_distances(&self) -> Vec<Option<f64>> {
+        self.emas
+            .iter()
+            .map(|ema| {
+                ema.filter(|ema| *ema != 0.0)
+                    .map(|ema| self.close / ema - 1.0)
+            })
+            .collect()
+    }
+
+    /// The fields of `to_record`, in order
+    pub fn columns() -> Vec<String> {
+        let mut columns: Vec<String> = [
+            "loose",
+            "stoch",
+            "ADX",
+            "RSI",
+            "direction",
+            "bars_held",
+            "close",
+        ]
+        .map(String::from)
+        .to_vec();
+        columns.extend(STACK.iter().map(|ema| ema.to_string()));
+        columns.extend(STACK.iter().map(|ema| format!("{}_distance", ema)));
+        columns
+    }
+
+    pub fn to_record(&self) -> Record {
+        let mut values = vec![
+            Field::Bool(self.loose),
+            Field::Number(Some(self.stoch)),
+            Field::Number(self.adxr),
+            Field::Number(self.rsi),
+            Field::Text(self.direction.name().to_string()),
+            Field::Number(Some(self.bars_held as f64)),
+            Field::Number(Some(self.close)),
+        ];
+        values.extend(self.emas.iter().map(|value| Field::Number(*value)));
+        values.extend(self.ema_distances().into_iter().map(Field::Number));
+        let section = if self.loose { "Loose" } else { "Stocks" };
+        Record {
+            ticker: self.ticker.clone(),
+            section: section.to_string(),
+            fields: TrendCandidate::columns().into_iter().zip(values).collect(),
+        }
+    }
+}
+
+/// What to rank candidates by. Everything but `Ticker` puts the largest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Ticker,
+    Adxr,
+    /// How far the stochastic has pulled back
+    Stoch,
+    Rsi,
+    BarsHeld,
+}
+
+impl SortKey {
+    fn value(&self, candidate: &TrendCandidate) -> f64 {
+        match self {
+            SortKey::Ticker => 0.0,
+            SortKey::Adxr => candidate.adxr.unwrap_or(f64::MIN),
+            SortKey::Stoch => candidate.stoch_extremity(),
+            SortKey::Rsi => candidate.rsi.unwrap_or(f64::MIN),
+            SortKey::BarsHeld => candidate.bars_held as f64,
+        }
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "ticker" => Ok(SortKey::Ticker),
+            "adxr" | "adx" => Ok(SortKey::Adxr),
+            "stoch" => Ok(SortKey::Stoch),
+            "rsi" => Ok(SortKey::Rsi),
+            "bars_held" | "held" => Ok(SortKey::BarsHeld),
+            _ => Err(anyhow::anyhow!(
+                "unknown sort key '{}' (ticker|adxr|stoch|rsi|bars_held)",
+                s
+            )),
+        }
+    }
+}
+
+/// Sort `candidates` best first by `key` (ties by ticker) and keep the `top` of them
+pub fn rank(candidates: &mut Vec<TrendCandidate>, key: SortKey, top: Option<usize>) {
+    candidates.sort_by(|a, b| {
+        key.value(b)
+            .partial_cmp(&key.value(a))
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.ticker.cmp(&b.ticker))
+    });
+    if let Some(top) = top {
+        candidates.truncate(top);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::quote::Quote;
+
+    fn candles(closes: impl Iterator<Item = f64>) -> Vec<QuoteRow> {
+        closes
+            .enumerate()
+            .map(|(id, close)| QuoteRow {
+                id: id as i32,
+                quote: Quote {
+                    timestamp: id as i64,
+                    open: close,
+                    close,
+                    high: close + 1.0,
+                    low: close - 1.0,
+                    ..Quote::default()
+                },
+            })
+            .collect()
+    }
+
+    fn params(loose: bool) -> Params {
+        Params {
+            ema_period: 42,
+            loose,
+            slow_stoch: Indicator::SlowStoch(8, 3, 3),
+            adxr: Indicator::Adx(13, 1),
+            rsi: Indicator::Rsi(2),
+            filter: None,
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        // a steady climb, then a 5 day dip
+        let closes = (0..200)
+            .map(|i| 100.0 + i as f64)
+            .chain([296.0, 293.0, 291.0, 290.0, 289.0]);
+        let rows = candles(closes);
+        let candidate = TrendCandidate::evaluate("UP", &rows, &params(false)).unwrap();
+        assert_eq!(candidate.direction, Direction::Bull);
+        assert!(!candidate.loose);
+        // ema(89) needs 88 candles to warm up
+        assert_eq!(candidate.bars_held, rows.len() - 88);
+        assert_eq!(candidate.close, 289.0);
+        assert!(candidate.stoch < 40.0);
+        // ...but the dip has the ADX reading a downtrend
+        assert!(!candidate.is_setup(10.0));
+        let distances = candidate.ema_distances();
+        assert!(distances[0].unwrap() < 0.0);
+        assert!(distances[3].unwrap() > 0.0);
+
+        let falling = candles((0..200).map(|i| 300.0 - i as f64));
+        let candidate = TrendCandidate::evaluate("DOWN", &falling, &params(false)).unwrap();
+        assert_eq!(candidate.direction, Direction::Bear);
+        // not bounced, so the stochastic is pinned at 0
+        assert!(!candidate.is_setup(10.0));
+
+        let mut liquid = params(false);
+        liquid.filter = Some("close >= 250".parse().unwrap());
+        assert!(TrendCandidate::evaluate("UP", &rows, &liquid).is_some());
+        assert!(TrendCandidate::evaluate("DOWN", &falling, &liquid).is_none());
+
+        // too short for the EMA period
+        assert!(TrendCandidate::evaluate("NEW", &rows[..30], &params(false)).is_none());
+    }
+
+    #[test]
+    fn test_rank() {
+        let candidate = |ticker: &str, stoch: f64, adxr: f64| TrendCandidate {
+            ticker: ticker.to_string(),
+            direction: Direction::Bull,
+            loose: false,
+            bars_held: 50,
+            close: 10.0,
+            stoch,
+            adxr: Some(adxr),
+            rsi: None,
+            emas: vec![None; 4],
+        };
+        let mut candidates = vec![
+            candidate("A", 30.0, 25.0),
+            candidate("B", 10.0, 22.0),
+            candidate("C", 20.0, 40.0),
+        ];
+        assert!(candidates[1].is_setup(10.0));
+        assert!(!candidates[1].is_setup(45.0));
+        assert!(!candidates[0].is_setup(30.0));
+        rank(&mut candidates, SortKey::Stoch, None);
+        let tickers: Vec<&str> = candidates.iter().map(|c| c.ticker.as_str()).collect();
+        assert_eq!(tickers, vec!["B", "C", "A"]);
+        rank(&mut candidates, SortKey::Adxr, Some(2));
+        let tickers: Vec<&str> = candidates.iter().map(|c| c.ticker.as_str()).collect();
+        assert_eq!(tickers, vec!["C", "A"]);
+        rank(&mut candidates, SortKey::Ticker, None);
+        assert_eq!(candidates[0].ticker, "A");
+    }
+}
@@ -0,0 +1,489 @@
+use std::fmt::Write;
+
+use crate::calendar;
+use crate::candidates::STACK;
+use crate::db::QuoteRow;
+use crate::indicators::{Indicator, Indicators, Series};
+use crate::quote::Quote;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Up,
+    Down,
+    Guide,
+    /// The nth EMA of `STACK`, or the nth oscillator line
+    Line(usize),
+}
+
+const LINE_COLORS: [(&str, &str); 4] = [
+    ("33", "#e6a700"),
+    ("36", "#00a3c4"),
+    ("34", "#3f51b5"),
+    ("35", "#b03fb5"),
+];
+
+impl Color {
+    fn ansi(&self) -> &'static str {
+        match self {
+            Color::Up => "32",
+            Color::Down => "31",
+            Color::Guide => "90",
+            Color::Line(n) => LINE_COLORS[n % LINE_COLORS.len()].0,
+        }
+    }
+
+    fn svg(&self) -> &'static str {
+        match self {
+            Color::Up => "#26a69a",
+            Color::Down => "#ef5350",
+            Color::Guide => "#b0b0b0",
+            Color::Line(n) => LINE_COLORS[n % LINE_COLORS.len()].1,
+        }
+    }
+}
+
+/// An oscillator drawn in its own pane under the candles, on a 0-100 scale
+struct Pane {
+    indicator: Indicator,
+    series: Series,
+    /// Levels to draw a guide line at, i.e. the stochastic's 20 and 80
+    guides: Vec<f64>,
+}
+
+/// A ticker's last candles with the `STACK` EMAs over them and slow stochastic and ADX panes
+/// under them
+pub struct Chart {
+    ticker: String,
+    candles: Vec<Quote>,
+    emas: Vec<(Indicator, Series)>,
+    panes: Vec<Pane>,
+}
+
+/// Which of `rows` rows `value` falls in, 0 being `hi`
+fn row(value: f64, lo: f64, hi: f64, rows: usize) -> usize {
+    let fraction = ((hi - value) / (hi - lo)).clamp(0.0, 1.0);
+    (fraction * (rows - 1) as f64).round() as usize
+}
+
+/// A grid of characters, each maybe colored
+struct Canvas {
+    cells: Vec<Vec<(char, Option<Color>)>>,
+}
+
+impl Canvas {
+    fn new(rows: usize, cols: usize) -> Self {
+        Canvas {
+            cells: vec![vec![(' ', None); cols]; rows],
+        }
+    }
+
+    fn set(&mut self, row: usize, col: usize, ch: char, color: Color) {
+        self.cells[row][col] = (ch, Some(color));
+    }
+
+    fn is_empty(&self, row: usize, col: usize) -> bool {
+        self.cells[row][col].0 == ' '
+    }
+
+    /// Append the rows to `out`, each followed by its `labels` entry if it has one
+    fn render(&self, out: &mut String, labels: &[(usize, String)], color: bool) {
+        for (r, cells) in self.cells.iter().enumerate() {
+            for (ch, c) in cells {
+                match c {
+                    Some(c) if color => {
+                        let _ = write!(out, "\x1b[{}m{}\x1b[0m", c.ansi(), ch);
+                    }
+                    _ => out.push(*ch),
+                }
+            }
+            if let Some((_, label)) = labels.iter().find(|(at, _)| *at == r) {
+                let _ = write!(out, " {}", label);
+            }
+            out.push('\n');
+        }
+    }
+}
+
+/// `s` as SVG/HTML text, i.e. a ticker like `A&B`
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl Chart {
+    /// The last `bars` of `rows` (oldest first). Indicators are computed over all of them so
+    /// they're warmed up by the first candle drawn
+    pub fn new(
+        ticker: &str,
+        rows: &[QuoteRow],
+        bars: usize,
+        slow_stoch: Indicator,
+        adx: Indicator,
+    ) -> Self {
+        let mut wanted = STACK.to_vec();
+        wanted.extend([slow_stoch, adx]);
+        let indicators = Indicators::compute(rows, &wanted);
+        let first = rows.len().saturating_sub(bars);
+        let series = |indicator: &Indicator| -> Series {
+            indicators
+                .series(indicator)
+                .map(|s| s[first..].to_vec())
+                .unwrap_or_default()
+        };
+        Chart {
+            ticker: ticker.to_string(),
+            candles: rows[first..].iter().map(|row| row.quote.clone()).collect(),
+            emas: STACK.iter().map(|ema| (*ema, series(ema))).collect(),
+            panes: vec![
+                Pane {
+                    indicator: slow_stoch,
+                    series: series(&slow_stoch),
+                    guides: vec![20.0, 80.0],
+                },
+                Pane {
+                    indicator: adx,
+                    series: series(&adx),
+                    guides: vec![20.0],
+                },
+            ],
+        }
+    }
+
+    /// Lowest and highest price drawn, candles and EMAs both
+    fn price_range(&self) -> (f64, f64) {
+        let prices = self.candles.iter().flat_map(|q| [q.low, q.high]).chain(
+            self.emas
+                .iter()
+                .flat_map(|(_, s)| s.iter().flatten().copied()),
+        );
+        let (lo, hi) = prices.fold((f64::MAX, f64::MIN), |(lo, hi), p| (lo.min(p), hi.max(p)));
+        if hi > lo {
+            (lo, hi)
+        } else {
+            (lo - 1.0, lo + 1.0)
+        }
+    }
+
+    fn date(&self, i: usize) -> String {
+        calendar::ny_date(self.candles[i].timestamp).to_string()
+    }
+
+    /// Draw the chart in `height` rows of candles (one column per candle), then the panes.
+    /// `color` adds ANSI colors
+    pub fn render(&self, height: usize, color: bool) -> String {
+        let cols = self.candles.len();
+        let mut out = String::new();
+        if cols == 0 {
+            return out;
+        }
+        let _ = write!(out, "{}", self.ticker);
+        for (n, (ema, _)) in self.emas.iter().enumerate() {
+            if color {
+                let _ = write!(out, "  \x1b[{}m{}\x1b[0m", Color::Line(n).ansi(), ema);
+            } else {
+                let _ = write!(out, "  {}", ema);
+            }
+        }
+        out.push('\n');
+
+        let height = height.max(3);
+        let (lo, hi) = self.price_range();
+        let mut canvas = Canvas::new(height, cols);
+        for (col, q) in self.candles.iter().enumerate() {
+            let c = if q.close >= q.open {
+                Color::Up
+            } else {
+                Color::Down
+            };
+            for r in row(q.high, lo, hi, height)..=row(q.low, lo, hi, height) {
+                canvas.set(r, col, '│', c);
+            }
+            let (top, bottom) = (q.open.max(q.close), q.open.min(q.close));
+            for r in row(top, lo, hi, height)..=row(bottom, lo, hi, height) {
+                canvas.set(r, col, '┃', c);
+            }
+        }
+        for (n, (_, series)) in self.emas.iter().enumerate() {
+            for (col, value) in series.iter().enumerate() {
+                if let Some(value) = value {
+                    let r = row(*value, lo, hi, height);
+                    if canvas.is_empty(r, col) {
+                        canvas.set(r, col, '•', Color::Line(n));
+                    }
+                }
+            }
+        }
+        let labels = vec![
+            (0, format!("{:.2}", hi)),
+            (height / 2, format!("{:.2}", (hi + lo) / 2.0)),
+            (height - 1, format!("{:.2}", lo)),
+        ];
+        canvas.render(&mut out, &labels, color);
+
+        let pane_height = (height / 3).max(3);
+        for (n, pane) in self.panes.iter().enumerate() {
+            out.push_str(&"─".repeat(cols));
+            let _ = writeln!(out, " {}", pane.indicator);
+            let mut canvas = Canvas::new(pane_height, cols);
+            for guide in pane.guides.iter() {
+                let r = row(*guide, 0.0, 100.0, pane_height);
+                for col in 0..cols {
+                    canvas.set(r, col, '·', Color::Guide);
+                }
+            }
+            for (col, value) in pane.series.iter().enumerate() {
+                if let Some(value) = value {
+                    canvas.set(
+                        row(*value, 0.0, 100.0, pane_height),
+                        col,
+                        '•',
+                        Color::Line(n),
+                    );
+                }
+            }
+            let mut labels = vec![(0, "100".to_string()), (pane_height - 1, "0".to_string())];
+            if let Some(last) = pane.series.last().copied().flatten() {
+                labels.push((row(last, 0.0, 100.0, pane_height), format!("{:.1}", last)));
+            }
+            canvas.render(&mut out, &labels, color);
+        }
+
+        let (first, last) = (self.date(0), self.date(cols - 1));
+        let gap = cols.saturating_sub(first.len() + last.len()).max(1);
+        let _ = writeln!(out, "{}{}{}", first, " ".repeat(gap), last);
+        out
+    }
+
+    /// The chart as a standalone SVG image
+    pub fn to_svg(&self) -> String {
+        const STEP: f64 = 8.0;
+        const PRICE_HEIGHT: f64 = 320.0;
+        const PANE_HEIGHT: f64 = 100.0;
+        const GAP: f64 = 24.0;
+        const LABELS: f64 = 64.0;
+        let cols = self.candles.len();
+        let width = cols as f64 * STEP + LABELS;
+        let height = GAP + PRICE_HEIGHT + self.panes.len() as f64 * (GAP + PANE_HEIGHT) + GAP;
+        let x = |col: usize| col as f64 * STEP + STEP / 2.0;
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="11">"#,
+            w = width,
+            h = height
+        );
+        let _ = writeln!(
+            svg,
+            r#"<rect width="{}" height="{}" fill="white"/>"#,
+            width, height
+        );
+        let _ = write!(
+            svg,
+            r#"<text x="4" y="16" font-weight="bold">{}</text>"#,
+            xml_escape(&self.ticker)
+        );
+        let mut legend_x = 8.0 + self.ticker.len() as f64 * 8.0;
+        for (n, (ema, _)) in self.emas.iter().enumerate() {
+            let _ = write!(
+                svg,
+                r#"<text x="{}" y="16" fill="{}">{}</text>"#,
+                legend_x,
+                Color::Line(n).svg(),
+                ema
+            );
+            legend_x += 56.0;
+        }
+        svg.push('\n');
+
+        // a line through each run of values, scaled from lo..hi onto top..top + height
+        let polyline = |svg: &mut String,
+                        series: &Series,
+                        lo: f64,
+                        hi: f64,
+                        top: f64,
+                        height: f64,
+                        color: Color| {
+            let points: Vec<String> = series
+                .iter()
+                .enumerate()
+                .filter_map(|(col, v)| {
+                    v.map(|v| format!("{:.1},{:.1}", x(col), top + (hi - v) / (hi - lo) * height))
+                })
+                .collect();
+            if !points.is_empty() {
+                let _ = writeln!(
+                    svg,
+                    r#"<polyline fill="none" stroke="{}" stroke-width="1.2" points="{}"/>"#,
+                    color.svg(),
+                    points.join(" ")
+                );
+            }
+        };
+
+        let (lo, hi) = self.price_range();
+        let top = GAP;
+        let y = |price: f64| top + (hi - price) / (hi - lo) * PRICE_HEIGHT;
+        for (col, q) in self.candles.iter().enumerate() {
+            let c = if q.close >= q.open {
+                Color::Up
+            } else {
+                Color::Down
+            };
+            let body_top = y(q.open.max(q.close));
+            let body_height = (y(q.open.min(q.close)) - body_top).max(1.0);
+            let _ = writeln!(
+                svg,
+                r#"<line x1="{x:.1}" x2="{x:.1}" y1="{:.1}" y2="{:.1}" stroke="{c}"/><rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{c}"/>"#,
+                y(q.high),
+                y(q.low),
+                x(col) - STEP * 0.35,
+                body_top,
+                STEP * 0.7,
+                body_height,
+                x = x(col),
+                c = c.svg()
+            );
+        }
+        for (n, (_, series)) in self.emas.iter().enumerate() {
+            polyline(&mut svg, series, lo, hi, top, PRICE_HEIGHT, Color::Line(n));
+        }
+        let right = cols as f64 * STEP + 4.0;
+        for price in [hi, (hi + lo) / 2.0, lo] {
+            let _ = writeln!(
+                svg,
+                r#"<text x="{:.1}" y="{:.1}">{:.2}</text>"#,
+                right,
+                y(price) + 4.0,
+                price
+            );
+        }
+
+        for (n, pane) in self.panes.iter().enumerate() {
+            let top = GAP + PRICE_HEIGHT + GAP + n as f64 * (PANE_HEIGHT + GAP);
+            let y = |v: f64| top + (100.0 - v) / 100.0 * PANE_HEIGHT;
+            let _ = writeln!(
+                svg,
+                r#"<text x="4" y="{:.1}">{}</text>"#,
+                top - 6.0,
+                pane.indicator
+            );
+            for guide in pane.guides.iter() {
+                let _ = writeln!(
+                    svg,
+                    r#"<line x1="0" x2="{:.1}" y1="{y:.1}" y2="{y:.1}" stroke="{}" stroke-dasharray="2,3"/><text x="{:.1}" y="{:.1}">{}</text>"#,
+                    right - 4.0,
+                    Color::Guide.svg(),
+                    right,
+                    y(*guide) + 4.0,
+                    guide,
+                    y = y(*guide)
+                );
+            }
+            polyline(
+                &mut svg,
+                &pane.series,
+                0.0,
+                100.0,
+                top,
+                PANE_HEIGHT,
+                Color::Line(n),
+            );
+        }
+
+        if cols > 0 {
+            let bottom = height - 6.0;
+            let _ = writeln!(svg, r#"<text x="0" y="{}">{}</text>"#, bottom, self.date(0));
+            let _ = writeln!(
+                svg,
+                r#"<text x="{:.1}" y="{}" text-anchor="end">{}</text>"#,
+                cols as f64 * STEP,
+                bottom,
+                self.date(cols - 1)
+            );
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    /// `to_svg` in a page of its own
+    pub fn to_html(&self) -> String {
+        format!(
+            "<!DOCTYPE html>\n<html>\n\
+             <head><meta charset=\"utf-8\"><title>{}</title></head>\n\
+             <body>\n{}</body>\n</html>\n",
+            xml_escape(&self.ticker),
+            self.to_svg()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chart(days: usize) -> Chart {
+        chart_of("AAPL", days)
+    }
+
+    fn chart_of(ticker: &str, days: usize) -> Chart {
+        let rows: Vec<QuoteRow> = (0..days)
+            .map(|i| {
+                let close = 100.0 + i as f64 + if i % 5 == 0 { -3.0 } else { 0.0 };
+                QuoteRow {
+                    id: i as i32,
+                    quote: Quote {
+                        timestamp: 1656014400 + i as i64 * 86400,
+                        open: close - 0.5,
+                        close,
+                        high: close + 1.0,
+                        low: close - 1.0,
+                        ..Quote::default()
+                    },
+                }
+            })
+            .collect();
+        Chart::new(
+            ticker,
+            &rows,
+            60,
+            Indicator::SlowStoch(8, 3, 3),
+            Indicator::Adx(13, 1),
+        )
+    }
+
+    #[test]
+    fn test_render() {
+        let chart = chart(200);
+        assert_eq!(chart.candles.len(), 60);
+        // warmed up on the candles before the ones drawn
+        assert!(chart.emas[3].1[0].is_some());
+        let text = chart.render(12, false);
+        let lines: Vec<&str> = text.lines().collect();
+        // legend, candles, 2 x (rule + pane), dates
+        assert_eq!(lines.len(), 1 + 12 + 2 * (1 + 4) + 1);
+        assert_eq!(lines[0], "AAPL  ema(8)  ema(21)  ema(34)  ema(89)");
+        assert!(lines[1].ends_with(&format!(" {:.2}", chart.price_range().1)));
+        assert!(lines[13].contains("slow_stoch(8,3,3)"));
+        assert!(lines.last().unwrap().starts_with("2022-11-10"));
+        assert!(!text.contains('\x1b'));
+        assert!(chart.render(12, true).contains("\x1b[32m"));
+    }
+
+    #[test]
+    fn test_svg() {
+        let svg = chart(200).to_svg();
+        assert!(svg.starts_with("<svg "));
+        // the background, then a body per candle
+        assert_eq!(svg.matches("<rect").count(), 61);
+        // 4 EMAs and 2 oscillators
+        assert_eq!(svg.matches("<polyline").count(), 6);
+        assert!(chart(200).to_html().contains("<title>AAPL</title>"));
+        let html = chart_of("A&B <\"C\">", 200).to_html();
+        assert!(html.contains("<title>A&amp;B &lt;&quot;C&quot;&gt;</title>"));
+        assert!(html.contains(r#"font-weight="bold">A&amp;B &lt;&quot;C&quot;&gt;</text>"#));
+        // too short for anything but candles
+        assert_eq!(chart(5).to_svg().matches("<polyline").count(), 0);
+    }
+}
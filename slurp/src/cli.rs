@@ -1,36 +1,326 @@
+use chrono::NaiveDate;
+use std::path::PathBuf;
 use structopt::{self, StructOpt};
 
+use crate::bars::BarSize;
+use crate::calendar;
+use crate::candidates::SortKey;
+use crate::config::Gateway;
+use crate::indicators::Indicator;
+use crate::liquidity::Liquidity;
+use crate::output::Format;
+use crate::source::Source;
+use crate::universe;
+
+/// Fill a SQLite database with candles from IB or Yahoo, and screen them
 #[derive(StructOpt, Debug)]
 #[structopt(name = "slurp", global_setting = structopt::clap::AppSettings::ColoredHelp)]
 pub struct Args {
     #[structopt(subcommand)]
     pub command: Command,
 
-    #[structopt(long, default_value = "127.0.0.1")]
-    pub ip: String,
+    /// Config file [default: ~/.config/stonks/config.toml]
+    #[structopt(long, env = "STONKS_CONFIG", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// SQLite database [default: the config's `db`, or ~/.local/stonks/db.sqlite3]
+    #[structopt(long, env = "STONKS_DB", parse(from_os_str))]
+    pub db: Option<PathBuf>,
 
-    #[structopt(long, default_value = "4001")]
-    pub port: u32,
+    #[structopt(flatten)]
+    pub gateway: Gateway,
 
     /// Max number of concurrent requests
     #[structopt(long, default_value = "40")]
     pub req_limit: usize,
 }
 
+// `--universe` for the subcommands that read tickers (a plain comment, or it'd become their about)
+#[derive(StructOpt, Debug)]
+pub struct TickerArgs {
+    /// Use the tickers of this universe (see `universe`) rather than reading them from stdin.
+    /// `-` reads stdin even if the config names a default universe
+    #[structopt(long)]
+    pub universe: Option<String>,
+}
+
 #[derive(StructOpt, Debug)]
 pub enum Command {
     /// Iterate all newline-delimitted tickers read from stdin and fill the DB with 2 years of
-    /// daily candles
-    Full,
+    /// daily candles (or --days of intraday candles)
+    Full {
+        /// Where to fetch candles from: ibkr (TWS/Gateway) or yahoo
+        #[structopt(long, default_value = "ibkr")]
+        source: Source,
+
+        /// Candle size: 1m, 5m, 15m, 30m, 1h, 4h or 1d. Intraday sizes are ibkr only
+        #[structopt(long, default_value = "1d")]
+        bar_size: BarSize,
+
+        /// Days of history to fetch for intraday bar sizes
+        #[structopt(long, default_value = "30")]
+        days: i64,
+
+        /// Also request tickers that previously failed permanently (see `failures`)
+        #[structopt(long)]
+        retry_failed: bool,
+
+        /// Also fetch dividend adjusted closes (ADJUSTED_LAST) from ibkr. Yahoo always has them
+        #[structopt(long)]
+        adjusted: bool,
+
+        #[structopt(flatten)]
+        ticker_args: TickerArgs,
+
+        /// Fetch the daily candles of the tickers `audit --enqueue` queued rather than reading
+        /// tickers from stdin
+        #[structopt(long)]
+        queued: bool,
+    },
 
     /// Iterate all newline-delimiitted tickers and append the days of candles since the last row
     /// we have for that ticker
     Incremental {
-        /// Don't rely on DB cache - always add latest days from IBKR
+        /// Don't rely on DB cache - always request the latest candles, even when the last one we
+        /// have is the newest that has closed
+        #[structopt(long)]
+        force: bool,
+
+        /// Where to fetch candles from: ibkr (TWS/Gateway) or yahoo
+        #[structopt(long, default_value = "ibkr")]
+        source: Source,
+
+        /// Candle size: 1m, 5m, 15m, 30m, 1h, 4h or 1d. Intraday sizes are ibkr only
+        #[structopt(long, default_value = "1d")]
+        bar_size: BarSize,
+
+        /// Also request tickers that previously failed permanently (see `failures`)
+        #[structopt(long)]
+        retry_failed: bool,
+
+        /// Also fetch dividend adjusted closes (ADJUSTED_LAST) from ibkr. Yahoo always has them
+        #[structopt(long)]
+        adjusted: bool,
+
+        #[structopt(flatten)]
+        ticker_args: TickerArgs,
+    },
+
+    /// Look up the contract (conId, primary exchange, name, industry, ...) of each
+    /// newline-delimitted ticker from stdin so later requests aren't ambiguous
+    Resolve,
+
+    /// Print the tickers we failed to fetch, per source, and why
+    Failures,
+
+    /// Print how each ticker's last incremental update lined up with the candles we had, per source
+    Reconciliations,
+
+    /// Check the daily candles of each newline-delimitted ticker from stdin against the session
+    /// calendar: missing or duplicated sessions, candles on holidays, zero volume, bad OHLC, split
+    /// sized jumps without a split on record and stale tickers
+    Audit {
+        /// Flag tickers whose last candle is more than this many sessions old
+        #[structopt(long, default_value = "3")]
+        stale_sessions: i64,
+
+        /// Queue tickers with problems a refetch would fix for `full --queued`
+        #[structopt(long)]
+        enqueue: bool,
+
+        #[structopt(flatten)]
+        ticker_args: TickerArgs,
+    },
+
+    /// Fill the ema_8/21/34/89 and sma_50/200 tables (keyed by daily_id) for each
+    /// newline-delimitted ticker from stdin. Only rows without a value yet are added
+    ComputeMetrics {
+        /// Recompute every row rather than only the new ones
         #[structopt(long)]
         force: bool,
     },
 
+    /// Inspect and upgrade the database schema
+    Db(DbCommand),
+
+    /// Manage named ticker universes, i.e. `universe import large-cap .raw.csv --min-cap 1e9`
+    Universe(UniverseCommand),
+
+    /// Print a ticker's candles and indicators, i.e.
+    /// `show AAPL --from 2022-06-01 --indicator rsi(2)`
+    Show {
+        ticker: String,
+
+        /// First day to print (YYYY-MM-DD). Earlier candles still warm the indicators up
+        #[structopt(long, parse(try_from_str = calendar::parse_date))]
+        from: Option<NaiveDate>,
+
+        /// Last day to print (YYYY-MM-DD)
+        #[structopt(long, parse(try_from_str = calendar::parse_date))]
+        to: Option<NaiveDate>,
+
+        /// An indicator column, i.e. ema(8), rsi(2) or slow_stoch(8,3,3). Repeat for more
+        /// [default: ema(8), ema(21), ema(34), ema(89), sma(50) and sma(200)]
+        #[structopt(long = "indicator", number_of_values = 1)]
+        indicators: Vec<Indicator>,
+
+        /// Candle size to print: 1m, 5m, 15m, 30m, 1h, 4h or 1d
+        #[structopt(long, default_value = "1d")]
+        bar_size: BarSize,
+
+        /// Print the dividend adjusted series rather than the raw candles
+        #[structopt(long)]
+        adjusted: bool,
+
+        /// tsv, csv or jsonl
+        #[structopt(long, default_value = "tsv")]
+        output: Format,
+    },
+
+    /// Draw a ticker's daily candles in the terminal, with the EMA 8/21/34/89 over them and slow
+    /// stochastic and ADX panes under them
+    Chart {
+        ticker: String,
+
+        /// Candles to draw, one per column
+        #[structopt(long, default_value = "100")]
+        bars: usize,
+
+        /// Rows of candles. The stochastic and ADX panes get a third of that each
+        #[structopt(long, default_value = "24")]
+        height: usize,
+
+        /// Draw up to this date (YYYY-MM-DD, after its close) or New York time (YYYY-MM-DD HH:MM)
+        #[structopt(long, parse(try_from_str = calendar::parse_as_of))]
+        as_of: Option<i64>,
+
+        /// Draw the dividend adjusted series rather than the raw candles
+        #[structopt(long)]
+        adjusted: bool,
+
+        /// K length of the slow stochastic pane
+        #[structopt(long, default_value = "8")]
+        stoch_k_len: usize,
+
+        /// Smoothing of the k
+        #[structopt(long, default_value = "3")]
+        stoch_k_smoothing: usize,
+
+        /// D smoothing (slow smoothing of the actual stochastic value)
+        #[structopt(long, default_value = "3")]
+        stoch_d_smoothing: usize,
+
+        /// Period of the ADX pane
+        #[structopt(long, default_value = "13")]
+        adx_period: usize,
+
+        /// Also write the chart to this .svg or .html file
+        #[structopt(long, parse(from_os_str))]
+        export: Option<PathBuf>,
+
+        /// No ANSI colors, even on a terminal
+        #[structopt(long)]
+        no_color: bool,
+    },
+
+    /// Print the splits and dividends we know of for each newline-delimitted ticker from stdin
+    Actions,
+
+    /// Evaluate a screen file (see screens/bounce.toml) over each newline-delimitted ticker from
+    /// stdin and print the ones that pass
+    Screen {
+        /// Screen file [default: the config's [screen] file]
+        #[structopt(long, parse(from_os_str))]
+        file: Option<PathBuf>,
+
+        /// Candle size to screen: 1m, 5m, 15m, 30m, 1h, 4h or 1d
+        #[structopt(long, default_value = "1d")]
+        bar_size: BarSize,
+
+        /// Compute indicators on the dividend adjusted series rather than the raw closes
+        #[structopt(long)]
+        adjusted: bool,
+
+        /// Evaluate as of this date (YYYY-MM-DD, after its close) or New York time
+        /// (YYYY-MM-DD HH:MM), ignoring any later candles. Daily candles only
+        #[structopt(long, parse(try_from_str = calendar::parse_as_of))]
+        as_of: Option<i64>,
+
+        /// tsv, csv, jsonl, tradingview (a watchlist with a section per signal) or ibkr (a TWS
+        /// watchlist)
+        #[structopt(long, default_value = "tsv")]
+        output: Format,
+
+        #[structopt(flatten)]
+        ticker_args: TickerArgs,
+
+        #[structopt(flatten)]
+        liquidity: Liquidity,
+    },
+
+    /// Stream market data from IB for each newline-delimitted ticker from stdin, building today's
+    /// candle, and re-evaluate a screen file on it until the close. Prints each signal as it
+    /// triggers
+    Stream {
+        /// Screen file [default: the config's [screen] file]
+        #[structopt(long, parse(from_os_str))]
+        file: Option<PathBuf>,
+
+        /// Request 15 minute delayed data, which doesn't need a market data subscription
+        #[structopt(long)]
+        delayed: bool,
+
+        /// Also subscribe to 5 second bars for the price between ticks. Live data only
+        #[structopt(long, conflicts_with = "delayed")]
+        real_time_bars: bool,
+
+        /// Seconds between evaluations of the screen
+        #[structopt(long, default_value = "60")]
+        interval: u64,
+
+        /// Market data lines the account has (100 unless topped up). Each ticker takes one, or two
+        /// with --real-time-bars
+        #[structopt(long, default_value = "100")]
+        max_lines: usize,
+
+        /// tsv, csv or jsonl
+        #[structopt(long, default_value = "tsv")]
+        output: Format,
+
+        #[structopt(flatten)]
+        ticker_args: TickerArgs,
+
+        #[structopt(flatten)]
+        liquidity: Liquidity,
+    },
+
+    /// Replay a screen file over the daily candles of each newline-delimitted ticker from stdin,
+    /// entering at the open after each signal. Prints the trade log, then a summary to stderr
+    Backtest {
+        /// Screen file [default: the config's [screen] file]
+        #[structopt(long, parse(from_os_str))]
+        file: Option<PathBuf>,
+
+        /// Period of the ATR used to size the stop
+        #[structopt(long, default_value = "14")]
+        atr_period: usize,
+
+        /// Stop distance from the entry, in ATRs
+        #[structopt(long, default_value = "2.0")]
+        atr_stop: f64,
+
+        /// Exit at the close after this many days in a trade
+        #[structopt(long, default_value = "10")]
+        max_bars: usize,
+
+        /// Compute indicators and fills on the dividend adjusted series rather than the raw closes
+        #[structopt(long)]
+        adjusted: bool,
+
+        #[structopt(flatten)]
+        ticker_args: TickerArgs,
+    },
+
     /// Find all tickers (of the ones provided) for whom the last 30-days of metrics abide by the
     /// EMA 8 < EMA 21 < EMA 34 < EMA 89 OR
     /// EMA 8 > EMA 21 > EMA 34 > EMA 89 rules
@@ -67,5 +357,89 @@ pub enum Command {
 
         #[structopt(long, default_value = "13")]
         adx_period: usize,
+
+        /// Candle size to screen: 1m, 5m, 15m, 30m, 1h, 4h or 1d
+        #[structopt(long, default_value = "1d")]
+        bar_size: BarSize,
+
+        /// Compute indicators on the dividend adjusted series rather than the raw closes
+        #[structopt(long)]
+        adjusted: bool,
+
+        /// Evaluate as of this date (YYYY-MM-DD, after its close) or New York time
+        /// (YYYY-MM-DD HH:MM), ignoring any later candles. Daily candles only
+        #[structopt(long, parse(try_from_str = calendar::parse_as_of))]
+        as_of: Option<i64>,
+
+        /// tsv, csv, jsonl, tradingview (a watchlist with a section per strict/loose stack) or ibkr
+        /// (a TWS watchlist)
+        #[structopt(long, default_value = "tsv")]
+        output: Format,
+
+        /// Order candidates by ticker, adxr, stoch (furthest pulled back first), rsi or bars_held
+        #[structopt(long, default_value = "ticker")]
+        sort: SortKey,
+
+        /// Only print the first N candidates after sorting
+        #[structopt(long)]
+        top: Option<usize>,
+
+        #[structopt(flatten)]
+        ticker_args: TickerArgs,
+
+        #[structopt(flatten)]
+        liquidity: Liquidity,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum DbCommand {
+    /// Apply pending schema migrations. Every other command applies them on start, too
+    Migrate {
+        /// Only print the migrations that would be applied
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Print the database's path, i.e. for `sqlite3 "$(slurp db path)"`
+    Path,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum UniverseCommand {
+    /// Replace a universe with the tickers in a vendor CSV (Symbol and marketcap columns, i.e. the
+    /// companiesmarketcap.com download) or a headerless ticker<TAB>market_cap TSV
+    Import {
+        name: String,
+
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// Whose symbols the file uses, for mapping them to IB tickers (see `universe map`)
+        #[structopt(long, default_value = universe::DEFAULT_VENDOR)]
+        vendor: String,
+
+        /// Leave out anything with a market cap no bigger than this (or unknown)
+        #[structopt(long)]
+        min_cap: Option<f64>,
+    },
+
+    /// Print every universe, its size and when it was imported
+    List,
+
+    /// Print a universe's tickers and market caps, largest first
+    Show { name: String },
+
+    /// Delete a universe (its tickers stay in the DB)
+    Remove { name: String },
+
+    /// Map a vendor's symbol to an IB ticker on import, or leave TICKER out to skip the symbol
+    Map {
+        symbol: String,
+
+        ticker: Option<String>,
+
+        #[structopt(long, default_value = universe::DEFAULT_VENDOR)]
+        vendor: String,
     },
 }
@@ -0,0 +1,206 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::env;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+use crate::liquidity::Liquidity;
+
+/// Read from `$HOME` unless `--config` or `STONKS_CONFIG` name another file
+const DEFAULT_FILE: &str = ".config/stonks/config.toml";
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+/// IB Gateway's. TWS listens on 7497
+const DEFAULT_PORT: u32 = 4001;
+const DEFAULT_CLIENT_ID: i32 = 7274605;
+
+// Where to reach TWS or IB Gateway. Also the `[gateway]` table of the config file (see `Liquidity`
+// for why this isn't a doc comment)
+#[derive(StructOpt, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Gateway {
+    /// TWS or IB Gateway host [default: 127.0.0.1]
+    #[structopt(long, alias = "ip", env = "STONKS_HOST")]
+    pub host: Option<String>,
+
+    /// 7497 for TWS or 4001 for IB Gateway, depending on the port you have set [default: 4001]
+    #[structopt(long, env = "STONKS_PORT")]
+    pub port: Option<u32>,
+
+    /// API client id, unique per connection to the same TWS/Gateway [default: 7274605]
+    #[structopt(long, env = "STONKS_CLIENT_ID")]
+    pub client_id: Option<i32>,
+}
+
+impl Gateway {
+    /// `self`, with anything not given taken from `defaults` (i.e. the config file)
+    pub fn or(self, defaults: &Gateway) -> Gateway {
+        Gateway {
+            host: self.host.or_else(|| defaults.host.clone()),
+            port: self.port.or(defaults.port),
+            client_id: self.client_id.or(defaults.client_id),
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        self.host.as_deref().unwrap_or(DEFAULT_HOST)
+    }
+
+    pub fn port(&self) -> u32 {
+        self.port.unwrap_or(DEFAULT_PORT)
+    }
+
+    pub fn client_id(&self) -> i32 {
+        self.client_id.unwrap_or(DEFAULT_CLIENT_ID)
+    }
+}
+
+/// Defaults for `screen` and `backtest`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScreenDefaults {
+    /// Screen file when `--file` isn't given
+    pub file: Option<PathBuf>,
+}
+
+/// Where a command reads its tickers from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tickers<'a> {
+    /// `--universe NAME`
+    Universe(&'a str),
+    /// `--universe -`
+    Stdin,
+    /// Whatever's piped in, or the default universe when stdin is a terminal or empty (as under
+    /// cron)
+    PipedOr(Option<&'a str>),
+}
+
+/// `~/.config/stonks/config.toml`, i.e.
+///
+/// ```toml
+/// db = "~/stonks/db.sqlite3"
+/// universe = "large-cap"
+///
+/// [gateway]
+/// port = 7497
+///
+/// [screen]
+/// file = "~/stonks/screens/bounce.toml"
+///
+/// [liquidity]
+/// min_price = 5.0
+/// min_dollar_volume = 20000000
+/// ```
+///
+/// Flags and their `STONKS_*` environment variables win over anything in here.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The SQLite database, instead of ~/.local/stonks/db.sqlite3
+    pub db: Option<PathBuf>,
+
+    /// Universe to read tickers from when a command isn't given `--universe` or piped any
+    pub universe: Option<String>,
+
+    pub gateway: Gateway,
+
+    pub screen: ScreenDefaults,
+
+    /// Liquidity floors for `screen` and `trend-candidates`, overridden flag by flag
+    pub liquidity: Liquidity,
+}
+
+/// `~/foo` => `$HOME/foo`
+fn expand_home(path: PathBuf) -> PathBuf {
+    match (path.strip_prefix("~"), env::var("HOME")) {
+        (Ok(rest), Ok(home)) => PathBuf::from(home).join(rest),
+        _ => path,
+    }
+}
+
+impl Config {
+    /// Read `path`, or the default file if there is one
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(path) => expand_home(path.to_path_buf()),
+            None => {
+                let default = PathBuf::from(env::var("HOME")?).join(DEFAULT_FILE);
+                if !default.exists() {
+                    return Ok(Config::default());
+                }
+                default
+            }
+        };
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("couldn't read {}", path.display()))?;
+        Config::parse(&contents).with_context(|| format!("bad config {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut config: Config = toml::from_str(contents)?;
+        config.db = config.db.map(expand_home);
+        config.screen.file = config.screen.file.map(expand_home);
+        Ok(config)
+    }
+
+    /// Where a command should read its tickers from, given its `--universe`
+    pub fn tickers<'a>(&'a self, flag: Option<&'a str>) -> Tickers<'a> {
+        match flag {
+            Some("-") => Tickers::Stdin,
+            Some(name) => Tickers::Universe(name),
+            None => Tickers::PipedOr(self.universe.as_deref()),
+        }
+    }
+
+    /// The screen file a command should read, given its `--file`
+    pub fn screen_file(&self, flag: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+        flag.or_else(|| self.screen.file.clone())
+            .ok_or_else(|| anyhow::anyhow!("no --file given and no [screen] file in the config"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            "db = \"/tmp/stonks.sqlite3\"\n\
+             universe = \"large-cap\"\n\
+             [gateway]\n\
+             port = 7497\n\
+             [screen]\n\
+             file = \"screens/bounce.toml\"\n\
+             [liquidity]\n\
+             min_price = 5.0\n\
+             liquidity_window = 50\n",
+        )
+        .unwrap();
+        assert_eq!(config.db, Some(PathBuf::from("/tmp/stonks.sqlite3")));
+        assert_eq!(config.tickers(None), Tickers::PipedOr(Some("large-cap")));
+        assert_eq!(config.tickers(Some("mine")), Tickers::Universe("mine"));
+        assert_eq!(config.tickers(Some("-")), Tickers::Stdin);
+        assert_eq!(
+            config.screen_file(None).unwrap(),
+            PathBuf::from("screens/bounce.toml")
+        );
+        assert_eq!(config.liquidity.min_price, Some(5.0));
+        assert_eq!(config.liquidity.liquidity_window, Some(50));
+
+        // flags win, the rest comes from the config or the defaults
+        let flags = Gateway {
+            host: Some("gateway.local".to_string()),
+            ..Gateway::default()
+        };
+        let gateway = flags.or(&config.gateway);
+        assert_eq!(gateway.host(), "gateway.local");
+        assert_eq!(gateway.port(), 7497);
+        assert_eq!(gateway.client_id(), DEFAULT_CLIENT_ID);
+
+        let empty = Config::parse("").unwrap();
+        assert!(empty.screen_file(None).is_err());
+        assert!(Config::parse("prot = 4001\n").is_err());
+        assert!(Config::parse("[liquidity]\nmin_prise = 5.0\n").is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use std::fmt;
+
+use crate::quote::Quote;
+
+/// Overnight moves in the raw close that mean a split rather than a (very) bad day
+const SPLIT_RATIOS: [f64; 13] = [
+    1.5,
+    2.0,
+    3.0,
+    4.0,
+    5.0,
+    8.0,
+    10.0,
+    15.0,
+    20.0,
+    1.0 / 2.0,
+    1.0 / 5.0,
+    1.0 / 10.0,
+    1.0 / 20.0,
+];
+
+/// How close a price ratio has to be to one of the `SPLIT_RATIOS`
+const SPLIT_TOLERANCE: f64 = 0.03;
+
+/// Adjustment factors this close to 1 are rounding noise rather than a dividend
+const FACTOR_EPSILON: f64 = 1e-4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionKind {
+    Split,
+    Dividend,
+}
+
+impl ActionKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ActionKind::Split => "split",
+            ActionKind::Dividend => "dividend",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "split" => Some(ActionKind::Split),
+            "dividend" => Some(ActionKind::Dividend),
+            _ => None,
+        }
+    }
+}
+
+/// A split or dividend, keyed on the daily close timestamp of its ex-date
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorporateAction {
+    pub timestamp: i64,
+    pub kind: ActionKind,
+    /// New shares per old share for splits (2.0 for a 2:1), cash per share for dividends
+    pub value: f64,
+}
+
+impl fmt::Display for CorporateAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ActionKind::Split => write!(f, "{}:1 split", self.value),
+            ActionKind::Dividend => write!(f, "{:.4} dividend", self.value),
+        }
+    }
+}
+
+fn nearest_split(ratio: f64) -> Option<f64> {
+    SPLIT_RATIOS
+        .iter()
+        .find(|r| (ratio / *r - 1.0).abs() < SPLIT_TOLERANCE)
+        .copied()
+}
+
+/// The split ratio (new shares per old) implied by a close moving from `before` to `after`, if it
+/// moved by one of the usual ratios. i.e. 276.54 => 138.27 is a 2:1 split
+pub fn split_ratio(before: f64, after: f64) -> Option<f64> {
+    if before <= 0.0 || after <= 0.0 {
+        return None;
+    }
+    nearest_split(before / after)
+}
+
+/// A split since `cached` was fetched, from split adjusted `fresh` candles over the same window:
+/// cached closes before the ex-date are off from the fresh ones by the split ratio and any from
+/// the ex-date on still match, so the ex-date is the first fresh candle after the last one off
+pub fn detect_split(cached: &[Quote], fresh: &[Quote]) -> Option<CorporateAction> {
+    let mut last_off = None;
+    for quote in fresh {
+        if let Some(c) = cached.iter().find(|c| c.timestamp == quote.timestamp) {
+            if let Some(ratio) = split_ratio(c.close, quote.close) {
+                last_off = Some((quote.timestamp, ratio));
+            }
+        }
+    }
+    let (timestamp, ratio) = last_off?;
+    let after = fresh.iter().find(|q| q.timestamp > timestamp)?;
+    Some(CorporateAction {
+        timestamp: after.timestamp,
+        kind: ActionKind::Split,
+        value: ratio,
+    })
+}
+
+/// Splits and dividends implied by the adjustment factor (adjclose / close) changing between
+/// consecutive candles
+pub fn detect_actions(quotes: &[Quote]) -> Vec<CorporateAction> {
+    let mut actions = vec![];
+    for pair in quotes.windows(2) {
+        let (prev, cur) = (&pair[0], &pair[1]);
+        if prev.close <= 0.0 || cur.close <= 0.0 || prev.adjclose <= 0.0 || cur.adjclose <= 0.0 {
+            continue;
+        }
+        // everything before an ex-date is scaled down by the action's factor
+        let factor = (prev.adjclose / prev.close) / (cur.adjclose / cur.close);
+        if (factor - 1.0).abs() < FACTOR_EPSILON {
+            continue;
+        }
+        if let Some(ratio) = nearest_split(1.0 / factor) {
+            actions.push(CorporateAction {
+                timestamp: cur.timestamp,
+                kind: ActionKind::Split,
+                value: ratio,
+            });
+        } else if factor < 1.0 {
+            actions.push(CorporateAction {
+                timestamp: cur.timestamp,
+                kind: ActionKind::Dividend,
+                value: prev.close * (1.0 - factor),
+            });
+        }
+    }
+    actions
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn quote(timestamp: i64, close: f64, adjclose: f64) -> Quote {
+        Quote {
+            timestamp,
+            close,
+            adjclose,
+            ..Quote::default()
+        }
+    }
+
+    #[test]
+    fn test_split_ratio() {
+        assert_eq!(split_ratio(276.54, 138.27), Some(2.0));
+        assert_eq!(split_ratio(10.0, 100.5), Some(0.1));
+        assert_eq!(split_ratio(100.0, 91.0), None);
+        assert_eq!(split_ratio(0.0, 91.0), None);
+    }
+
+    #[test]
+    fn test_detect_split() {
+        // a 2:1 split going ex on day 3, with days 1, 2 and 4 cached
+        let cached = [
+            quote(1, 200.0, 200.0),
+            quote(2, 202.0, 202.0),
+            quote(4, 102.0, 102.0),
+        ];
+        let fresh: Vec<Quote> = [100.0, 101.0, 100.5, 102.0, 103.0]
+            .iter()
+            .enumerate()
+            .map(|(i, close)| quote(i as i64 + 1, *close, *close))
+            .collect();
+        assert_eq!(
+            detect_split(&cached, &fresh),
+            Some(CorporateAction {
+                timestamp: 3,
+                kind: ActionKind::Split,
+                value: 2.0
+            })
+        );
+        // nothing fresh past the last cached close that's off
+        assert_eq!(detect_split(&cached[..2], &fresh[..2]), None);
+        assert_eq!(detect_split(&fresh, &fresh), None);
+    }
+
+    #[test]
+    fn test_detect_actions() {
+        let quotes = vec![
+            // 0.50 dividend going ex on day 3, then a 4:1 split on day 5
+            quote(1, 100.0, 24.875),
+            quote(2, 100.0, 24.875),
+            quote(3, 99.0, 24.75),
+            quote(4, 100.0, 25.0),
+            quote(5, 25.0, 25.0),
+            quote(6, 26.0, 26.0),
+        ];
+        let actions = detect_actions(&quotes);
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0].kind, ActionKind::Dividend);
+        assert_eq!(actions[0].timestamp, 3);
+        assert!((actions[0].value - 0.5).abs() < 1e-6);
+        assert_eq!(
+            actions[1],
+            CorporateAction {
+                timestamp: 5,
+                kind: ActionKind::Split,
+                value: 4.0
+            }
+        );
+    }
+}
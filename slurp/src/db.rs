@@ -1,12 +1,28 @@
 use anyhow::Context;
 use rusqlite::{params, Connection, OptionalExtension};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::path::{Path, PathBuf};
 
 const DEFAULT_FILE: &str = ".local/stonks/db.sqlite3";
 
+use crate::bars::BarSize;
+use crate::calendar;
+use crate::corporate::{ActionKind, CorporateAction};
+use crate::indicators::{Indicator, Indicators};
 use crate::quote::Quote;
+use crate::reconcile::Outcome;
+use crate::universe::Member;
+
+/// Indicator tables (`daily_id`, `value`) that the SQL scripts join against
+const METRIC_TABLES: [(&str, Indicator); 6] = [
+    ("ema_8", Indicator::Ema(8)),
+    ("ema_21", Indicator::Ema(21)),
+    ("ema_34", Indicator::Ema(34)),
+    ("ema_89", Indicator::Ema(89)),
+    ("sma_50", Indicator::Sma(50)),
+    ("sma_200", Indicator::Sma(200)),
+];
 
 #[derive(Debug)]
 pub struct Calculations {
@@ -14,17 +30,162 @@ pub struct Calculations {
     pub values: Vec<(i32, f64)>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct QuoteRow {
     pub id: i32,
     pub quote: Quote,
 }
 
+/// A ticker we couldn't fetch from a source, and why
+#[derive(Debug, Clone, PartialEq)]
+pub struct FetchFailure {
+    pub ticker: String,
+    pub source: String,
+    /// IB error code, if the source has them
+    pub error_code: Option<i32>,
+    pub message: String,
+    /// Retrying won't help (i.e. "No security definition has been found")
+    pub permanent: bool,
+    pub attempts: i32,
+    pub failed_at: i64,
+}
+
+/// The outcome of a ticker's last incremental update
+#[derive(Debug, Clone)]
+pub struct Reconciliation {
+    pub ticker: String,
+    pub source: String,
+    pub outcome: String,
+    pub detail: String,
+    pub reconciled_at: i64,
+}
+
+/// What IB told us about a ticker's contract
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContractRow {
+    pub ticker: String,
+    pub con_id: i32,
+    pub primary_exchange: String,
+    pub long_name: String,
+    pub industry: String,
+    pub category: String,
+    pub subcategory: String,
+    pub currency: String,
+}
+
+/// Inclusive bounds on candle timestamps, open on either end when `None`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Range {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+}
+
+impl Range {
+    pub fn between(start: i64, end: i64) -> Self {
+        Range {
+            start: Some(start),
+            end: Some(end),
+        }
+    }
+}
+
 pub struct Db {
     conn: Connection,
 }
 
-fn init_tables(conn: &mut Connection) -> rusqlite::Result<()> {
+/// A schema change, applied once and in `version` order by `Db::init`. New tables and columns get
+/// a new migration rather than an edit to an old one, so existing databases pick them up.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    apply: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+const MIGRATIONS: [Migration; 4] = [
+    Migration {
+        version: 1,
+        name: "create_tables",
+        apply: create_tables,
+    },
+    Migration {
+        version: 2,
+        name: "rekey_winter_closes",
+        apply: rekey_winter_closes,
+    },
+    Migration {
+        version: 3,
+        name: "create_live_daily",
+        apply: create_live_daily,
+    },
+    Migration {
+        version: 4,
+        name: "seed_unavailable_tickers",
+        apply: seed_unavailable_tickers,
+    },
+];
+
+/// Tickers IB had no data for, kept by hand before `fetch_failures` existed
+const UNAVAILABLE_TICKERS: &str = include_str!("../data/unavailable.conf");
+
+/// The last migration applied, 0 for a database from before `schema_version`
+fn schema_version(conn: &Connection) -> rusqlite::Result<i64> {
+    let versioned: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !versioned {
+        return Ok(0);
+    }
+    conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )
+}
+
+/// Apply the migrations past the database's version, each in its own transaction, unless
+/// `dry_run`. Returns the ones that were (or would be) applied.
+fn migrate(conn: &mut Connection, dry_run: bool) -> anyhow::Result<Vec<&'static Migration>> {
+    let version = schema_version(conn)?;
+    let latest = MIGRATIONS.last().map_or(0, |m| m.version);
+    if version > latest {
+        anyhow::bail!(
+            "database is at schema version {}, newer than this slurp's {}",
+            version,
+            latest
+        );
+    }
+    let pending: Vec<&'static Migration> =
+        MIGRATIONS.iter().filter(|m| m.version > version).collect();
+    if dry_run {
+        return Ok(pending);
+    }
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+           version INTEGER PRIMARY KEY NOT NULL,
+           name TEXT NOT NULL,
+           applied_at INTEGER NOT NULL
+         )",
+        [],
+    )?;
+    for migration in pending.iter() {
+        eprintln!("migrating to {} ({})", migration.version, migration.name);
+        let tx = conn.transaction()?;
+        (migration.apply)(&tx).with_context(|| format!("migration {} failed", migration.name))?;
+        tx.execute(
+            "INSERT INTO schema_version (version, name, applied_at)
+             VALUES (?, ?, strftime('%s', 'now'))",
+            params![migration.version, migration.name],
+        )?;
+        tx.commit()?;
+    }
+    Ok(pending)
+}
+
+/// The tables as they were when migrations were introduced. `IF NOT EXISTS` so databases from
+/// before then are adopted as they are.
+fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS daily (
            id INTEGER PRIMARY KEY NOT NULL,
@@ -46,6 +207,41 @@ fn init_tables(conn: &mut Connection) -> rusqlite::Result<()> {
         [],
     )?;
 
+    for (table, _) in METRIC_TABLES {
+        conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                   daily_id INTEGER PRIMARY KEY NOT NULL,
+                   value REAL
+                 )",
+                table
+            ),
+            [],
+        )?;
+    }
+
+    // Intraday candles, keyed on the bar's start time
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS bars (
+           id INTEGER PRIMARY KEY NOT NULL,
+           ticker TEXT,
+           bar_size TEXT,
+           timestamp INTEGER,
+           high REAL,
+           low REAL,
+           open REAL,
+           close REAL,
+           avg REAL,
+           volume INTEGER,
+           count INTEGER
+         )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS bars_idx ON bars (ticker, bar_size, timestamp)",
+        [],
+    )?;
+
     conn.execute(
         "CREATE TABLE IF NOT EXISTS ticker_exchange (
            ticker TEXT PRIMARY KEY NOT NULL,
@@ -53,9 +249,247 @@ fn init_tables(conn: &mut Connection) -> rusqlite::Result<()> {
          )",
         [],
     )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS contracts (
+           ticker TEXT PRIMARY KEY NOT NULL,
+           con_id INTEGER NOT NULL,
+           primary_exchange TEXT,
+           long_name TEXT,
+           industry TEXT,
+           category TEXT,
+           subcategory TEXT,
+           currency TEXT,
+           updated_at INTEGER
+         )",
+        [],
+    )?;
+
+    // Splits and dividends, keyed on the daily close timestamp of the ex-date
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS corporate_actions (
+           ticker TEXT NOT NULL,
+           timestamp INTEGER NOT NULL,
+           kind TEXT NOT NULL,
+           value REAL NOT NULL,
+           source TEXT,
+           PRIMARY KEY (ticker, timestamp, kind)
+         )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS fetch_failures (
+           ticker TEXT NOT NULL,
+           source TEXT NOT NULL,
+           error_code INTEGER,
+           message TEXT,
+           permanent INTEGER NOT NULL,
+           attempts INTEGER NOT NULL DEFAULT 1,
+           failed_at INTEGER NOT NULL,
+           PRIMARY KEY (ticker, source)
+         )",
+        [],
+    )?;
+
+    // Named ticker lists (i.e. "large-cap"), replaced wholesale on each import
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS universes (
+           name TEXT NOT NULL,
+           ticker TEXT NOT NULL,
+           market_cap REAL,
+           imported_at INTEGER NOT NULL,
+           PRIMARY KEY (name, ticker)
+         )",
+        [],
+    )?;
+
+    // A data vendor's symbol => IB ticker, NULL for symbols IB doesn't have
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS symbol_map (
+           vendor TEXT NOT NULL,
+           symbol TEXT NOT NULL,
+           ticker TEXT,
+           PRIMARY KEY (vendor, symbol)
+         )",
+        [],
+    )?;
+
+    // Tickers `audit` found problems with, waiting on a full refetch
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS refetch_queue (
+           ticker TEXT PRIMARY KEY NOT NULL,
+           reason TEXT,
+           queued_at INTEGER NOT NULL
+         )",
+        [],
+    )?;
+
+    // How each ticker's last incremental update lined up with what we had cached
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS reconciliations (
+           ticker TEXT NOT NULL,
+           source TEXT NOT NULL,
+           outcome TEXT NOT NULL,
+           detail TEXT,
+           reconciled_at INTEGER NOT NULL,
+           PRIMARY KEY (ticker, source)
+         )",
+        [],
+    )?;
     Ok(())
 }
 
+/// Daily candles used to be keyed on 4PM EDT all year, which is 3PM in the winter. Move those
+/// onto 4PM EST, dropping any that have since been re-fetched under the right key.
+fn rekey_winter_closes(conn: &Connection) -> rusqlite::Result<()> {
+    // 20:00 UTC
+    let stale: Vec<i64> = {
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT timestamp FROM daily WHERE timestamp % 86400 = 72000
+             UNION
+             SELECT DISTINCT timestamp FROM corporate_actions WHERE timestamp % 86400 = 72000",
+        )?;
+        let timestamps = stmt.query_map([], |row| row.get(0))?;
+        timestamps
+            .collect::<rusqlite::Result<Vec<i64>>>()?
+            .into_iter()
+            .filter(|ts| calendar::close_timestamp(calendar::ny_date(*ts)) != *ts)
+            .collect()
+    };
+    if stale.is_empty() {
+        return Ok(());
+    }
+    eprintln!("re-keying {} winter daily closes", stale.len());
+    for ts in stale {
+        let key = ts + 3600;
+        for table in ["daily", "corporate_actions"] {
+            conn.execute(
+                &format!(
+                    "UPDATE OR IGNORE {} SET timestamp = ? WHERE timestamp = ?",
+                    table
+                ),
+                params![key, ts],
+            )?;
+            if table == "daily" {
+                // whatever's left already has a candle at `key`, and takes its metrics with it
+                for (metric, _) in METRIC_TABLES {
+                    conn.execute(
+                        &format!(
+                            "DELETE FROM {} WHERE daily_id IN
+                               (SELECT id FROM daily WHERE timestamp = ?)",
+                            metric
+                        ),
+                        [ts],
+                    )?;
+                }
+            }
+            conn.execute(&format!("DELETE FROM {} WHERE timestamp = ?", table), [ts])?;
+        }
+    }
+    Ok(())
+}
+
+/// The in-progress candle of each streamed ticker, kept out of `daily` so nothing mistakes it for
+/// a settled one
+fn create_live_daily(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS live_daily (
+           ticker TEXT PRIMARY KEY NOT NULL,
+           timestamp INTEGER NOT NULL,
+           open REAL NOT NULL,
+           high REAL NOT NULL,
+           low REAL NOT NULL,
+           close REAL NOT NULL,
+           volume INTEGER NOT NULL,
+           updated_at INTEGER NOT NULL
+         )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Carry the old unavailable.conf over into the ledger of databases filled while it was in use, so
+/// `full` and `incremental` keep skipping those tickers until `--retry-failed`. A new database
+/// records them itself the first time IB turns them down.
+fn seed_unavailable_tickers(conn: &Connection) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT OR IGNORE INTO fetch_failures (ticker, source, message, permanent, failed_at)
+         SELECT ?, 'ibkr', 'listed in unavailable.conf', 1, strftime('%s', 'now')
+         WHERE EXISTS (SELECT 1 FROM daily)",
+    )?;
+    for ticker in UNAVAILABLE_TICKERS.lines().map(str::trim) {
+        if !ticker.is_empty() {
+            stmt.execute([ticker])?;
+        }
+    }
+    Ok(())
+}
+
+/// Drop the ticker's indicator values from the candle at `since` on. Each one depends on every
+/// candle before it, so a changed candle spoils its own value and all the later ones.
+fn delete_metrics(conn: &Connection, ticker: &str, since: i64) -> rusqlite::Result<()> {
+    for (table, _) in METRIC_TABLES {
+        conn.execute(
+            &format!(
+                "DELETE FROM {} WHERE daily_id IN
+                   (SELECT id FROM daily WHERE ticker = ? AND timestamp >= ?)",
+                table
+            ),
+            params![ticker, since],
+        )?;
+    }
+    Ok(())
+}
+
+/// Insert or update daily candles in place, keeping their ids. Candles we already have as they
+/// are get skipped, and the indicator values are dropped from the first one that didn't.
+fn insert_daily(conn: &Connection, ticker: &str, daily_quotes: &[Quote]) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO daily
+          (ticker, timestamp, open, close, high, low, avg, volume, count, adjclose)
+        VALUES
+          (?,      ?,         ?,    ?,   ?,    ?,     ?,   ?,      ?,     ?)
+        ON CONFLICT (ticker, timestamp) DO UPDATE SET
+          open = excluded.open, close = excluded.close, high = excluded.high,
+          low = excluded.low, avg = excluded.avg, volume = excluded.volume,
+          count = excluded.count, adjclose = excluded.adjclose
+        WHERE (open, close, high, low, avg, volume, count, adjclose) IS NOT
+          (excluded.open, excluded.close, excluded.high, excluded.low, excluded.avg,
+           excluded.volume, excluded.count, excluded.adjclose)",
+    )?;
+    let mut first_changed: Option<i64> = None;
+    for quote in daily_quotes {
+        let changed = stmt.execute(params![
+            ticker,
+            quote.timestamp,
+            &quote.open,
+            &quote.close,
+            &quote.high,
+            &quote.low,
+            &quote.avg,
+            &quote.volume,
+            &quote.count,
+            &quote.adjclose
+        ])?;
+        if changed > 0 {
+            first_changed = Some(first_changed.map_or(quote.timestamp, |f| f.min(quote.timestamp)));
+        }
+    }
+    if let Some(first) = first_changed {
+        delete_metrics(conn, ticker, first)?;
+    }
+    Ok(())
+}
+
+/// `file`, or the default database under `$HOME`
+pub fn path(file: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    match file {
+        Some(file) => Ok(file),
+        None => Ok(PathBuf::from(env::var("HOME")?).join(DEFAULT_FILE)),
+    }
+}
+
 fn ensure_parent(db_path: &Path) -> anyhow::Result<()> {
     if let Some(parent) = db_path.parent() {
         if !parent.as_os_str().is_empty() {
@@ -75,6 +509,8 @@ fn row_to_quote(row: &rusqlite::Row) -> rusqlite::Result<QuoteRow> {
     let avg: f64 = row.get(6)?;
     let volume: i64 = row.get(7)?;
     let count: i32 = row.get(8)?;
+    // rows from before adjclose was written
+    let adjclose: Option<f64> = row.get(9)?;
     let quote = Quote {
         timestamp,
         open,
@@ -84,19 +520,34 @@ fn row_to_quote(row: &rusqlite::Row) -> rusqlite::Result<QuoteRow> {
         avg,
         volume,
         count,
+        adjclose: adjclose.unwrap_or(close),
     };
     Ok(QuoteRow { id, quote })
 }
 
 impl Db {
+    /// Open the database, bringing its schema up to date
     pub fn init(file: Option<PathBuf>) -> anyhow::Result<Self> {
-        let home = env::var("HOME")?;
-        let db_path = file.unwrap_or(PathBuf::from(home).join(DEFAULT_FILE));
+        let mut db = Db::open(file)?;
+        migrate(&mut db.conn, false)?;
+        Ok(db)
+    }
+
+    /// Open the database without migrating it
+    pub fn open(file: Option<PathBuf>) -> anyhow::Result<Self> {
+        let db_path = path(file)?;
         ensure_parent(&db_path)?;
-        let mut conn = Connection::open(&db_path)?;
-        init_tables(&mut conn)?;
-        let instance = Db { conn };
-        Ok(instance)
+        let conn = Connection::open(&db_path)?;
+        Ok(Db { conn })
+    }
+
+    pub fn schema_version(&self) -> anyhow::Result<i64> {
+        Ok(schema_version(&self.conn)?)
+    }
+
+    /// Apply (or with `dry_run`, list) the pending migrations
+    pub fn migrate(&mut self, dry_run: bool) -> anyhow::Result<Vec<&'static Migration>> {
+        migrate(&mut self.conn, dry_run)
     }
 
     pub fn get_exchange(&self, ticker: &str) -> anyhow::Result<Option<String>> {
@@ -111,52 +562,72 @@ impl Db {
         Ok(row)
     }
 
-    /*
-    pub fn get_all_daily_quotes(&self, ticker: &str) -> anyhow::Result<Vec<QuoteRow>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, timestamp, open, close, high, low, avg, volume, count
-         FROM daily
-         WHERE ticker = ?
-         ORDER BY timestamp ASC",
+    pub fn get_contract(&self, ticker: &str) -> anyhow::Result<Option<ContractRow>> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT ticker, con_id, primary_exchange, long_name, industry, category,
+                   subcategory, currency
+                 FROM contracts
+                 WHERE ticker = ?",
+                [ticker],
+                |row| {
+                    Ok(ContractRow {
+                        ticker: row.get(0)?,
+                        con_id: row.get(1)?,
+                        primary_exchange: row.get(2)?,
+                        long_name: row.get(3)?,
+                        industry: row.get(4)?,
+                        category: row.get(5)?,
+                        subcategory: row.get(6)?,
+                        currency: row.get(7)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(row)
+    }
+
+    pub fn upsert_contract(&self, contract: &ContractRow) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO contracts
+               (ticker, con_id, primary_exchange, long_name, industry, category, subcategory,
+                currency, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, strftime('%s', 'now'))",
+            params![
+                contract.ticker,
+                contract.con_id,
+                contract.primary_exchange,
+                contract.long_name,
+                contract.industry,
+                contract.category,
+                contract.subcategory,
+                contract.currency,
+            ],
         )?;
-        let mut rows = stmt.query([ticker])?;
-        // rows.try_into
-        let mut result = vec![];
-        while let Some(row) = rows.next()? {
-            result.push(row_to_quote(row)?);
-        }
-        Ok(result)
+        Ok(())
     }
-    */
 
-    pub fn get_daily_batch(
+    /// `get_candles` for each of `tickers`, leaving out the ones without any
+    pub fn get_candles_batch(
         &self,
         tickers: &[String],
+        bar_size: BarSize,
+        range: Range,
     ) -> anyhow::Result<BTreeMap<String, Vec<QuoteRow>>> {
-        let mut vars = "?,".repeat(tickers.len());
-        vars.pop();
-        let sql = format!(
-            "SELECT id, timestamp, open, close, high, low, avg, volume, count, ticker
-             FROM daily
-             WHERE ticker IN ({})
-             ORDER BY timestamp ASC",
-            vars,
-        );
-        let mut stmt = self.conn.prepare(&sql)?;
-
-        let mut rows = stmt.query(rusqlite::params_from_iter(tickers))?;
-        let mut sym2quotes: BTreeMap<String, Vec<QuoteRow>> = BTreeMap::new();
-        while let Some(row) = rows.next()? {
-            let ticker: String = row.get(9)?;
-            let quote = row_to_quote(row)?;
-            sym2quotes.entry(ticker).or_default().push(quote);
+        let mut sym2quotes = BTreeMap::new();
+        for ticker in tickers {
+            let rows = self.get_candles(ticker, bar_size, range)?;
+            if !rows.is_empty() {
+                sym2quotes.insert(ticker.clone(), rows);
+            }
         }
         Ok(sym2quotes)
     }
 
     pub fn get_last_quote(&self, ticker: &str) -> anyhow::Result<QuoteRow> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, timestamp, open, close, high, low, avg, volume, count
+            "SELECT id, timestamp, open, close, high, low, avg, volume, count, adjclose
          FROM daily
          WHERE ticker = ?
          ORDER BY timestamp DESC
@@ -168,71 +639,924 @@ impl Db {
         Ok(quote_row)
     }
 
-    pub fn get_quote_with_timestamp(
+    /// A ticker's `bar_size` candles keyed within `range`, oldest first
+    pub fn get_candles(
         &self,
         ticker: &str,
+        bar_size: BarSize,
+        range: Range,
+    ) -> anyhow::Result<Vec<QuoteRow>> {
+        let start = range.start.unwrap_or(i64::MIN);
+        let end = range.end.unwrap_or(i64::MAX);
+        let rows = if bar_size.is_intraday() {
+            let mut stmt = self.conn.prepare(
+                "SELECT id, timestamp, open, close, high, low, avg, volume, count, close
+                 FROM bars
+                 WHERE ticker = ? AND bar_size = ? AND timestamp BETWEEN ? AND ?
+                 ORDER BY timestamp ASC",
+            )?;
+            let rows = stmt
+                .query_map(params![ticker, bar_size.name(), start, end], row_to_quote)?
+                .collect::<rusqlite::Result<Vec<QuoteRow>>>()?;
+            rows
+        } else {
+            let mut stmt = self.conn.prepare(
+                "SELECT id, timestamp, open, close, high, low, avg, volume, count, adjclose
+                 FROM daily
+                 WHERE ticker = ? AND timestamp BETWEEN ? AND ?
+                 ORDER BY timestamp ASC",
+            )?;
+            let rows = stmt
+                .query_map(params![ticker, start, end], row_to_quote)?
+                .collect::<rusqlite::Result<Vec<QuoteRow>>>()?;
+            rows
+        };
+        Ok(rows)
+    }
+
+    pub fn insert_daily_quotes(
+        &mut self,
+        ticker: &str,
+        daily_quotes: &[Quote],
+    ) -> anyhow::Result<()> {
+        let tx = self.conn.transaction()?;
+        insert_daily(&tx, ticker, daily_quotes)?;
+        Ok(tx.commit()?)
+    }
+
+    /// Like `insert_daily_quotes`, but first drop the candles we had between the first and last of
+    /// `daily_quotes` that it doesn't have (duplicates, candles on holidays, ...), since a full
+    /// fetch is the authority on the sessions it covers
+    pub fn replace_daily_quotes(
+        &mut self,
+        ticker: &str,
+        daily_quotes: &[Quote],
+    ) -> anyhow::Result<()> {
+        let (first, last) = match (daily_quotes.first(), daily_quotes.last()) {
+            (Some(first), Some(last)) => (first.timestamp, last.timestamp),
+            _ => return Ok(()),
+        };
+        let fetched: HashSet<i64> = daily_quotes.iter().map(|q| q.timestamp).collect();
+        let tx = self.conn.transaction()?;
+        let stale: Vec<i64> = {
+            let mut stmt = tx.prepare(
+                "SELECT timestamp FROM daily WHERE ticker = ? AND timestamp BETWEEN ? AND ?",
+            )?;
+            let timestamps = stmt.query_map(params![ticker, first, last], |row| row.get(0))?;
+            timestamps
+                .collect::<rusqlite::Result<Vec<i64>>>()?
+                .into_iter()
+                .filter(|ts| !fetched.contains(ts))
+                .collect()
+        };
+        if let Some(first_stale) = stale.iter().min() {
+            delete_metrics(&tx, ticker, *first_stale)?;
+        }
+        for ts in stale {
+            tx.execute(
+                "DELETE FROM daily WHERE ticker = ? AND timestamp = ?",
+                params![ticker, ts],
+            )?;
+        }
+        insert_daily(&tx, ticker, daily_quotes)?;
+        Ok(tx.commit()?)
+    }
+
+    /// A dividend or split that went ex after `timestamp` changes the adjusted close of every
+    /// candle up to it. Rescale the stored adjusted closes before `timestamp` by however much the
+    /// one at `timestamp` moved to reach `adjclose`.
+    pub fn rescale_adjcloses(
+        &mut self,
+        ticker: &str,
         timestamp: i64,
-    ) -> anyhow::Result<Option<QuoteRow>> {
-        eprintln!("{} {}", ticker, timestamp);
+        adjclose: f64,
+    ) -> anyhow::Result<()> {
+        let cached: Option<Option<f64>> = self
+            .conn
+            .query_row(
+                "SELECT adjclose FROM daily WHERE ticker = ? AND timestamp = ?",
+                params![ticker, timestamp],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let cached = match cached.flatten() {
+            Some(cached) if cached > 0.0 => cached,
+            _ => return Ok(()),
+        };
+        let factor = adjclose / cached;
+        if (factor - 1.0).abs() > 1e-9 {
+            eprintln!("rescaling {} adjusted closes by {}", ticker, factor);
+            self.conn.execute(
+                "UPDATE daily SET adjclose = adjclose * ? WHERE ticker = ? AND timestamp < ?",
+                params![factor, ticker, timestamp],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Store the closes of an adjusted series (i.e. IB's ADJUSTED_LAST) as the adjusted closes of
+    /// the candles we already have
+    pub fn update_adjcloses(&mut self, ticker: &str, adjusted: &[Quote]) -> anyhow::Result<()> {
+        if let Some(first) = adjusted.first() {
+            self.rescale_adjcloses(ticker, first.timestamp, first.close)?;
+        }
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt =
+                tx.prepare("UPDATE daily SET adjclose = ? WHERE ticker = ? AND timestamp = ?")?;
+            for quote in adjusted {
+                stmt.execute(params![quote.close, ticker, quote.timestamp])?;
+            }
+        }
+        Ok(tx.commit()?)
+    }
+
+    pub fn insert_corporate_actions(
+        &self,
+        ticker: &str,
+        source: &str,
+        actions: &[CorporateAction],
+    ) -> anyhow::Result<()> {
+        for action in actions {
+            self.conn.execute(
+                "INSERT OR REPLACE INTO corporate_actions (ticker, timestamp, kind, value, source)
+                 VALUES (?, ?, ?, ?, ?)",
+                params![
+                    ticker,
+                    action.timestamp,
+                    action.kind.name(),
+                    action.value,
+                    source
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_corporate_actions(&self, ticker: &str) -> anyhow::Result<Vec<CorporateAction>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, timestamp, open, close, high, low, avg, volume, count
-             FROM daily
-             WHERE ticker = ? AND timestamp = ?",
+            "SELECT timestamp, kind, value
+             FROM corporate_actions
+             WHERE ticker = ?
+             ORDER BY timestamp ASC",
         )?;
-        let quote_row = stmt
-            .query_row(params![ticker, timestamp], |row| Ok(row_to_quote(row)?))
-            .optional()?;
-        Ok(quote_row)
+        let mut rows = stmt.query([ticker])?;
+        let mut actions = vec![];
+        while let Some(row) = rows.next()? {
+            let kind: String = row.get(1)?;
+            if let Some(kind) = ActionKind::from_name(&kind) {
+                actions.push(CorporateAction {
+                    timestamp: row.get(0)?,
+                    kind,
+                    value: row.get(2)?,
+                });
+            }
+        }
+        Ok(actions)
     }
 
-    pub fn insert_daily_quotes(
+    pub fn insert_bars(
         &mut self,
         ticker: &str,
-        daily_quotes: &[Quote],
+        bar_size: BarSize,
+        bars: &[Quote],
     ) -> anyhow::Result<()> {
         let tx = self.conn.transaction()?;
         {
             let mut stmt = tx.prepare(
-                "INSERT OR REPLACE INTO daily
-                  (ticker, timestamp, open, close, high, low, avg, volume, count)
+                "INSERT OR REPLACE INTO bars
+                  (ticker, bar_size, timestamp, open, close, high, low, avg, volume, count)
                 VALUES
-                  (?,      ?,         ?,    ?,   ?,    ?,     ?,   ?,      ?)",
+                  (?,      ?,        ?,         ?,    ?,     ?,    ?,   ?,   ?,      ?)",
             )?;
-            for quote in daily_quotes {
+            for bar in bars {
                 stmt.execute(params![
                     ticker,
-                    quote.timestamp,
-                    &quote.open,
-                    &quote.close,
-                    &quote.high,
-                    &quote.low,
-                    &quote.avg,
-                    &quote.volume,
-                    &quote.count
+                    bar_size.name(),
+                    bar.timestamp,
+                    &bar.open,
+                    &bar.close,
+                    &bar.high,
+                    &bar.low,
+                    &bar.avg,
+                    &bar.volume,
+                    &bar.count
                 ])?;
             }
         }
         Ok(tx.commit()?)
     }
 
-    /*
-    pub fn insert_calculations(
-        &mut self,
-        table: &str,
-        values: &[(i32, f64)],
-    ) -> anyhow::Result<()> {
+    pub fn get_last_bar(
+        &self,
+        ticker: &str,
+        bar_size: BarSize,
+    ) -> anyhow::Result<Option<QuoteRow>> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT id, timestamp, open, close, high, low, avg, volume, count, close
+                 FROM bars
+                 WHERE ticker = ? AND bar_size = ?
+                 ORDER BY timestamp DESC
+                 LIMIT 1",
+                params![ticker, bar_size.name()],
+                row_to_quote,
+            )
+            .optional()?;
+        Ok(row)
+    }
+
+    pub fn insert_calculations(&mut self, calculations: &Calculations) -> anyhow::Result<()> {
         let tx = self.conn.transaction()?;
         {
             let fmt_stmt = format!(
                 "INSERT OR REPLACE INTO {} (daily_id, value) VALUES (?, ?)",
-                table
+                calculations.table
             );
             let mut stmt = tx.prepare(&fmt_stmt)?;
-            for row in values {
+            for row in calculations.values.iter() {
                 stmt.execute(params![row.0, row.1])?;
             }
         }
         Ok(tx.commit()?)
     }
-    */
+
+    /// The `daily_id`s of the ticker's rows in `table`
+    fn get_calculated_ids(&self, table: &str, ticker: &str) -> anyhow::Result<HashSet<i32>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT c.daily_id FROM {} c JOIN daily d ON d.id = c.daily_id WHERE d.ticker = ?",
+            table
+        ))?;
+        let ids = stmt.query_map([ticker], |row| row.get(0))?;
+        Ok(ids.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Fill the indicator tables for the ticker's daily rows that have no value yet. Returns the
+    /// number of values inserted.
+    pub fn calculate_and_insert_metrics(&mut self, ticker: &str) -> anyhow::Result<usize> {
+        let rows = self.get_candles(ticker, BarSize::Day1, Range::default())?;
+        let indicators: Vec<Indicator> = METRIC_TABLES.iter().map(|(_, i)| *i).collect();
+        // the whole history is needed to warm up, even if only the tail gets inserted
+        let series = Indicators::compute(&rows, &indicators);
+        let mut inserted = 0;
+        for (table, indicator) in METRIC_TABLES {
+            let calculated = self.get_calculated_ids(table, ticker)?;
+            let values: Vec<(i32, f64)> = rows
+                .iter()
+                .enumerate()
+                .filter(|(_, row)| !calculated.contains(&row.id))
+                .filter_map(|(idx, row)| series.value(&indicator, idx).map(|v| (row.id, v)))
+                .collect();
+            inserted += values.len();
+            self.insert_calculations(&Calculations {
+                table: table.to_string(),
+                values,
+            })?;
+        }
+        Ok(inserted)
+    }
+
+    /// Forget the ticker's indicator values so the next calculation starts from scratch
+    pub fn delete_calculations(&self, ticker: &str) -> anyhow::Result<()> {
+        Ok(delete_metrics(&self.conn, ticker, i64::MIN)?)
+    }
+
+    /// Daily rows deleted out from under their indicator values (or re-ided by an older slurp)
+    /// leave those values dangling
+    pub fn delete_orphaned_calculations(&self) -> anyhow::Result<usize> {
+        let mut deleted = 0;
+        for (table, _) in METRIC_TABLES {
+            deleted += self.conn.execute(
+                &format!(
+                    "DELETE FROM {} WHERE daily_id NOT IN (SELECT id FROM daily)",
+                    table
+                ),
+                [],
+            )?;
+        }
+        Ok(deleted)
+    }
+
+    /// Record (or bump the attempt count of) a failed fetch
+    pub fn record_fetch_failure(
+        &self,
+        ticker: &str,
+        source: &str,
+        error_code: Option<i32>,
+        message: &str,
+        permanent: bool,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO fetch_failures
+               (ticker, source, error_code, message, permanent, failed_at)
+             VALUES (?, ?, ?, ?, ?, strftime('%s', 'now'))
+             ON CONFLICT (ticker, source) DO UPDATE SET
+               error_code = excluded.error_code,
+               message = excluded.message,
+               permanent = excluded.permanent,
+               attempts = attempts + 1,
+               failed_at = excluded.failed_at",
+            params![ticker, source, error_code, message, permanent],
+        )?;
+        Ok(())
+    }
+
+    /// Forget a ticker's failures once a fetch for it succeeds
+    pub fn clear_fetch_failure(&self, ticker: &str, source: &str) -> anyhow::Result<()> {
+        self.conn.execute(
+            "DELETE FROM fetch_failures WHERE ticker = ? AND source = ?",
+            [ticker, source],
+        )?;
+        Ok(())
+    }
+
+    /// Tickers not worth requesting from `source` again
+    pub fn get_permanent_failures(&self, source: &str) -> anyhow::Result<HashSet<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT ticker FROM fetch_failures WHERE source = ? AND permanent = 1")?;
+        let tickers = stmt
+            .query_map([source], |row| row.get(0))?
+            .collect::<rusqlite::Result<HashSet<String>>>()?;
+        Ok(tickers)
+    }
+
+    pub fn get_fetch_failures(&self) -> anyhow::Result<Vec<FetchFailure>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT ticker, source, error_code, message, permanent, attempts, failed_at
+             FROM fetch_failures
+             ORDER BY source, ticker",
+        )?;
+        let failures = stmt
+            .query_map([], |row| {
+                Ok(FetchFailure {
+                    ticker: row.get(0)?,
+                    source: row.get(1)?,
+                    error_code: row.get(2)?,
+                    message: row.get(3)?,
+                    permanent: row.get(4)?,
+                    attempts: row.get(5)?,
+                    failed_at: row.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<FetchFailure>>>()?;
+        Ok(failures)
+    }
+
+    pub fn record_reconciliation(
+        &self,
+        ticker: &str,
+        source: &str,
+        outcome: &Outcome,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO reconciliations (ticker, source, outcome, detail, reconciled_at)
+             VALUES (?, ?, ?, ?, strftime('%s', 'now'))",
+            params![ticker, source, outcome.name(), outcome.detail()],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_reconciliations(&self) -> anyhow::Result<Vec<Reconciliation>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT ticker, source, outcome, detail, reconciled_at
+             FROM reconciliations
+             ORDER BY source, ticker",
+        )?;
+        let reconciliations = stmt
+            .query_map([], |row| {
+                Ok(Reconciliation {
+                    ticker: row.get(0)?,
+                    source: row.get(1)?,
+                    outcome: row.get(2)?,
+                    detail: row.get(3)?,
+                    reconciled_at: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Reconciliation>>>()?;
+        Ok(reconciliations)
+    }
+
+    pub fn upsert_live_daily(&self, ticker: &str, quote: &Quote) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO live_daily
+               (ticker, timestamp, open, high, low, close, volume, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, strftime('%s', 'now'))",
+            params![
+                ticker,
+                quote.timestamp,
+                quote.open,
+                quote.high,
+                quote.low,
+                quote.close,
+                quote.volume,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The in-progress candle streamed for `ticker` on the session closing at `timestamp`
+    pub fn get_live_daily(&self, ticker: &str, timestamp: i64) -> anyhow::Result<Option<Quote>> {
+        let quote = self
+            .conn
+            .query_row(
+                "SELECT open, high, low, close, volume
+                 FROM live_daily
+                 WHERE ticker = ? AND timestamp = ?",
+                params![ticker, timestamp],
+                |row| {
+                    Ok(Quote {
+                        timestamp,
+                        open: row.get(0)?,
+                        high: row.get(1)?,
+                        low: row.get(2)?,
+                        close: row.get(3)?,
+                        volume: row.get(4)?,
+                        adjclose: row.get(3)?,
+                        ..Quote::default()
+                    })
+                },
+            )
+            .optional()?;
+        Ok(quote)
+    }
+
+    /// Drop the in-progress candles of sessions before the one closing at `timestamp`. Returns how
+    /// many there were.
+    pub fn delete_live_daily_before(&self, timestamp: i64) -> anyhow::Result<usize> {
+        Ok(self
+            .conn
+            .execute("DELETE FROM live_daily WHERE timestamp < ?", [timestamp])?)
+    }
+
+    /// Drop the in-progress candles of the session closing at `timestamp`, i.e. once it has
+    pub fn delete_live_daily(&self, timestamp: i64) -> anyhow::Result<usize> {
+        Ok(self
+            .conn
+            .execute("DELETE FROM live_daily WHERE timestamp = ?", [timestamp])?)
+    }
+
+    pub fn enqueue_refetch(&self, ticker: &str, reason: &str) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO refetch_queue (ticker, reason, queued_at)
+             VALUES (?, ?, strftime('%s', 'now'))",
+            [ticker, reason],
+        )?;
+        Ok(())
+    }
+
+    /// (ticker, reason) of everything waiting on a full refetch, oldest first
+    pub fn get_refetch_queue(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT ticker, reason FROM refetch_queue ORDER BY queued_at, ticker")?;
+        let queue = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+        Ok(queue)
+    }
+
+    /// Take a ticker off the refetch queue once a full fetch for it succeeds
+    pub fn clear_refetch(&self, ticker: &str) -> anyhow::Result<()> {
+        self.conn
+            .execute("DELETE FROM refetch_queue WHERE ticker = ?", [ticker])?;
+        Ok(())
+    }
+
+    /// Replace the `name` universe with `members`
+    pub fn replace_universe(&mut self, name: &str, members: &[Member]) -> anyhow::Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM universes WHERE name = ?", [name])?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO universes (name, ticker, market_cap, imported_at)
+                 VALUES (?, ?, ?, strftime('%s', 'now'))",
+            )?;
+            for member in members {
+                stmt.execute(params![name, member.ticker, member.market_cap])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The `name` universe, largest first
+    pub fn get_universe(&self, name: &str) -> anyhow::Result<Vec<Member>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT ticker, market_cap FROM universes
+             WHERE name = ?
+             ORDER BY market_cap IS NULL, market_cap DESC, ticker",
+        )?;
+        let members = stmt
+            .query_map([name], |row| {
+                Ok(Member {
+                    ticker: row.get(0)?,
+                    market_cap: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Member>>>()?;
+        Ok(members)
+    }
+
+    /// (name, member count, when it was imported) of every universe
+    pub fn get_universes(&self) -> anyhow::Result<Vec<(String, usize, i64)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT name, COUNT(*), MAX(imported_at) FROM universes GROUP BY name ORDER BY name",
+        )?;
+        let universes = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<Vec<(String, usize, i64)>>>()?;
+        Ok(universes)
+    }
+
+    pub fn delete_universe(&self, name: &str) -> anyhow::Result<usize> {
+        Ok(self
+            .conn
+            .execute("DELETE FROM universes WHERE name = ?", [name])?)
+    }
+
+    /// Map `vendor`'s `symbol` to an IB `ticker`, or to nothing if IB doesn't have it
+    pub fn set_symbol_mapping(
+        &self,
+        vendor: &str,
+        symbol: &str,
+        ticker: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO symbol_map (vendor, symbol, ticker) VALUES (?, ?, ?)",
+            params![vendor, symbol, ticker],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_symbol_map(&self, vendor: &str) -> anyhow::Result<HashMap<String, Option<String>>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT symbol, ticker FROM symbol_map WHERE vendor = ?")?;
+        let map = stmt
+            .query_map([vendor], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<String, Option<String>>>>()?;
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn daily(timestamp: i64, close: f64, adjclose: f64) -> Quote {
+        Quote {
+            timestamp,
+            close,
+            adjclose,
+            ..Quote::default()
+        }
+    }
+
+    #[test]
+    fn test_adjclose_rescale() {
+        let mut db = Db::init(Some(PathBuf::from(":memory:"))).unwrap();
+        db.insert_daily_quotes("KO", &[daily(1, 60.0, 60.0), daily(2, 61.0, 61.0)])
+            .unwrap();
+        // a 0.44 dividend went ex on day 3
+        db.update_adjcloses("KO", &[daily(2, 60.56, 60.56), daily(3, 61.0, 61.0)])
+            .unwrap();
+        let rows = db
+            .get_candles("KO", BarSize::Day1, Range::default())
+            .unwrap();
+        let adj: Vec<f64> = rows.iter().map(|r| r.quote.adjclose).collect();
+        assert!((adj[0] - 60.0 * 60.56 / 61.0).abs() < 1e-9);
+        assert_eq!(adj[1], 60.56);
+        // day 3 wasn't cached yet, so there's nothing to update
+        assert_eq!(adj.len(), 2);
+        assert_eq!(rows[1].quote.close, 61.0);
+    }
+
+    #[test]
+    fn test_migrations() {
+        // a database from before schema_version, with closes keyed on 4PM EDT in the winter
+        let mut db = Db::open(Some(PathBuf::from(":memory:"))).unwrap();
+        create_tables(&db.conn).unwrap();
+        // 2022-12-01 and 2022-06-23 at 4PM EDT
+        let (winter, summer) = (1669924800, 1656014400);
+        db.insert_daily_quotes("AAPL", &[daily(winter, 148.31, 148.31)])
+            .unwrap();
+        db.insert_daily_quotes("AAPL", &[daily(summer, 138.27, 138.27)])
+            .unwrap();
+        // the same winter close, already fetched again under the right key, and metrics for each
+        db.insert_daily_quotes("AAPL", &[daily(winter + 3600, 148.31, 148.31)])
+            .unwrap();
+        for (table, _) in METRIC_TABLES {
+            db.conn
+                .execute(
+                    &format!("INSERT INTO {} SELECT id, close FROM daily", table),
+                    [],
+                )
+                .unwrap();
+        }
+        assert_eq!(db.schema_version().unwrap(), 0);
+
+        let pending: Vec<&str> = db.migrate(true).unwrap().iter().map(|m| m.name).collect();
+        assert_eq!(
+            pending,
+            vec![
+                "create_tables",
+                "rekey_winter_closes",
+                "create_live_daily",
+                "seed_unavailable_tickers"
+            ]
+        );
+        // a dry run leaves the database alone
+        assert_eq!(db.schema_version().unwrap(), 0);
+
+        assert_eq!(db.migrate(false).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(db.schema_version().unwrap(), MIGRATIONS.len() as i64);
+        let rows = db
+            .get_candles("AAPL", BarSize::Day1, Range::default())
+            .unwrap();
+        let timestamps: Vec<i64> = rows.iter().map(|r| r.quote.timestamp).collect();
+        assert_eq!(timestamps, vec![summer, winter + 3600]);
+        // the dropped duplicate's metrics go with it
+        for (table, _) in METRIC_TABLES {
+            let orphans: i64 = db
+                .conn
+                .query_row(
+                    &format!(
+                        "SELECT COUNT(*) FROM {} WHERE daily_id NOT IN (SELECT id FROM daily)",
+                        table
+                    ),
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(orphans, 0, "{}", table);
+            assert_eq!(metric(&db, table, "AAPL").len(), 2, "{}", table);
+        }
+        let unavailable = db.get_permanent_failures("ibkr").unwrap();
+        assert_eq!(unavailable.len(), 61);
+        assert!(unavailable.contains("ACC"));
+        assert!(db.migrate(false).unwrap().is_empty());
+
+        // a database migrated by a newer slurp
+        db.conn
+            .execute(
+                "INSERT INTO schema_version (version, name, applied_at) VALUES (99, 'future', 0)",
+                [],
+            )
+            .unwrap();
+        assert!(db.migrate(true).is_err());
+    }
+
+    #[test]
+    fn test_winter_closes_are_rekeyed_once() {
+        let file = std::env::temp_dir().join(format!("slurp-rekey-{}.sqlite3", std::process::id()));
+        let _ = std::fs::remove_file(&file);
+        let winter = 1669924800;
+        {
+            let mut db = Db::open(Some(file.clone())).unwrap();
+            create_tables(&db.conn).unwrap();
+            db.insert_daily_quotes("AAPL", &[daily(winter, 148.31, 148.31)])
+                .unwrap();
+        }
+        let mut db = Db::init(Some(file.clone())).unwrap();
+        assert_eq!(
+            db.get_last_quote("AAPL").unwrap().quote.timestamp,
+            winter + 3600
+        );
+
+        // the migration is recorded, so later starts don't scan or touch the candles again
+        db.insert_daily_quotes("MSFT", &[daily(winter, 255.14, 255.14)])
+            .unwrap();
+        drop(db);
+        let db = Db::init(Some(file.clone())).unwrap();
+        assert_eq!(
+            db.get_last_quote("AAPL").unwrap().quote.timestamp,
+            winter + 3600
+        );
+        assert_eq!(db.get_last_quote("MSFT").unwrap().quote.timestamp, winter);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_migrations_are_in_order() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i64 + 1, "{}", migration.name);
+        }
+    }
+
+    /// The ticker's values in a metric table, oldest first
+    fn metric(db: &Db, table: &str, ticker: &str) -> Vec<(i32, f64)> {
+        let mut stmt = db
+            .conn
+            .prepare(&format!(
+                "SELECT c.daily_id, c.value FROM {} c JOIN daily d ON d.id = c.daily_id
+                 WHERE d.ticker = ? ORDER BY d.timestamp",
+                table
+            ))
+            .unwrap();
+        let values = stmt
+            .query_map([ticker], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        values.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    fn expected_ema_8(db: &Db, ticker: &str) -> Vec<(i32, f64)> {
+        let rows = db
+            .get_candles(ticker, BarSize::Day1, Range::default())
+            .unwrap();
+        crate::calc::get_exp_moving_avgs(8, &rows)
+    }
+
+    #[test]
+    fn test_metrics_are_incremental() {
+        let mut db = Db::init(Some(PathBuf::from(":memory:"))).unwrap();
+        let quotes: Vec<Quote> = (0..60)
+            .map(|i| daily(i * 86400, 100.0 + (i % 7) as f64, 100.0 + (i % 7) as f64))
+            .collect();
+        db.insert_daily_quotes("AAPL", &quotes[..55]).unwrap();
+        db.calculate_and_insert_metrics("AAPL").unwrap();
+        assert_eq!(metric(&db, "ema_8", "AAPL"), expected_ema_8(&db, "AAPL"));
+        assert_eq!(db.calculate_and_insert_metrics("AAPL").unwrap(), 0);
+
+        // appending only calculates the new candles
+        let before = metric(&db, "ema_8", "AAPL");
+        db.insert_daily_quotes("AAPL", &quotes[55..]).unwrap();
+        db.calculate_and_insert_metrics("AAPL").unwrap();
+        let after = metric(&db, "ema_8", "AAPL");
+        assert_eq!(after[..before.len()], before[..]);
+        assert_eq!(after, expected_ema_8(&db, "AAPL"));
+
+        // an incremental fetch overlapping what we have leaves the unchanged candles' values alone
+        // and only calculates the changed ones
+        let mut overlap = quotes[50..].to_vec();
+        overlap[7].close = 90.0;
+        db.insert_daily_quotes("AAPL", &overlap).unwrap();
+        let kept = metric(&db, "ema_8", "AAPL");
+        assert_eq!(kept[..], after[..after.len() - 3]);
+        // ema_8, ema_21, ema_34 and sma_50 for each of the last 3 candles
+        assert_eq!(db.calculate_and_insert_metrics("AAPL").unwrap(), 4 * 3);
+        let after = metric(&db, "ema_8", "AAPL");
+        assert_eq!(after[..kept.len()], kept[..]);
+        assert_eq!(after, expected_ema_8(&db, "AAPL"));
+
+        db.delete_calculations("AAPL").unwrap();
+        assert!(metric(&db, "ema_8", "AAPL").is_empty());
+        db.calculate_and_insert_metrics("AAPL").unwrap();
+        assert_eq!(metric(&db, "ema_8", "AAPL"), after);
+    }
+
+    #[test]
+    fn test_refetched_candles_recalculate_metrics() {
+        let mut db = Db::init(Some(PathBuf::from(":memory:"))).unwrap();
+        let mut quotes: Vec<Quote> = (0..30)
+            .map(|i| daily(i * 86400, 100.0 + i as f64, 100.0 + i as f64))
+            .collect();
+        db.insert_daily_quotes("AAPL", &quotes).unwrap();
+        db.insert_daily_quotes("MSFT", &quotes).unwrap();
+        db.calculate_and_insert_metrics("AAPL").unwrap();
+        db.calculate_and_insert_metrics("MSFT").unwrap();
+        let msft = metric(&db, "ema_8", "MSFT");
+        let aapl = metric(&db, "ema_8", "AAPL");
+        let ids: Vec<i32> = db
+            .get_candles("AAPL", BarSize::Day1, Range::default())
+            .unwrap()
+            .iter()
+            .map(|r| r.id)
+            .collect();
+
+        // a corrected candle mid-series changes every value after it, but none before
+        quotes[20].close = 90.0;
+        db.insert_daily_quotes("AAPL", &quotes[20..21]).unwrap();
+        let kept: Vec<(i32, f64)> = aapl
+            .iter()
+            .copied()
+            .filter(|(id, _)| *id < ids[20])
+            .collect();
+        assert_eq!(metric(&db, "ema_8", "AAPL"), kept);
+        db.calculate_and_insert_metrics("AAPL").unwrap();
+        assert_eq!(metric(&db, "ema_8", "AAPL"), expected_ema_8(&db, "AAPL"));
+
+        quotes[25].close = 95.0;
+        db.replace_daily_quotes("AAPL", &quotes[10..]).unwrap();
+        db.calculate_and_insert_metrics("AAPL").unwrap();
+        assert_eq!(metric(&db, "ema_8", "AAPL"), expected_ema_8(&db, "AAPL"));
+        assert_eq!(db.delete_orphaned_calculations().unwrap(), 0);
+        // the candles kept their ids throughout
+        let refetched: Vec<i32> = db
+            .get_candles("AAPL", BarSize::Day1, Range::default())
+            .unwrap()
+            .iter()
+            .map(|r| r.id)
+            .collect();
+        assert_eq!(refetched, ids);
+        // other tickers keep theirs
+        assert_eq!(metric(&db, "ema_8", "MSFT"), msft);
+    }
+
+    #[test]
+    fn test_fetch_failure_ledger() {
+        let db = Db::init(Some(PathBuf::from(":memory:"))).unwrap();
+        db.record_fetch_failure("CASH", "ibkr", Some(200), "No security definition", true)
+            .unwrap();
+        db.record_fetch_failure("AAPL", "ibkr", Some(322), "Error processing request", false)
+            .unwrap();
+        db.record_fetch_failure("CASH", "ibkr", Some(200), "No security definition", true)
+            .unwrap();
+        db.record_fetch_failure("CASH", "yahoo", None, "404 Not Found", true)
+            .unwrap();
+
+        let expected: HashSet<String> = ["CASH".to_string()].into_iter().collect();
+        assert_eq!(db.get_permanent_failures("ibkr").unwrap(), expected);
+        let failures = db.get_fetch_failures().unwrap();
+        assert_eq!(failures.len(), 3);
+        assert_eq!(failures[1].ticker, "CASH");
+        assert_eq!(failures[1].attempts, 2);
+
+        db.clear_fetch_failure("CASH", "ibkr").unwrap();
+        assert!(db.get_permanent_failures("ibkr").unwrap().is_empty());
+        assert_eq!(db.get_permanent_failures("yahoo").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_refetch_replaces_range() {
+        let mut db = Db::init(Some(PathBuf::from(":memory:"))).unwrap();
+        // a duplicate of the second session, keyed an hour off
+        db.insert_daily_quotes(
+            "AAPL",
+            &[
+                daily(1000, 1.0, 1.0),
+                daily(2000, 2.0, 2.0),
+                daily(2000 + 3600, 2.0, 2.0),
+                daily(9000, 9.0, 9.0),
+            ],
+        )
+        .unwrap();
+        db.enqueue_refetch("AAPL", "duplicate").unwrap();
+        assert_eq!(
+            db.get_refetch_queue().unwrap(),
+            vec![("AAPL".to_string(), "duplicate".to_string())]
+        );
+
+        db.replace_daily_quotes("AAPL", &[daily(2000, 2.5, 2.5), daily(8000, 8.0, 8.0)])
+            .unwrap();
+        db.clear_refetch("AAPL").unwrap();
+        let rows = db
+            .get_candles("AAPL", BarSize::Day1, Range::default())
+            .unwrap();
+        let closes: Vec<(i64, f64)> = rows
+            .iter()
+            .map(|r| (r.quote.timestamp, r.quote.close))
+            .collect();
+        // candles outside the fetched range are left alone
+        assert_eq!(
+            closes,
+            vec![(1000, 1.0), (2000, 2.5), (8000, 8.0), (9000, 9.0)]
+        );
+        assert!(db.get_refetch_queue().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_universes() {
+        let mut db = Db::init(Some(PathBuf::from(":memory:"))).unwrap();
+        let member = |ticker: &str, market_cap: Option<f64>| Member {
+            ticker: ticker.to_string(),
+            market_cap,
+        };
+        db.replace_universe(
+            "large-cap",
+            &[member("MSFT", Some(2e12)), member("NEW", None)],
+        )
+        .unwrap();
+        db.replace_universe(
+            "large-cap",
+            &[member("BRK B", Some(6e11)), member("AAPL", Some(2.5e12))],
+        )
+        .unwrap();
+        db.replace_universe("mine", &[member("TSLA", None)])
+            .unwrap();
+        assert_eq!(
+            db.get_universe("large-cap").unwrap(),
+            vec![member("AAPL", Some(2.5e12)), member("BRK B", Some(6e11))]
+        );
+        let names: Vec<(String, usize)> = db
+            .get_universes()
+            .unwrap()
+            .into_iter()
+            .map(|(name, count, _)| (name, count))
+            .collect();
+        assert_eq!(
+            names,
+            vec![("large-cap".to_string(), 2), ("mine".to_string(), 1)]
+        );
+        assert_eq!(db.delete_universe("mine").unwrap(), 1);
+
+        db.set_symbol_mapping("vendor", "ACC", None).unwrap();
+        db.set_symbol_mapping("vendor", "TVTY", Some("NLSN"))
+            .unwrap();
+        let map = db.get_symbol_map("vendor").unwrap();
+        assert_eq!(map["ACC"], None);
+        assert_eq!(map["TVTY"], Some("NLSN".to_string()));
+        assert!(db.get_symbol_map("other").unwrap().is_empty());
+    }
 }
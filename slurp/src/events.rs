@@ -0,0 +1,89 @@
+use std::collections::{BTreeMap, HashMap};
+
+use ibtwsapi::core::messages::ServerRspMsg;
+
+pub type Handler = Box<dyn FnMut(&ServerRspMsg)>;
+
+/// Fans IB events out to handlers registered by `ServerRspMsg` variant name (i.e. "TickPrice"),
+/// and keeps count of the events nobody wanted instead of treating them as fatal.
+#[derive(Default)]
+pub struct Dispatcher {
+    handlers: HashMap<String, Vec<Handler>>,
+    unhandled: BTreeMap<String, usize>,
+}
+
+/// The variant name of an event, i.e. "HistoricalData"
+pub fn event_name(event: &ServerRspMsg) -> String {
+    event.to_string()
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Dispatcher::default()
+    }
+
+    /// Call `handler` for every event of the `variant` kind
+    pub fn subscribe<F>(&mut self, variant: &str, handler: F)
+    where
+        F: FnMut(&ServerRspMsg) + 'static,
+    {
+        self.handlers
+            .entry(variant.to_string())
+            .or_default()
+            .push(Box::new(handler));
+    }
+
+    /// Run the handlers subscribed to this kind of event. Returns whether there were any.
+    pub fn dispatch(&mut self, event: &ServerRspMsg) -> bool {
+        match self.handlers.get_mut(&event_name(event)) {
+            Some(handlers) if !handlers.is_empty() => {
+                for handler in handlers.iter_mut() {
+                    handler(event);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Note an event that neither the caller nor any subscriber handled. Logged the first time
+    /// each kind is seen.
+    pub fn unhandled(&mut self, event: &ServerRspMsg) {
+        let count = self.unhandled.entry(event_name(event)).or_insert(0);
+        if *count == 0 {
+            eprintln!("ignoring unhandled event: {:?}", event);
+        }
+        *count += 1;
+    }
+
+    pub fn unhandled_counts(&self) -> &BTreeMap<String, usize> {
+        &self.unhandled
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_dispatch_by_variant() {
+        let mut events = Dispatcher::new();
+        let seen = Rc::new(Cell::new(0));
+        let s = seen.clone();
+        events.subscribe("MarketDataType", move |_| s.set(s.get() + 1));
+
+        let market_data_type = ServerRspMsg::MarketDataType {
+            req_id: 1,
+            market_data_type: 3,
+        };
+        assert!(events.dispatch(&market_data_type));
+        assert!(!events.dispatch(&ServerRspMsg::OpenOrderEnd));
+        events.unhandled(&ServerRspMsg::OpenOrderEnd);
+        events.unhandled(&ServerRspMsg::OpenOrderEnd);
+
+        assert_eq!(seen.get(), 1);
+        assert_eq!(events.unhandled_counts().get("OpenOrderEnd"), Some(&2));
+    }
+}
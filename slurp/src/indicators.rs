@@ -0,0 +1,358 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::calc;
+use crate::db::QuoteRow;
+use crate::quote::Quote;
+use crate::stoch;
+
+/// One value per candle, aligned with the candles it was computed from. `None` until the
+/// indicator has warmed up (i.e. the first 7 candles of an `ema(8)`).
+pub type Series = Vec<Option<f64>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Indicator {
+    Open,
+    High,
+    Low,
+    Close,
+    Volume,
+    Ema(usize),
+    Sma(usize),
+    Rsi(usize),
+    /// Average true range (RMA of the true ranges)
+    Atr(usize),
+    /// Directional index over `di_len` candles, RMA smoothed over `adx_len`, as 0-100
+    Adx(usize, usize),
+    /// Fast stochastic %K
+    Stoch(usize),
+    /// %K smoothed twice: (k_len, k_smoothing, d_smoothing)
+    SlowStoch(usize, usize, usize),
+    /// Average shares traded per candle
+    AvgVolume(usize),
+    /// Average of close * volume per candle
+    DollarVolume(usize),
+    /// `Atr` as a percentage of the close
+    AtrPct(usize),
+}
+
+impl Indicator {
+    fn periods(&self) -> Vec<usize> {
+        match *self {
+            Indicator::Open
+            | Indicator::High
+            | Indicator::Low
+            | Indicator::Close
+            | Indicator::Volume => vec![],
+            Indicator::Ema(n)
+            | Indicator::Sma(n)
+            | Indicator::Rsi(n)
+            | Indicator::Atr(n)
+            | Indicator::Stoch(n)
+            | Indicator::AvgVolume(n)
+            | Indicator::DollarVolume(n)
+            | Indicator::AtrPct(n) => vec![n],
+            Indicator::Adx(di_len, adx_len) => vec![di_len, adx_len],
+            Indicator::SlowStoch(k_len, k_smooth, d_smooth) => vec![k_len, k_smooth, d_smooth],
+        }
+    }
+
+    /// How many leading candles have no value
+    pub fn warmup(&self) -> usize {
+        match *self {
+            Indicator::Open
+            | Indicator::High
+            | Indicator::Low
+            | Indicator::Close
+            | Indicator::Volume => 0,
+            Indicator::Ema(n)
+            | Indicator::Sma(n)
+            | Indicator::Stoch(n)
+            | Indicator::AvgVolume(n)
+            | Indicator::DollarVolume(n) => n - 1,
+            // these start from the change between candles
+            Indicator::Rsi(n) | Indicator::Atr(n) | Indicator::AtrPct(n) => n,
+            Indicator::Adx(di_len, adx_len) => di_len + adx_len - 1,
+            Indicator::SlowStoch(k_len, k_smooth, d_smooth) => k_len + k_smooth + d_smooth - 3,
+        }
+    }
+
+    /// This indicator over `rows`, whose candles are `quotes`
+    fn compute(&self, rows: &[QuoteRow], quotes: &[Quote]) -> Series {
+        let len = quotes.len();
+        if self.periods().contains(&0) || self.warmup() >= len {
+            return vec![None; len];
+        }
+        let closes = || quotes.iter().map(|q| q.close).collect::<Vec<f64>>();
+        let values = match *self {
+            Indicator::Open => quotes.iter().map(|q| q.open).collect(),
+            Indicator::High => quotes.iter().map(|q| q.high).collect(),
+            Indicator::Low => quotes.iter().map(|q| q.low).collect(),
+            Indicator::Close => closes(),
+            Indicator::Volume => quotes.iter().map(|q| q.volume as f64).collect(),
+            Indicator::Ema(n) => calc::get_exp_moving_avgs(n, rows)
+                .into_iter()
+                .map(|(_, ema)| ema)
+                .collect(),
+            Indicator::Sma(n) => stoch::get_smas(&closes(), n),
+            Indicator::Rsi(n) => stoch::get_rsis(quotes, n),
+            Indicator::Atr(n) => stoch::get_rmas(&stoch::get_true_ranges(quotes), n),
+            Indicator::Adx(di_len, adx_len) => {
+                stoch::get_rmas(&stoch::get_adxs(quotes, di_len), adx_len)
+                    .into_iter()
+                    .map(|adx| adx * 100.0)
+                    .collect()
+            }
+            Indicator::Stoch(k_len) => stoch::get_stochastics(rows, k_len),
+            Indicator::SlowStoch(k_len, k_smooth, d_smooth) => {
+                let ks = stoch::get_smas(&stoch::get_stochastics(rows, k_len), k_smooth);
+                stoch::get_smas(&ks, d_smooth)
+            }
+            Indicator::AvgVolume(n) => {
+                let volumes: Vec<f64> = quotes.iter().map(|q| q.volume as f64).collect();
+                stoch::get_smas(&volumes, n)
+            }
+            Indicator::DollarVolume(n) => {
+                let dollars: Vec<f64> = quotes.iter().map(|q| q.close * q.volume as f64).collect();
+                stoch::get_smas(&dollars, n)
+            }
+            Indicator::AtrPct(n) => {
+                return Indicator::Atr(n)
+                    .compute(rows, quotes)
+                    .into_iter()
+                    .zip(quotes)
+                    .map(|(atr, q)| {
+                        atr.filter(|_| q.close > 0.0)
+                            .map(|atr| atr / q.close * 100.0)
+                    })
+                    .collect();
+            }
+        };
+        align(len, values)
+    }
+}
+
+/// Pad `values`, which end at the last candle, with `None`s so they line up with all `len`
+fn align(len: usize, values: Vec<f64>) -> Series {
+    let mut series = vec![None; len.saturating_sub(values.len())];
+    series.extend(values.into_iter().map(Some));
+    series.truncate(len);
+    series
+}
+
+impl fmt::Display for Indicator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Indicator::Open => write!(f, "open"),
+            Indicator::High => write!(f, "high"),
+            Indicator::Low => write!(f, "low"),
+            Indicator::Close => write!(f, "close"),
+            Indicator::Volume => write!(f, "volume"),
+            Indicator::Ema(n) => write!(f, "ema({})", n),
+            Indicator::Sma(n) => write!(f, "sma({})", n),
+            Indicator::Rsi(n) => write!(f, "rsi({})", n),
+            Indicator::Atr(n) => write!(f, "atr({})", n),
+            Indicator::Adx(di_len, adx_len) => write!(f, "adx({},{})", di_len, adx_len),
+            Indicator::Stoch(n) => write!(f, "stoch({})", n),
+            Indicator::SlowStoch(k_len, k_smooth, d_smooth) => {
+                write!(f, "slow_stoch({},{},{})", k_len, k_smooth, d_smooth)
+            }
+            Indicator::AvgVolume(n) => write!(f, "avg_volume({})", n),
+            Indicator::DollarVolume(n) => write!(f, "dollar_volume({})", n),
+            Indicator::AtrPct(n) => write!(f, "atr_pct({})", n),
+        }
+    }
+}
+
+/// i.e. "close", "ema(8)" or "slow_stoch(8, 3, 3)"
+impl FromStr for Indicator {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim().to_lowercase();
+        let (name, args) = match s.find('(') {
+            Some(open) if s.ends_with(')') => (&s[..open], &s[open + 1..s.len() - 1]),
+            _ => (s.as_str(), ""),
+        };
+        let args: Vec<usize> = args
+            .split(',')
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+            .map(|a| match a.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(n),
+                _ => Err(anyhow::anyhow!("bad period '{}' in '{}'", a, s)),
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let indicator = match (name.trim(), args.as_slice()) {
+            ("open", []) => Indicator::Open,
+            ("high", []) => Indicator::High,
+            ("low", []) => Indicator::Low,
+            ("close", []) => Indicator::Close,
+            ("volume", []) => Indicator::Volume,
+            ("ema", [n]) => Indicator::Ema(*n),
+            ("sma", [n]) => Indicator::Sma(*n),
+            ("rsi", [n]) => Indicator::Rsi(*n),
+            ("atr", [n]) => Indicator::Atr(*n),
+            ("adx", [n]) => Indicator::Adx(*n, 1),
+            ("adx", [di_len, adx_len]) => Indicator::Adx(*di_len, *adx_len),
+            ("stoch", [n]) => Indicator::Stoch(*n),
+            ("slow_stoch", [k_len, k_smooth, d_smooth]) => {
+                Indicator::SlowStoch(*k_len, *k_smooth, *d_smooth)
+            }
+            ("avg_volume", [n]) => Indicator::AvgVolume(*n),
+            ("dollar_volume", [n]) => Indicator::DollarVolume(*n),
+            ("atr_pct", [n]) => Indicator::AtrPct(*n),
+            _ => anyhow::bail!("unknown indicator '{}'", s),
+        };
+        Ok(indicator)
+    }
+}
+
+/// A set of indicators computed over one ticker's candles
+pub struct Indicators {
+    len: usize,
+    series: Vec<(Indicator, Series)>,
+}
+
+impl Indicators {
+    pub fn compute(rows: &[QuoteRow], indicators: &[Indicator]) -> Self {
+        let quotes: Vec<Quote> = rows.iter().map(|row| row.quote.clone()).collect();
+        let mut series: Vec<(Indicator, Series)> = Vec::with_capacity(indicators.len());
+        for indicator in indicators {
+            if !series.iter().any(|(i, _)| i == indicator) {
+                series.push((*indicator, indicator.compute(rows, &quotes)));
+            }
+        }
+        Indicators {
+            len: rows.len(),
+            series,
+        }
+    }
+
+    pub fn series(&self, indicator: &Indicator) -> Option<&Series> {
+        self.series
+            .iter()
+            .find(|(i, _)| i == indicator)
+            .map(|(_, series)| series)
+    }
+
+    /// The indicator's value at candle `idx`, if it was computed and has warmed up by then
+    pub fn value(&self, indicator: &Indicator, idx: usize) -> Option<f64> {
+        self.series(indicator)
+            .and_then(|series| series.get(idx).copied().flatten())
+    }
+
+    pub fn last(&self, indicator: &Indicator) -> Option<f64> {
+        self.len
+            .checked_sub(1)
+            .and_then(|idx| self.value(indicator, idx))
+    }
+
+    /// Whether `indicators` are strictly decreasing (or increasing, if `!descending`) at `idx`.
+    /// False if any of them has no value there.
+    pub fn ordered(&self, indicators: &[Indicator], idx: usize, descending: bool) -> bool {
+        let values: Option<Vec<f64>> = indicators.iter().map(|i| self.value(i, idx)).collect();
+        match values {
+            Some(values) => {
+                values
+                    .windows(2)
+                    .all(|w| if descending { w[0] > w[1] } else { w[0] < w[1] })
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rows(closes: &[f64]) -> Vec<QuoteRow> {
+        closes
+            .iter()
+            .enumerate()
+            .map(|(id, close)| QuoteRow {
+                id: id as i32,
+                quote: Quote {
+                    close: *close,
+                    high: close + 1.0,
+                    low: close - 1.0,
+                    ..Quote::default()
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_aligned_series() {
+        let rows = rows(&[2.0, 3.0, 4.0, 5.5, 6.0, 7.0]);
+        let compute = |indicator: Indicator| {
+            Indicators::compute(&rows, &[indicator])
+                .series(&indicator)
+                .unwrap()
+                .clone()
+        };
+        let ema = compute(Indicator::Ema(3));
+        assert_eq!(ema.len(), rows.len());
+        assert_eq!(&ema[..2], &[None, None]);
+        assert_eq!(ema[2], Some(3.333333333333333));
+        assert_eq!(ema[5], Some(6.104166666666666));
+
+        let sma = compute(Indicator::Sma(3));
+        assert_eq!(sma[1], None);
+        assert_eq!(sma[2], Some(3.0));
+
+        for indicator in [
+            Indicator::Rsi(2),
+            Indicator::Atr(2),
+            Indicator::Adx(2, 2),
+            Indicator::SlowStoch(2, 2, 2),
+            Indicator::AvgVolume(3),
+            Indicator::DollarVolume(3),
+            Indicator::AtrPct(2),
+        ] {
+            let series = compute(indicator);
+            assert_eq!(series.len(), rows.len());
+            let warmed = series.iter().position(Option::is_some);
+            assert_eq!(warmed, Some(indicator.warmup()), "{}", indicator);
+        }
+        // the rows have no volume, and a true range of 2 on a close of 4
+        assert_eq!(compute(Indicator::DollarVolume(3))[2], Some(0.0));
+        assert_eq!(compute(Indicator::AtrPct(1))[2], Some(50.0));
+
+        // not enough candles to warm up at all
+        assert_eq!(compute(Indicator::Ema(8)), vec![None; 6]);
+    }
+
+    #[test]
+    fn test_indicators() {
+        let rows = rows(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+        let stack = [Indicator::Ema(2), Indicator::Ema(3), Indicator::Ema(5)];
+        let set = Indicators::compute(&rows, &stack);
+        // ema(5) hasn't warmed up yet
+        assert!(!set.ordered(&stack, 3, true));
+        assert!(set.ordered(&stack, 7, true));
+        assert!(!set.ordered(&stack, 7, false));
+        assert_eq!(set.last(&Indicator::Rsi(2)), None);
+    }
+
+    #[test]
+    fn test_parse() {
+        for s in [
+            "close",
+            "ema(8)",
+            "adx(13,1)",
+            "slow_stoch(8,3,3)",
+            "dollar_volume(20)",
+            "atr_pct(14)",
+        ] {
+            assert_eq!(s.parse::<Indicator>().unwrap().to_string(), s);
+        }
+        assert_eq!(
+            "Slow_Stoch(8, 3, 3)".parse::<Indicator>().unwrap(),
+            Indicator::SlowStoch(8, 3, 3)
+        );
+        assert!("ema(0)".parse::<Indicator>().is_err());
+        assert!("ema".parse::<Indicator>().is_err());
+        assert!("macd(12,26)".parse::<Indicator>().is_err());
+    }
+}
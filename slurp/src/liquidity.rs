@@ -0,0 +1,106 @@
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use crate::indicators::Indicator;
+use crate::screen::{Cmp, Condition, Operand};
+
+const DEFAULT_WINDOW: usize = 20;
+
+// Price and volume floors for keeping illiquid names out of a screen. Also the `[liquidity]` table
+// of the config file. Not a doc comment, which structopt would use as the --help of any command
+// flattening it
+#[derive(StructOpt, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Liquidity {
+    /// Candles to average volume, dollar volume and ATR over [default: 20]
+    #[structopt(long)]
+    pub liquidity_window: Option<usize>,
+
+    /// Skip tickers closing below this price
+    #[structopt(long)]
+    pub min_price: Option<f64>,
+
+    /// Skip tickers averaging fewer shares traded per candle
+    #[structopt(long)]
+    pub min_volume: Option<f64>,
+
+    /// Skip tickers averaging less close * volume per candle
+    #[structopt(long)]
+    pub min_dollar_volume: Option<f64>,
+
+    /// Skip tickers whose average true range is less than this percent of the close
+    #[structopt(long)]
+    pub min_atr_pct: Option<f64>,
+
+    /// Skip tickers whose average true range is more than this percent of the close
+    #[structopt(long)]
+    pub max_atr_pct: Option<f64>,
+}
+
+impl Liquidity {
+    /// `self`, with anything not given taken from `defaults` (i.e. the config file)
+    pub fn or(self, defaults: &Liquidity) -> Liquidity {
+        Liquidity {
+            liquidity_window: self.liquidity_window.or(defaults.liquidity_window),
+            min_price: self.min_price.or(defaults.min_price),
+            min_volume: self.min_volume.or(defaults.min_volume),
+            min_dollar_volume: self.min_dollar_volume.or(defaults.min_dollar_volume),
+            min_atr_pct: self.min_atr_pct.or(defaults.min_atr_pct),
+            max_atr_pct: self.max_atr_pct.or(defaults.max_atr_pct),
+        }
+    }
+
+    /// The floors as a screen condition, or `None` if none were given
+    pub fn condition(&self) -> Option<Condition> {
+        let n = self.liquidity_window.unwrap_or(DEFAULT_WINDOW);
+        let limits = [
+            (Indicator::Close, Cmp::Ge, self.min_price),
+            (Indicator::AvgVolume(n), Cmp::Ge, self.min_volume),
+            (Indicator::DollarVolume(n), Cmp::Ge, self.min_dollar_volume),
+            (Indicator::AtrPct(n), Cmp::Ge, self.min_atr_pct),
+            (Indicator::AtrPct(n), Cmp::Le, self.max_atr_pct),
+        ];
+        let conditions: Vec<Condition> = limits
+            .into_iter()
+            .filter_map(|(indicator, cmp, limit)| {
+                limit.map(|limit| {
+                    Condition::Compare(Operand::Indicator(indicator), cmp, Operand::Number(limit))
+                })
+            })
+            .collect();
+        match conditions.len() {
+            0 => None,
+            1 => conditions.into_iter().next(),
+            _ => Some(Condition::And(conditions)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_condition() {
+        let mut liquidity = Liquidity::default();
+        assert_eq!(liquidity.condition(), None);
+        liquidity.min_price = Some(5.0);
+        assert_eq!(liquidity.condition().unwrap().to_string(), "close >= 5");
+        liquidity.min_dollar_volume = Some(2e7);
+        liquidity.max_atr_pct = Some(8.0);
+        assert_eq!(
+            liquidity.condition().unwrap().to_string(),
+            "(close >= 5) and (dollar_volume(20) >= 20000000) and (atr_pct(20) <= 8)"
+        );
+
+        let defaults = Liquidity {
+            liquidity_window: Some(50),
+            min_price: Some(10.0),
+            ..Liquidity::default()
+        };
+        let merged = liquidity.or(&defaults);
+        assert_eq!(merged.min_price, Some(5.0));
+        assert_eq!(merged.liquidity_window, Some(50));
+        assert_eq!(merged.max_atr_pct, Some(8.0));
+    }
+}
@@ -1,118 +1,793 @@
-use std::io::{self, prelude::*};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, prelude::*, IsTerminal};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 mod app;
+mod audit;
+mod backtest;
+mod bars;
 mod calc;
+mod calendar;
+mod candidates;
+mod chart;
 mod cli;
+mod config;
+mod corporate;
 mod db;
+mod events;
+mod indicators;
+mod liquidity;
+#[cfg(test)]
+mod mock_tws;
+mod output;
 mod quote;
+mod reconcile;
+mod scheduler;
+mod screen;
+mod show;
+mod source;
 mod stoch;
+mod stream;
+mod universe;
+mod yahoo;
 
-use crate::cli::{Args, Command};
+use crate::bars::BarSize;
+use crate::candidates::TrendCandidate;
+use crate::chart::Chart;
+use crate::cli::{Args, Command, DbCommand, UniverseCommand};
+use crate::config::{Config, Tickers};
+use crate::db::QuoteRow;
+use crate::indicators::Indicator;
+use crate::liquidity::Liquidity;
+use crate::output::{Field, Format, Record};
 use crate::quote::Quote;
+use crate::screen::{Screen, Signal};
+use crate::source::{QuoteSource, Source};
+use crate::yahoo::YahooSource;
 use app::App;
 
-fn main() -> anyhow::Result<()> {
-    let db = db::Db::init(None)?;
+fn connect_ibkr(db: db::Db, args: &Args, force: bool, adjusted: bool) -> anyhow::Result<App> {
+    let mut app = App::new(db, args.req_limit, force);
+    app.adjusted = adjusted;
+    let gateway = &args.gateway;
+    app.client
+        .connect(gateway.host(), gateway.port(), gateway.client_id())?;
+    Ok(app)
+}
+
+fn quote_source(
+    source: Source,
+    db: db::Db,
+    args: &Args,
+    force: bool,
+    adjusted: bool,
+) -> anyhow::Result<Box<dyn QuoteSource>> {
+    Ok(match source {
+        Source::Ibkr => Box::new(connect_ibkr(db, args, force, adjusted)?),
+        Source::Yahoo => Box::new(YahooSource::new(db, force)),
+    })
+}
+
+/// The members of universe `name`
+fn universe_tickers(db: &db::Db, name: &str) -> anyhow::Result<Vec<String>> {
+    let members = db.get_universe(name)?;
+    if members.is_empty() {
+        anyhow::bail!("no universe named '{}' (see `universe list`)", name);
+    }
+    Ok(members.into_iter().map(|m| m.ticker).collect())
+}
+
+/// The newline-delimitted tickers on stdin
+fn stdin_tickers() -> anyhow::Result<Vec<String>> {
+    let lines = io::stdin()
+        .lock()
+        .lines()
+        .collect::<io::Result<Vec<String>>>()?;
+    Ok(lines.into_iter().filter(|l| !l.trim().is_empty()).collect())
+}
+
+/// The tickers to read `from`, which had better be some
+fn input_tickers(db: &db::Db, from: Tickers) -> anyhow::Result<Vec<String>> {
+    let tickers = match from {
+        Tickers::Universe(name) => universe_tickers(db, name)?,
+        Tickers::Stdin => stdin_tickers()?,
+        Tickers::PipedOr(default) => {
+            let piped = if io::stdin().is_terminal() {
+                vec![]
+            } else {
+                stdin_tickers()?
+            };
+            match default {
+                Some(name) if piped.is_empty() => universe_tickers(db, name)?,
+                _ => piped,
+            }
+        }
+    };
+    if tickers.is_empty() {
+        anyhow::bail!("no tickers: pipe some in, pass --universe or set a default universe");
+    }
+    Ok(tickers)
+}
+
+/// `input_tickers`, minus any that failed permanently for `source` (unless `retry_failed`)
+fn read_tickers(
+    db: &db::Db,
+    source: Source,
+    retry_failed: bool,
+    from: Tickers,
+) -> anyhow::Result<Vec<String>> {
+    let failed = if retry_failed {
+        HashSet::new()
+    } else {
+        db.get_permanent_failures(source.name())?
+    };
+    let mut tickers = vec![];
+    for ticker in input_tickers(db, from)? {
+        if failed.contains(&ticker) {
+            eprintln!("skipping {} (failed permanently)", ticker);
+            continue;
+        }
+        tickers.push(ticker);
+    }
+    Ok(tickers)
+}
+
+/// Candles for each of the `input_tickers`, oldest first, up to the daily candle keyed `as_of` if
+/// given
+fn load_quotes(
+    db: &db::Db,
+    from: Tickers,
+    bar_size: BarSize,
+    adjusted: bool,
+    as_of: Option<i64>,
+) -> anyhow::Result<BTreeMap<String, Vec<QuoteRow>>> {
+    if as_of.is_some() && bar_size.is_intraday() {
+        anyhow::bail!("--as-of only applies to daily candles");
+    }
+    let tickers = input_tickers(db, from)?;
+    let range = db::Range {
+        start: None,
+        end: as_of,
+    };
+    let sym2quotes = db.get_candles_batch(&tickers, bar_size, range)?;
+    let sym2quotes: BTreeMap<String, Vec<QuoteRow>> = if adjusted {
+        sym2quotes
+            .into_iter()
+            .map(|(ticker, rows)| {
+                let rows = rows
+                    .into_iter()
+                    .map(|row| QuoteRow {
+                        id: row.id,
+                        quote: row.quote.adjusted(),
+                    })
+                    .collect();
+                (ticker, rows)
+            })
+            .collect()
+    } else {
+        sym2quotes
+    };
+    if sym2quotes.len() != tickers.len() {
+        for ticker in tickers {
+            if !sym2quotes.contains_key(&ticker) {
+                eprintln!("missing quotes for: {}", ticker);
+            }
+        }
+    }
+    Ok(sym2quotes)
+}
+
+/// The screen file a command names (or the config's), filtered by the liquidity floors
+fn load_screen(
+    config: &Config,
+    file: Option<PathBuf>,
+    liquidity: Liquidity,
+) -> anyhow::Result<Screen> {
+    let mut screen = Screen::load(&config.screen_file(file)?)?;
+    if let Some(condition) = liquidity.or(&config.liquidity).condition() {
+        screen.add_filter(condition);
+    }
+    Ok(screen)
+}
+
+/// The names of `signal_fields`
+fn signal_columns(screen: &Screen) -> Vec<String> {
+    let mut names = vec!["signal".to_string(), "side".to_string()];
+    names.extend(screen.columns.iter().map(|c| c.to_string()));
+    names
+}
 
-    let args = Args::from_args();
+/// A triggered signal's name, side and the screen's columns
+fn signal_fields(
+    screen: &Screen,
+    signal: &Signal,
+    columns: Vec<Option<f64>>,
+) -> Vec<(String, Field)> {
+    let mut fields = vec![
+        ("signal".to_string(), Field::Text(signal.name.clone())),
+        (
+            "side".to_string(),
+            Field::Text(signal.side.name().to_string()),
+        ),
+    ];
+    fields.extend(
+        screen
+            .columns
+            .iter()
+            .zip(columns)
+            .map(|(c, value)| (c.to_string(), Field::Number(value))),
+    );
+    fields
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = Args::from_args();
+    let config = Config::load(args.config.as_deref())?;
+    args.gateway = args.gateway.or(&config.gateway);
+    let db_file = args.db.clone().or_else(|| config.db.clone());
+    // `db migrate` decides for itself whether to migrate, and `db path` doesn't need a database
+    let mut db = match args.command {
+        Command::Db(DbCommand::Path) => {
+            println!("{}", db::path(db_file)?.display());
+            return Ok(());
+        }
+        Command::Db(_) => db::Db::open(db_file.clone())?,
+        _ => db::Db::init(db_file.clone())?,
+    };
     match args.command {
-        Command::Full => {
-            let mut app = App::new(db, args.req_limit, false);
-            // port 7497 for TWS or 4001 for IB Gateway, depending on the port you have set
-            app.client.connect(&args.ip, args.port, 7274605)?;
-            for io_ticker in io::stdin().lock().lines() {
-                let ticker = io_ticker?;
-                app.add_ticker_to_request_queue(ticker);
+        Command::Full {
+            source,
+            bar_size,
+            days,
+            retry_failed,
+            ref ticker_args,
+            queued,
+            ..
+        } if bar_size.is_intraday() => {
+            if queued {
+                anyhow::bail!("--queued refetches daily candles only");
+            }
+            if source != Source::Ibkr {
+                anyhow::bail!("{} bars are only available from ibkr", bar_size);
+            }
+            let tickers = read_tickers(
+                &db,
+                source,
+                retry_failed,
+                config.tickers(ticker_args.universe.as_deref()),
+            )?;
+            let mut app = connect_ibkr(db, &args, false, false)?;
+            for ticker in tickers {
+                app.add_bars_request(ticker, bar_size, days);
             }
             app.run()?;
         }
-        Command::Incremental { force } => {
-            let mut app = App::new(db, args.req_limit, force);
-            // port 7497 for TWS or 4001 for IB Gateway, depending on the port you have set
-            app.client.connect(&args.ip, args.port, 7274605)?;
-            for io_ticker in io::stdin().lock().lines() {
-                let ticker = io_ticker?;
-                app.add_incremental_ticker(ticker);
+        Command::Incremental {
+            force,
+            source,
+            bar_size,
+            retry_failed,
+            ref ticker_args,
+            ..
+        } if bar_size.is_intraday() => {
+            if source != Source::Ibkr {
+                anyhow::bail!("{} bars are only available from ibkr", bar_size);
+            }
+            let tickers = read_tickers(
+                &db,
+                source,
+                retry_failed,
+                config.tickers(ticker_args.universe.as_deref()),
+            )?;
+            let mut app = connect_ibkr(db, &args, force, false)?;
+            for ticker in tickers {
+                if let Err(e) = app.add_incremental_bars(ticker, bar_size) {
+                    eprintln!("{}", e);
+                }
             }
             app.run()?;
         }
-        Command::TrendCandidates {
-            ref force,
-            ref ema_period,
-            ref stoch_k_len,
-            ref stoch_k_smoothing,
-            ref stoch_d_smoothing,
-            ref stoch_threshold,
-            ref loose,
-            ref adx_period,
+        Command::Full {
+            source,
+            retry_failed,
+            adjusted,
+            ref ticker_args,
+            queued,
+            ..
         } => {
-            let mut tickers: Vec<String> = Vec::with_capacity(2048);
+            let tickers = if queued {
+                db.get_refetch_queue()?
+                    .into_iter()
+                    .map(|(t, _)| t)
+                    .collect()
+            } else {
+                read_tickers(
+                    &db,
+                    source,
+                    retry_failed,
+                    config.tickers(ticker_args.universe.as_deref()),
+                )?
+            };
+            let mut source = quote_source(source, db, &args, false, adjusted)?;
+            for ticker in tickers {
+                source.add_ticker_to_request_queue(ticker);
+            }
+            source.run()?;
+        }
+        Command::Incremental {
+            force,
+            source,
+            retry_failed,
+            adjusted,
+            ref ticker_args,
+            ..
+        } => {
+            let tickers = read_tickers(
+                &db,
+                source,
+                retry_failed,
+                config.tickers(ticker_args.universe.as_deref()),
+            )?;
+            let mut source = quote_source(source, db, &args, force, adjusted)?;
+            for ticker in tickers {
+                source.add_incremental_ticker(ticker);
+            }
+            source.run()?;
+        }
+        Command::Resolve => {
+            let tickers = read_tickers(&db, Source::Ibkr, true, Tickers::Stdin)?;
+            let mut app = connect_ibkr(db, &args, false, false)?;
+            for ticker in tickers {
+                app.add_ticker_to_resolve(ticker);
+            }
+            app.run()?;
+        }
+        Command::Failures => {
+            println!("ticker\tsource\tcode\tpermanent\tattempts\tmessage");
+            for f in db.get_fetch_failures()? {
+                let code = f.error_code.map(|c| c.to_string()).unwrap_or_default();
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    f.ticker, f.source, code, f.permanent, f.attempts, f.message
+                );
+            }
+        }
+        Command::Reconciliations => {
+            println!("ticker\tsource\toutcome\treconciled_at\tdetail");
+            for r in db.get_reconciliations()? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    r.ticker, r.source, r.outcome, r.reconciled_at, r.detail
+                );
+            }
+        }
+        Command::Audit {
+            stale_sessions,
+            enqueue,
+            ticker_args,
+        } => {
+            let last_session = calendar::last_completed_session(chrono::Utc::now());
+            let mut queued = 0;
+            println!("ticker\tdate\tissue\tdetail");
+            for ticker in input_tickers(&db, config.tickers(ticker_args.universe.as_deref()))? {
+                let rows = db.get_candles(&ticker, BarSize::Day1, db::Range::default())?;
+                if rows.is_empty() {
+                    eprintln!("no daily candles for {}", ticker);
+                    continue;
+                }
+                let actions = db.get_corporate_actions(&ticker)?;
+                let issues = audit::audit(&rows, &actions, last_session, stale_sessions);
+                for issue in issues.iter() {
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        ticker,
+                        issue.date,
+                        issue.kind.name(),
+                        issue.detail
+                    );
+                }
+                if let Some(issue) = issues.iter().find(|i| i.kind.needs_refetch()) {
+                    if enqueue {
+                        db.enqueue_refetch(&ticker, issue.kind.name())?;
+                        queued += 1;
+                    }
+                }
+            }
+            if enqueue {
+                eprintln!("queued {} tickers for `full --queued`", queued);
+            }
+        }
+        Command::ComputeMetrics { force } => {
+            let orphaned = db.delete_orphaned_calculations()?;
+            if orphaned > 0 {
+                eprintln!("deleted {} stale metric rows", orphaned);
+            }
             for io_ticker in io::stdin().lock().lines() {
                 let ticker = io_ticker?;
-                tickers.push(ticker.clone());
+                if force {
+                    db.delete_calculations(&ticker)?;
+                }
+                let inserted = db.calculate_and_insert_metrics(&ticker)?;
+                eprintln!("{} - {} metric rows", ticker, inserted);
             }
-            let sym2quotes = db.get_daily_batch(&tickers)?;
-            if sym2quotes.len() != tickers.len() {
-                for ticker in tickers {
-                    if !sym2quotes.contains_key(&ticker) {
-                        eprintln!("missing quotes for: {}", ticker);
-                    }
+        }
+        Command::Db(DbCommand::Migrate { dry_run }) => {
+            let version = db.schema_version()?;
+            let migrations = db.migrate(dry_run)?;
+            if migrations.is_empty() {
+                eprintln!("up to date at schema version {}", version);
+            }
+            for migration in migrations {
+                let verb = if dry_run { "pending" } else { "applied" };
+                println!("{}\t{}\t{}", verb, migration.version, migration.name);
+            }
+        }
+        Command::Show {
+            ticker,
+            from,
+            to,
+            mut indicators,
+            bar_size,
+            adjusted,
+            output,
+        } => {
+            if matches!(output, Format::TradingView | Format::Ibkr) {
+                anyhow::bail!("show prints tsv, csv or jsonl");
+            }
+            if indicators.is_empty() {
+                indicators = show::DEFAULT_INDICATORS.to_vec();
+            }
+            let range = db::Range {
+                start: None,
+                end: to.map(|to| calendar::day_bounds(to).1),
+            };
+            let mut rows = db.get_candles(&ticker, bar_size, range)?;
+            if rows.is_empty() {
+                anyhow::bail!("no {} candles for {}", bar_size, ticker);
+            }
+            if adjusted {
+                for row in rows.iter_mut() {
+                    row.quote = row.quote.adjusted();
+                }
+            }
+            let start = from.map(|from| calendar::day_bounds(from).0);
+            let records = show::records(&ticker, &rows, bar_size, &indicators, start);
+            let columns = show::columns(&indicators);
+            output::write(&mut io::stdout().lock(), output, &columns, &records)?;
+        }
+        Command::Chart {
+            ticker,
+            bars,
+            height,
+            as_of,
+            adjusted,
+            stoch_k_len,
+            stoch_k_smoothing,
+            stoch_d_smoothing,
+            adx_period,
+            export,
+            no_color,
+        } => {
+            let range = db::Range {
+                start: None,
+                end: as_of,
+            };
+            let mut rows = db.get_candles(&ticker, BarSize::Day1, range)?;
+            if rows.is_empty() {
+                anyhow::bail!("no daily candles for {}", ticker);
+            }
+            if adjusted {
+                for row in rows.iter_mut() {
+                    row.quote = row.quote.adjusted();
+                }
+            }
+            let chart = Chart::new(
+                &ticker,
+                &rows,
+                bars,
+                Indicator::SlowStoch(stoch_k_len, stoch_k_smoothing, stoch_d_smoothing),
+                Indicator::Adx(adx_period, 1),
+            );
+            if let Some(path) = export {
+                let contents = match path.extension().and_then(|e| e.to_str()) {
+                    Some("svg") => chart.to_svg(),
+                    Some("html") | Some("htm") => chart.to_html(),
+                    _ => anyhow::bail!("--export {} isn't an .svg or .html file", path.display()),
+                };
+                std::fs::write(&path, contents)?;
+                eprintln!("wrote {}", path.display());
+            }
+            let color = !no_color && io::stdout().is_terminal();
+            print!("{}", chart.render(height, color));
+        }
+        // printed before opening the database
+        Command::Db(DbCommand::Path) => {}
+        Command::Universe(command) => match command {
+            UniverseCommand::Import {
+                name,
+                file,
+                vendor,
+                min_cap,
+            } => {
+                let rows = universe::parse(&std::fs::read_to_string(&file)?)?;
+                let rules = universe::rules(&vendor, db.get_symbol_map(&vendor)?);
+                let read = rows.len();
+                let members = universe::members(rows, &rules, min_cap);
+                db.replace_universe(&name, &members)?;
+                eprintln!("{}: {} of {} symbols imported", name, members.len(), read);
+            }
+            UniverseCommand::List => {
+                println!("name\ttickers\timported");
+                for (name, count, imported_at) in db.get_universes()? {
+                    println!("{}\t{}\t{}", name, count, calendar::ny_date(imported_at));
+                }
+            }
+            UniverseCommand::Show { name } => {
+                println!("ticker\tmarket_cap");
+                for member in db.get_universe(&name)? {
+                    let cap = member.market_cap.map(|c| c.to_string()).unwrap_or_default();
+                    println!("{}\t{}", member.ticker, cap);
+                }
+            }
+            UniverseCommand::Remove { name } => {
+                let removed = db.delete_universe(&name)?;
+                eprintln!("removed {} tickers from {}", removed, name);
+            }
+            UniverseCommand::Map {
+                symbol,
+                ticker,
+                vendor,
+            } => db.set_symbol_mapping(&vendor, &symbol, ticker.as_deref())?,
+        },
+        Command::Actions => {
+            println!("ticker\ttimestamp\tkind\tvalue");
+            for io_ticker in io::stdin().lock().lines() {
+                let ticker = io_ticker?;
+                for action in db.get_corporate_actions(&ticker)? {
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        ticker,
+                        action.timestamp,
+                        action.kind.name(),
+                        action.value
+                    );
                 }
             }
-            println!("{}\t{}\t{}\t{}\t{}", "ticker", "loose", "stoch", "ADX", "RSI");
+        }
+        Command::Screen {
+            file,
+            bar_size,
+            adjusted,
+            as_of,
+            output,
+            ticker_args,
+            liquidity,
+        } => {
+            let screen = load_screen(&config, file, liquidity)?;
+            eprintln!("screening for {}", screen.name);
+            let sym2quotes = load_quotes(
+                &db,
+                config.tickers(ticker_args.universe.as_deref()),
+                bar_size,
+                adjusted,
+                as_of,
+            )?;
+            let mut records = vec![];
             for (ticker, quotes) in sym2quotes {
-                let ema_8: HashMap<i32, f64> =
-                    calc::get_exp_moving_avgs(8, &quotes).into_iter().collect();
-                let ema_21: HashMap<i32, f64> =
-                    calc::get_exp_moving_avgs(21, &quotes).into_iter().collect();
-                let ema_34: HashMap<i32, f64> =
-                    calc::get_exp_moving_avgs(34, &quotes).into_iter().collect();
-                let ema_89: HashMap<i32, f64> =
-                    calc::get_exp_moving_avgs(89, &quotes).into_iter().collect();
+                if let Some((signal, columns)) = screen.evaluate(&quotes) {
+                    records.push(Record {
+                        ticker,
+                        section: signal.name.clone(),
+                        fields: signal_fields(&screen, signal, columns),
+                    });
+                }
+            }
+            let columns = signal_columns(&screen);
+            output::write(&mut io::stdout().lock(), output, &columns, &records)?;
+        }
+        Command::Stream {
+            ref file,
+            delayed,
+            real_time_bars,
+            interval,
+            max_lines,
+            output,
+            ref ticker_args,
+            ref liquidity,
+        } => {
+            if matches!(output, Format::TradingView | Format::Ibkr) {
+                anyhow::bail!("stream prints tsv, csv or jsonl");
+            }
+            let screen = load_screen(&config, file.clone(), liquidity.clone())?;
+            let now = chrono::Utc::now();
+            let session = calendar::ny_date(now.timestamp());
+            let close = calendar::session_close(session);
+            if !calendar::is_trading_day(session) || now >= close {
+                anyhow::bail!("no session left to stream today");
+            }
+            let tickers = input_tickers(&db, config.tickers(ticker_args.universe.as_deref()))?;
+            let timestamp = calendar::close_timestamp(session);
+            // leaving out today's candle, in case it was fetched already
+            let range = db::Range {
+                start: None,
+                end: Some(timestamp - 1),
+            };
+            let completed = db.get_candles_batch(&tickers, BarSize::Day1, range)?;
+            let mut app = connect_ibkr(db, &args, false, false)?;
+            app.stream(&tickers, session, delayed, real_time_bars, max_lines)?;
+            eprintln!("streaming {} tickers for {}", tickers.len(), screen.name);
 
-                // get 2 months of data for
-                if *ema_period > quotes.len() {
-                    continue;
+            let interval = Duration::from_secs(interval);
+            let mut evaluated = Instant::now();
+            // the signal each ticker last printed, so it's only printed again once it changes
+            let mut triggered: HashMap<String, String> = HashMap::new();
+            let mut header = true;
+            let mut columns = vec!["time".to_string(), "last".to_string()];
+            columns.extend(signal_columns(&screen));
+            // print the tickers whose candle now triggers a different signal than last time
+            let mut evaluate = |updates: Vec<(String, Quote)>| -> anyhow::Result<()> {
+                let time = chrono::Utc::now().with_timezone(&chrono_tz::America::New_York);
+                let mut records = vec![];
+                for (ticker, quote) in updates {
+                    let last = quote.close;
+                    let mut rows = completed.get(&ticker).cloned().unwrap_or_default();
+                    rows.push(QuoteRow { id: 0, quote });
+                    match screen.evaluate(&rows) {
+                        Some((signal, columns)) => {
+                            if triggered.get(&ticker) == Some(&signal.name) {
+                                continue;
+                            }
+                            triggered.insert(ticker.clone(), signal.name.clone());
+                            let mut fields = vec![
+                                (
+                                    "time".to_string(),
+                                    Field::Text(time.format("%H:%M").to_string()),
+                                ),
+                                ("last".to_string(), Field::Number(Some(last))),
+                            ];
+                            fields.extend(signal_fields(&screen, signal, columns));
+                            records.push(Record {
+                                ticker,
+                                section: signal.name.clone(),
+                                fields,
+                            });
+                        }
+                        None => {
+                            if triggered.remove(&ticker).is_some() {
+                                eprintln!("{} no longer triggers", ticker);
+                            }
+                        }
+                    }
                 }
-                let ema_start_idx = quotes.len() - ema_period;
-                let mut is_loose_result = false;
-                let mut bull_trend = quotes[ema_start_idx..].iter().all(|q| {
-                    let i = q.id;
-                    ema_8.get(&i) > ema_21.get(&i) && ema_21.get(&i) > ema_34.get(&i) && ema_34.get(&i) > ema_89.get(&i)
-                });
-                if !bull_trend && *loose {
-                    bull_trend = quotes[ema_start_idx..].iter().all(|q| { ema_8.get(&q.id) > ema_34.get(&q.id) });
-                    is_loose_result = bull_trend;
+                if !records.is_empty() {
+                    let mut stdout = io::stdout().lock();
+                    output::write_rows(&mut stdout, output, &columns, &records, header)?;
+                    stdout.flush()?;
+                    header = false;
                 }
-                let mut bear_trend = quotes[ema_start_idx..].iter().all(|q| {
-                    let i = q.id;
-                    ema_8.get(&i) < ema_21.get(&i) && ema_21.get(&i) < ema_34.get(&i) && ema_34.get(&i) < ema_89.get(&i)
-                });
-                if !bear_trend && *loose {
-                    bear_trend = quotes[ema_start_idx..].iter().all(|q| { ema_8.get(&q.id) < ema_34.get(&q.id) });
-                    is_loose_result |= bear_trend;
+                Ok(())
+            };
+            while app.is_streaming() && chrono::Utc::now() < close {
+                app.process_ib_response()?;
+                if evaluated.elapsed() < interval {
+                    continue;
                 }
-
-                let slow_stoch = stoch::get_slow_stoch(
-                    *stoch_k_len,
-                    *stoch_k_smoothing,
-                    *stoch_d_smoothing,
-                    &quotes,
-                );
-                let quotes: Vec<Quote> = quotes.into_iter().map(|qr| qr.quote).collect();
-                let adxr = stoch::get_adxr(&quotes, *adx_period, 1);
-
-                if *force
-                    || (bull_trend && slow_stoch <= (50.0 - stoch_threshold)
-                        || bear_trend && slow_stoch >= (50.0 + stoch_threshold))
-                        && adxr > 20.0
-                {
-                    let rsi = stoch::get_last_rsi(&quotes, 2);
-                    println!("{}\t{}\t{}\t{}\t{}", ticker, is_loose_result, slow_stoch, adxr, rsi);
+                evaluated = Instant::now();
+                evaluate(app.take_live_updates()?)?;
+            }
+            app.stop_streaming()?;
+            // whatever ticked since the last evaluation
+            evaluate(app.take_live_updates()?)?;
+            eprintln!("stopped streaming at {}", chrono::Utc::now());
+            for (ticker, error) in app.dropped_streams() {
+                eprintln!("dropped {} ({})", ticker, error);
+            }
+            // the session's own candle comes from the next daily fetch
+            if chrono::Utc::now() >= close {
+                app.db.delete_live_daily(timestamp)?;
+            }
+        }
+        Command::Backtest {
+            file,
+            atr_period,
+            atr_stop,
+            max_bars,
+            adjusted,
+            ticker_args,
+        } => {
+            let screen = Screen::load(&config.screen_file(file)?)?;
+            let rules = backtest::Rules {
+                atr_period,
+                atr_stop,
+                max_bars,
+            };
+            let sym2quotes = load_quotes(
+                &db,
+                config.tickers(ticker_args.universe.as_deref()),
+                BarSize::Day1,
+                adjusted,
+                None,
+            )?;
+            println!(
+                "ticker\tsignal\tside\tentry_date\tentry\tstop\t\
+                 exit_date\texit\treason\tbars\treturn\tr"
+            );
+            let mut trades = vec![];
+            for (ticker, quotes) in sym2quotes {
+                for trade in backtest::backtest(&screen, &rules, &ticker, &quotes) {
+                    println!(
+                        "{}\t{}\t{}\t{}\t{:.2}\t{:.2}\t{}\t{:.2}\t{}\t{}\t{:.4}\t{:.2}",
+                        trade.ticker,
+                        trade.signal,
+                        trade.side.name(),
+                        calendar::ny_date(trade.entry_timestamp),
+                        trade.entry,
+                        trade.stop,
+                        calendar::ny_date(trade.exit_timestamp),
+                        trade.exit,
+                        trade.reason.name(),
+                        trade.bars,
+                        trade.return_pct(),
+                        trade.r_multiple()
+                    );
+                    trades.push(trade);
                 }
             }
+            let summary = backtest::summarize(&trades);
+            eprintln!(
+                "{}: {} trades, {:.1}% winners, expectancy {:.2}% ({:.2}R), max drawdown {:.2}%",
+                screen.name,
+                summary.trades,
+                summary.win_rate * 100.0,
+                summary.expectancy * 100.0,
+                summary.avg_r,
+                summary.max_drawdown * 100.0
+            );
+        }
+        Command::TrendCandidates {
+            force,
+            ema_period,
+            stoch_k_len,
+            stoch_k_smoothing,
+            stoch_d_smoothing,
+            stoch_threshold,
+            loose,
+            adx_period,
+            bar_size,
+            adjusted,
+            as_of,
+            output,
+            sort,
+            top,
+            ticker_args,
+            liquidity,
+        } => {
+            let sym2quotes = load_quotes(
+                &db,
+                config.tickers(ticker_args.universe.as_deref()),
+                bar_size,
+                adjusted,
+                as_of,
+            )?;
+            let params = candidates::Params {
+                ema_period,
+                loose,
+                slow_stoch: Indicator::SlowStoch(stoch_k_len, stoch_k_smoothing, stoch_d_smoothing),
+                adxr: Indicator::Adx(adx_period, 1),
+                rsi: Indicator::Rsi(2),
+                filter: liquidity.or(&config.liquidity).condition(),
+            };
+            let mut candidates: Vec<TrendCandidate> = sym2quotes
+                .iter()
+                .filter_map(|(ticker, quotes)| TrendCandidate::evaluate(ticker, quotes, &params))
+                .filter(|candidate| force || candidate.is_setup(stoch_threshold))
+                .collect();
+            candidates::rank(&mut candidates, sort, top);
+            let records: Vec<Record> = candidates.iter().map(|c| c.to_record()).collect();
+            let columns = TrendCandidate::columns();
+            output::write(&mut io::stdout().lock(), output, &columns, &records)?;
         }
     }
     Ok(())
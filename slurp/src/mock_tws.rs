@@ -0,0 +1,490 @@
+//! Local stand-in for TWS/IB Gateway that speaks enough of the wire protocol for `EClient` to
+//! connect and make historical data, contract details and market data requests. Each symbol gets
+//! a script of canned replies (bars, ticks, error codes, delays) that is replayed in order, one
+//! entry per request for that symbol.
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use ibtwsapi::core::common::BarData;
+
+const SERVER_VERSION: i32 = 151;
+
+// Outgoing (server -> client) message ids
+const TICK_PRICE: i32 = 1;
+const TICK_SIZE: i32 = 2;
+const ERR_MSG: i32 = 4;
+const NEXT_VALID_ID: i32 = 9;
+const CONTRACT_DATA: i32 = 10;
+const MANAGED_ACCTS: i32 = 15;
+const HISTORICAL_DATA: i32 = 17;
+const CONTRACT_DATA_END: i32 = 52;
+
+// Incoming (client -> server) message ids
+const REQ_MKT_DATA: i32 = 1;
+const CANCEL_MKT_DATA: i32 = 2;
+const REQ_CONTRACT_DATA: i32 = 9;
+const REQ_HISTORICAL_DATA: i32 = 20;
+const CANCEL_REAL_TIME_BARS: i32 = 51;
+const START_API: i32 = 71;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bar {
+    pub date: String,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: i64,
+    pub wap: f64,
+    pub count: i32,
+}
+
+impl From<Bar> for BarData {
+    fn from(bar: Bar) -> Self {
+        BarData {
+            date: bar.date,
+            open: bar.open,
+            high: bar.high,
+            low: bar.low,
+            close: bar.close,
+            volume: bar.volume,
+            average: bar.wap,
+            bar_count: bar.count,
+        }
+    }
+}
+
+/// The parts of a `ContractDetails` we store
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContractInfo {
+    pub symbol: String,
+    pub con_id: i32,
+    pub primary_exchange: String,
+    pub long_name: String,
+    pub industry: String,
+    pub category: String,
+    pub subcategory: String,
+}
+
+/// One step of the answer to a request
+#[derive(Clone, Debug)]
+pub enum Reply {
+    /// A full `HistoricalData` message (bars followed by the end marker)
+    Bars(Vec<Bar>),
+    /// One `ContractData` message per contract followed by `ContractDataEnd`
+    Contracts(Vec<ContractInfo>),
+    /// An `ErrMsg` for the request's req_id, i.e. 162, 200 or 354
+    Error(i32, String),
+    /// A `TickPrice` of the given tick type (i.e. 4 for the last trade, 68 for a delayed one)
+    Price(i32, f64),
+    /// A `TickSize` of the given tick type (i.e. 8 for the day's volume)
+    Size(i32, i32),
+    /// Wait before sending the next step
+    Delay(Duration),
+    /// Any other message, sent as-is (message id first)
+    Raw(Vec<String>),
+}
+
+/// The fields we care about from a `reqHistoricalData` call
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoricalRequest {
+    pub req_id: i32,
+    pub con_id: i32,
+    pub symbol: String,
+    pub primary_exchange: String,
+    pub end_date_time: String,
+    pub bar_size: String,
+    pub duration: String,
+    pub what_to_show: String,
+}
+
+/// The fields we care about from a `reqContractDetails` call
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContractRequest {
+    pub req_id: i32,
+    pub symbol: String,
+    pub primary_exchange: String,
+}
+
+type Script = HashMap<String, VecDeque<Vec<Reply>>>;
+
+pub struct MockTws {
+    pub port: u32,
+    script: Arc<Mutex<Script>>,
+    requests: Arc<Mutex<Vec<HistoricalRequest>>>,
+    contract_requests: Arc<Mutex<Vec<ContractRequest>>>,
+    cancellations: Arc<Mutex<Vec<i32>>>,
+}
+
+/// Read a recorded bar stream: one bar per line as IB sends them, tab separated:
+/// date, open, high, low, close, volume, wap, count. Lines starting with '#' are ignored.
+pub fn load_bars<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<Bar>> {
+    let contents = fs::read_to_string(path)?;
+    let mut bars = vec![];
+    for line in contents.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let f: Vec<&str> = line.split('\t').collect();
+        if f.len() != 8 {
+            anyhow::bail!("expected 8 fields: {}", line);
+        }
+        bars.push(Bar {
+            date: f[0].to_string(),
+            open: f[1].parse()?,
+            high: f[2].parse()?,
+            low: f[3].parse()?,
+            close: f[4].parse()?,
+            volume: f[5].parse()?,
+            wap: f[6].parse()?,
+            count: f[7].parse()?,
+        });
+    }
+    Ok(bars)
+}
+
+/// Recorded bars shipped under `testdata/`
+pub fn recorded(name: &str) -> Vec<Bar> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata")
+        .join(format!("{}.tsv", name));
+    load_bars(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+}
+
+fn encode(fields: &[String]) -> Vec<u8> {
+    let mut payload = String::new();
+    for field in fields {
+        payload.push_str(field);
+        payload.push('\0');
+    }
+    let mut msg = (payload.len() as i32).to_be_bytes().to_vec();
+    msg.extend_from_slice(payload.as_bytes());
+    msg
+}
+
+fn read_msg(stream: &mut TcpStream) -> io::Result<Vec<String>> {
+    let mut size = [0u8; 4];
+    stream.read_exact(&mut size)?;
+    let mut payload = vec![0u8; i32::from_be_bytes(size) as usize];
+    stream.read_exact(&mut payload)?;
+    let text = String::from_utf8_lossy(&payload).to_string();
+    let mut fields: Vec<String> = text.split('\0').map(String::from).collect();
+    fields.pop(); // every field is NUL terminated
+    Ok(fields)
+}
+
+fn send(writer: &Mutex<TcpStream>, fields: Vec<String>) {
+    // A single write per message so the client's reader never sees half of one
+    let mut stream = writer.lock().unwrap();
+    stream.write_all(&encode(&fields)).ok();
+}
+
+fn error_fields(req_id: i32, code: i32, msg: &str) -> Vec<String> {
+    vec![
+        ERR_MSG.to_string(),
+        "2".to_string(),
+        req_id.to_string(),
+        code.to_string(),
+        msg.to_string(),
+    ]
+}
+
+fn bars_fields(req_id: i32, bars: &[Bar]) -> Vec<String> {
+    let start = bars.first().map(|b| b.date.clone()).unwrap_or_default();
+    let end = bars.last().map(|b| b.date.clone()).unwrap_or_default();
+    let mut fields = vec![
+        HISTORICAL_DATA.to_string(),
+        req_id.to_string(),
+        start,
+        end,
+        bars.len().to_string(),
+    ];
+    for bar in bars {
+        fields.extend([
+            bar.date.clone(),
+            bar.open.to_string(),
+            bar.high.to_string(),
+            bar.low.to_string(),
+            bar.close.to_string(),
+            bar.volume.to_string(),
+            bar.wap.to_string(),
+            bar.count.to_string(),
+        ]);
+    }
+    fields
+}
+
+fn contract_fields(req_id: i32, c: &ContractInfo) -> Vec<String> {
+    let fields = [
+        &CONTRACT_DATA.to_string(),
+        "8", // version
+        &req_id.to_string(),
+        &c.symbol,
+        "STK",
+        "", // last trade date
+        "0",
+        "", // right
+        "SMART",
+        "USD",
+        &c.symbol, // local symbol
+        "NMS",     // market name
+        &c.symbol, // trading class
+        &c.con_id.to_string(),
+        "0.01", // min tick
+        "100",  // md size multiplier
+        "",     // multiplier
+        "LMT,MKT",
+        &format!("SMART,{}", c.primary_exchange),
+        "1", // price magnifier
+        "0", // under con_id
+        &c.long_name,
+        &c.primary_exchange,
+        "", // contract month
+        &c.industry,
+        &c.category,
+        &c.subcategory,
+        "US/Eastern",
+        "", // trading hours
+        "", // liquid hours
+        "", // ev rule
+        "0",
+        "0", // sec id list
+        "1", // agg group
+        "",  // under symbol
+        "",  // under sec type
+        "",  // market rules
+        "",  // real expiration date
+    ];
+    fields.iter().map(|f| f.to_string()).collect()
+}
+
+fn parse_contract_request(fields: &[String]) -> Option<ContractRequest> {
+    // id, version, req_id, con_id, symbol, sec_type, last_trade_date, strike, right, multiplier,
+    // exchange, primary_exchange, ...
+    if fields.len() < 12 {
+        return None;
+    }
+    Some(ContractRequest {
+        req_id: fields[2].parse().ok()?,
+        symbol: fields[4].clone(),
+        primary_exchange: fields[11].clone(),
+    })
+}
+
+fn parse_historical_request(fields: &[String]) -> Option<HistoricalRequest> {
+    // id, req_id, con_id, symbol, sec_type, last_trade_date, strike, right, multiplier, exchange,
+    // primary_exchange, currency, local_symbol, trading_class, include_expired, end_date_time,
+    // bar_size, duration, use_rth, what_to_show, ...
+    if fields.len() < 20 {
+        return None;
+    }
+    Some(HistoricalRequest {
+        req_id: fields[1].parse().ok()?,
+        con_id: fields[2].parse().ok()?,
+        symbol: fields[3].clone(),
+        primary_exchange: fields[10].clone(),
+        end_date_time: fields[15].clone(),
+        bar_size: fields[16].clone(),
+        duration: fields[17].clone(),
+        what_to_show: fields[19].clone(),
+    })
+}
+
+fn replay(writer: Arc<Mutex<TcpStream>>, req_id: i32, replies: Vec<Reply>) {
+    thread::spawn(move || {
+        for reply in replies {
+            match reply {
+                Reply::Delay(d) => thread::sleep(d),
+                Reply::Error(code, msg) => send(&writer, error_fields(req_id, code, &msg)),
+                Reply::Bars(bars) => send(&writer, bars_fields(req_id, &bars)),
+                Reply::Raw(fields) => send(&writer, fields),
+                Reply::Price(tick_type, price) => send(
+                    &writer,
+                    vec![
+                        TICK_PRICE.to_string(),
+                        "6".to_string(),
+                        req_id.to_string(),
+                        tick_type.to_string(),
+                        price.to_string(),
+                        "0".to_string(), // size
+                        "0".to_string(), // attributes
+                    ],
+                ),
+                Reply::Size(tick_type, size) => send(
+                    &writer,
+                    vec![
+                        TICK_SIZE.to_string(),
+                        "6".to_string(),
+                        req_id.to_string(),
+                        tick_type.to_string(),
+                        size.to_string(),
+                    ],
+                ),
+                Reply::Contracts(contracts) => {
+                    for c in contracts.iter() {
+                        send(&writer, contract_fields(req_id, c));
+                    }
+                    send(
+                        &writer,
+                        vec![
+                            CONTRACT_DATA_END.to_string(),
+                            "1".to_string(),
+                            req_id.to_string(),
+                        ],
+                    );
+                }
+            }
+        }
+    });
+}
+
+fn next_replies(script: &Mutex<Script>, symbol: &str) -> Vec<Reply> {
+    script
+        .lock()
+        .unwrap()
+        .get_mut(symbol)
+        .and_then(|queue| queue.pop_front())
+        .unwrap_or_else(|| {
+            vec![Reply::Error(
+                200,
+                "No security definition has been found for the request".to_string(),
+            )]
+        })
+}
+
+fn serve(
+    mut stream: TcpStream,
+    script: Arc<Mutex<Script>>,
+    requests: Arc<Mutex<Vec<HistoricalRequest>>>,
+    contract_requests: Arc<Mutex<Vec<ContractRequest>>>,
+    cancellations: Arc<Mutex<Vec<i32>>>,
+) -> io::Result<()> {
+    // handshake: "API\0" followed by the supported version range
+    let mut prefix = [0u8; 4];
+    stream.read_exact(&mut prefix)?;
+    read_msg(&mut stream)?;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    send(
+        &writer,
+        vec![
+            SERVER_VERSION.to_string(),
+            "20220624 12:00:00 EST".to_string(),
+        ],
+    );
+
+    loop {
+        let fields = read_msg(&mut stream)?;
+        let msg_id: i32 = fields.first().and_then(|f| f.parse().ok()).unwrap_or(-1);
+        match msg_id {
+            START_API => {
+                send(
+                    &writer,
+                    vec![NEXT_VALID_ID.to_string(), "1".to_string(), "1".to_string()],
+                );
+                send(
+                    &writer,
+                    vec![
+                        MANAGED_ACCTS.to_string(),
+                        "1".to_string(),
+                        "DU1234567".to_string(),
+                    ],
+                );
+            }
+            REQ_HISTORICAL_DATA => {
+                let req = match parse_historical_request(&fields) {
+                    Some(req) => req,
+                    None => continue,
+                };
+                let replies = next_replies(&script, &req.symbol);
+                let req_id = req.req_id;
+                requests.lock().unwrap().push(req);
+                replay(writer.clone(), req_id, replies);
+            }
+            REQ_CONTRACT_DATA => {
+                let req = match parse_contract_request(&fields) {
+                    Some(req) => req,
+                    None => continue,
+                };
+                let replies = next_replies(&script, &req.symbol);
+                let req_id = req.req_id;
+                contract_requests.lock().unwrap().push(req);
+                replay(writer.clone(), req_id, replies);
+            }
+            // laid out like a contract details request up to the primary exchange
+            REQ_MKT_DATA => {
+                let req = match parse_contract_request(&fields) {
+                    Some(req) => req,
+                    None => continue,
+                };
+                let replies = next_replies(&script, &req.symbol);
+                replay(writer.clone(), req.req_id, replies);
+            }
+            // msg id, version, req_id
+            CANCEL_MKT_DATA | CANCEL_REAL_TIME_BARS => {
+                if let Some(req_id) = fields.get(2).and_then(|f| f.parse().ok()) {
+                    cancellations.lock().unwrap().push(req_id);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl MockTws {
+    /// Listen on an ephemeral localhost port and serve a single client connection
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let script: Arc<Mutex<Script>> = Arc::new(Mutex::new(HashMap::new()));
+        let requests = Arc::new(Mutex::new(vec![]));
+        let contract_requests = Arc::new(Mutex::new(vec![]));
+        let cancellations = Arc::new(Mutex::new(vec![]));
+        let (s, r, c) = (script.clone(), requests.clone(), contract_requests.clone());
+        let x = cancellations.clone();
+        thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                serve(stream, s, r, c, x).ok();
+            }
+        });
+        MockTws {
+            port,
+            script,
+            requests,
+            contract_requests,
+            cancellations,
+        }
+    }
+
+    /// Queue the replies for the next request (historical, contract or market data) made for
+    /// `symbol`
+    pub fn on(&self, symbol: &str, replies: Vec<Reply>) -> &Self {
+        self.script
+            .lock()
+            .unwrap()
+            .entry(symbol.to_string())
+            .or_default()
+            .push_back(replies);
+        self
+    }
+
+    /// Every historical data request received so far, in order
+    pub fn requests(&self) -> Vec<HistoricalRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Every contract details request received so far, in order
+    pub fn contract_requests(&self) -> Vec<ContractRequest> {
+        self.contract_requests.lock().unwrap().clone()
+    }
+
+    /// The req_ids of every market data and real-time bars subscription cancelled so far
+    pub fn cancellations(&self) -> Vec<i32> {
+        self.cancellations.lock().unwrap().clone()
+    }
+}
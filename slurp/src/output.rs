@@ -0,0 +1,223 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+
+/// How screener results are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tsv,
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+    /// `###Section,AAPL,MSFT` lines, importable as a TradingView watchlist
+    TradingView,
+    /// `DES,AAPL,STK,SMART` lines, importable as a TWS watchlist
+    Ibkr,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "tsv" => Ok(Format::Tsv),
+            "csv" => Ok(Format::Csv),
+            "jsonl" | "json" => Ok(Format::Jsonl),
+            "tradingview" | "tv" => Ok(Format::TradingView),
+            "ibkr" | "ib" | "tws" => Ok(Format::Ibkr),
+            _ => Err(anyhow::anyhow!(
+                "unknown output format '{}' (tsv|csv|jsonl|tradingview|ibkr)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Bool(bool),
+    Text(String),
+    /// `None` for indicators that haven't warmed up
+    Number(Option<f64>),
+}
+
+impl Field {
+    fn to_text(&self) -> String {
+        match self {
+            Field::Bool(b) => b.to_string(),
+            Field::Text(s) => s.clone(),
+            Field::Number(n) => n.map(|n| n.to_string()).unwrap_or_default(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            Field::Bool(b) => Value::from(*b),
+            Field::Text(s) => Value::from(s.as_str()),
+            Field::Number(n) => n.map(Value::from).unwrap_or(Value::Null),
+        }
+    }
+}
+
+/// One ticker that passed a screen
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub ticker: String,
+    /// Which watchlist section the ticker is listed under
+    pub section: String,
+    pub fields: Vec<(String, Field)>,
+}
+
+fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Write `records` as `format`. The tsv/csv header is `ticker` and `columns`, the names of the
+/// records' fields in order, so it's there even when nothing passed.
+pub fn write<W: Write>(
+    out: &mut W,
+    format: Format,
+    columns: &[String],
+    records: &[Record],
+) -> anyhow::Result<()> {
+    write_rows(out, format, columns, records, true)
+}
+
+/// `write`, leaving out the tsv/csv header unless `header`, i.e. for the later batches of a stream
+pub fn write_rows<W: Write>(
+    out: &mut W,
+    format: Format,
+    columns: &[String],
+    records: &[Record],
+    header: bool,
+) -> anyhow::Result<()> {
+    match format {
+        Format::Tsv | Format::Csv => {
+            let (sep, escape): (&str, fn(&str) -> String) = match format {
+                Format::Csv => (",", csv_escape),
+                _ => ("\t", str::to_string),
+            };
+            if header {
+                let mut names = vec![escape("ticker")];
+                names.extend(columns.iter().map(|c| escape(c)));
+                writeln!(out, "{}", names.join(sep))?;
+            }
+            for record in records {
+                let mut line = vec![escape(&record.ticker)];
+                line.extend(record.fields.iter().map(|(_, f)| escape(&f.to_text())));
+                writeln!(out, "{}", line.join(sep))?;
+            }
+        }
+        Format::Jsonl => {
+            for record in records {
+                let mut object = Map::new();
+                object.insert("ticker".to_string(), Value::from(record.ticker.as_str()));
+                for (name, field) in record.fields.iter() {
+                    object.insert(name.clone(), field.to_json());
+                }
+                writeln!(out, "{}", Value::Object(object))?;
+            }
+        }
+        Format::TradingView => {
+            for section in sections(records) {
+                let tickers: Vec<String> = records
+                    .iter()
+                    .filter(|r| r.section == section)
+                    // TradingView spells share classes with a dot, i.e. BRK.B
+                    .map(|r| r.ticker.replace(' ', "."))
+                    .collect();
+                writeln!(out, "###{},{}", section, tickers.join(","))?;
+            }
+        }
+        Format::Ibkr => {
+            for record in records {
+                writeln!(out, "DES,{},STK,SMART,,,,", record.ticker)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Distinct sections, in the order they first appear
+fn sections(records: &[Record]) -> Vec<String> {
+    let mut sections: Vec<String> = vec![];
+    for record in records {
+        if !sections.contains(&record.section) {
+            sections.push(record.section.clone());
+        }
+    }
+    sections
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn columns() -> Vec<String> {
+        ["loose", "direction", "rsi"].map(String::from).to_vec()
+    }
+
+    fn records() -> Vec<Record> {
+        let record = |ticker: &str, section: &str, loose: bool, rsi: Option<f64>| Record {
+            ticker: ticker.to_string(),
+            section: section.to_string(),
+            fields: vec![
+                ("loose".to_string(), Field::Bool(loose)),
+                ("direction".to_string(), Field::Text("bull".to_string())),
+                ("rsi".to_string(), Field::Number(rsi)),
+            ],
+        };
+        vec![
+            record("AAPL", "Stocks", false, Some(12.5)),
+            record("BRK B", "Loose", true, None),
+            record("MSFT", "Stocks", false, Some(3.0)),
+        ]
+    }
+
+    fn written(format: Format) -> String {
+        let mut out = vec![];
+        write(&mut out, format, &columns(), &records()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(
+            written(Format::Tsv),
+            "ticker\tloose\tdirection\trsi\n\
+             AAPL\tfalse\tbull\t12.5\n\
+             BRK B\ttrue\tbull\t\n\
+             MSFT\tfalse\tbull\t3\n"
+        );
+        assert!(
+            written(Format::Csv).starts_with("ticker,loose,direction,rsi\nAAPL,false,bull,12.5\n")
+        );
+        assert_eq!(
+            written(Format::Jsonl).lines().nth(1).unwrap(),
+            r#"{"direction":"bull","loose":true,"rsi":null,"ticker":"BRK B"}"#
+        );
+        assert_eq!(
+            written(Format::TradingView),
+            "###Stocks,AAPL,MSFT\n###Loose,BRK.B\n"
+        );
+        assert_eq!(
+            written(Format::Ibkr).lines().next().unwrap(),
+            "DES,AAPL,STK,SMART,,,,"
+        );
+        let mut out = vec![];
+        write_rows(&mut out, Format::Tsv, &columns(), &records()[..1], false).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "AAPL\tfalse\tbull\t12.5\n");
+        // nothing passed, but the header still says what would have
+        let mut out = vec![];
+        write(&mut out, Format::Csv, &columns(), &[]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "ticker,loose,direction,rsi\n"
+        );
+        assert_eq!(csv_escape("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+}
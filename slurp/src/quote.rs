@@ -1,9 +1,12 @@
 use anyhow::Context;
 use chrono::format::{self, strftime::StrftimeItems, Parsed};
 use chrono::prelude::*;
+use chrono_tz::{America::New_York, Tz};
 use ibtwsapi::core::common::BarData;
 
-#[derive(Debug, Default)]
+use crate::calendar;
+
+#[derive(Debug, Default, Clone)]
 pub struct Quote {
     pub timestamp: i64,
     pub open: f64,
@@ -12,29 +15,72 @@ pub struct Quote {
     pub low: f64,
     pub avg: f64, // weighted avg price
     pub volume: i64,
-    pub count: i32, // number of trades during the bar's timespan (day)
+    pub count: i32,    // number of trades during the bar's timespan (day)
+    pub adjclose: f64, // close adjusted for dividends (and any splits since)
 }
 
-fn to_timestamp(daily: &str) -> anyhow::Result<i64> {
-    // "20220623" => 1654781400
-    let mut p = Parsed::default();
-    format::parse(&mut p, daily, StrftimeItems::new("%Y%m%d"))
-        .with_context(|| format!("parsing {}", daily))?;
-    p.hour_mod_12 = Some(4); // 4:00
-    p.hour_div_12 = Some(1); // PM
-    p.minute = Some(0);
-    p.second = Some(0);
+impl Quote {
+    /// The candle scaled onto the adjusted close series
+    pub fn adjusted(&self) -> Quote {
+        let factor = if self.close > 0.0 && self.adjclose > 0.0 {
+            self.adjclose / self.close
+        } else {
+            1.0
+        };
+        Quote {
+            timestamp: self.timestamp,
+            open: self.open * factor,
+            close: self.adjclose,
+            high: self.high * factor,
+            low: self.low * factor,
+            avg: self.avg * factor,
+            volume: self.volume,
+            count: self.count,
+            adjclose: self.adjclose,
+        }
+    }
+}
 
-    // Convert parsed information into a DateTime in the EDT timezone
-    let edt_tz = FixedOffset::west(4 * 3600);
-    let dt = p.to_datetime_with_timezone(&edt_tz)?;
-    let utc_dt = dt.with_timezone(&Utc);
+/// Parse a bar's date as IB sends it:
+///   "20220623"                        => daily candle, keyed on that day's close
+///   "1656077400"                      => epoch seconds (formatDate=2)
+///   "20220624  09:30:00"              => bar start in the TWS login timezone (New York)
+///   "20220624 09:30:00 US/Eastern"    => bar start in the given timezone
+fn to_timestamp(date: &str) -> anyhow::Result<i64> {
+    let date = date.trim();
+    if date.len() == 8 {
+        // "20220623" => 1654781400
+        let mut p = Parsed::default();
+        format::parse(&mut p, date, StrftimeItems::new("%Y%m%d"))
+            .with_context(|| format!("parsing {}", date))?;
+        return Ok(calendar::close_timestamp(p.to_naive_date()?));
+    }
+    if date.chars().all(|c| c.is_ascii_digit()) {
+        return date.parse().with_context(|| format!("parsing {}", date));
+    }
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    if parts.len() < 2 || parts.len() > 3 {
+        anyhow::bail!("unexpected bar date '{}'", date);
+    }
+    let naive =
+        NaiveDateTime::parse_from_str(&format!("{} {}", parts[0], parts[1]), "%Y%m%d %H:%M:%S")
+            .with_context(|| format!("parsing {}", date))?;
+    let tz: Tz = match parts.get(2) {
+        Some(name) => name
+            .parse()
+            .map_err(|e| anyhow::anyhow!("parsing {}: {}", date, e))?,
+        None => New_York,
+    };
+    let dt = tz
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| anyhow::anyhow!("{} doesn't exist in {}", date, tz))?;
+    Ok(dt.timestamp())
+}
 
-    // let naive_date = NaiveDate::parse_from_str(daily, "%Y%m%d")?;
-    // let naive_dt = naive_date.and_hms(4, 0, 0); // 4PM EST
-    // let offset = chrono::FixedOffset::west(5 * 3600); // EST TZ
-    // let ts: DateTime<Utc> = chrono::DateTime::from_utc(naive_dt, TimeZone::from_offset(&offset));
-    Ok(utc_dt.timestamp())
+/// Snap any timestamp during a trading day to that day's 4PM close timestamp
+pub fn daily_close_timestamp(timestamp: i64) -> i64 {
+    calendar::close_timestamp(calendar::ny_date(timestamp))
 }
 
 impl TryFrom<BarData> for Quote {
@@ -50,6 +96,7 @@ impl TryFrom<BarData> for Quote {
             volume: bar.volume,
             avg: bar.average,
             count: bar.bar_count,
+            adjclose: bar.close,
         })
     }
 }
@@ -57,14 +104,38 @@ impl TryFrom<BarData> for Quote {
 impl From<yahoo_finance_api::Quote> for Quote {
     fn from(yq: yahoo_finance_api::Quote) -> Self {
         Quote {
-            timestamp: yq.timestamp as i64,
+            timestamp: daily_close_timestamp(yq.timestamp as i64),
             high: yq.high,
             low: yq.low,
             open: yq.open,
-            close: yq.adjclose, // accounts for splits AND dividends!
+            close: yq.close,
             volume: yq.volume as i64,
             avg: -1.0,
             count: -1,
+            adjclose: yq.adjclose, // accounts for splits AND dividends!
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_timestamp() {
+        // daily => 4PM EDT, or EST in the winter
+        assert_eq!(to_timestamp("20220623").unwrap(), 1656014400);
+        assert_eq!(to_timestamp("20221201").unwrap(), 1669928400);
+        assert_eq!(to_timestamp("1656077400").unwrap(), 1656077400);
+        // 9:30 EDT
+        assert_eq!(to_timestamp("20220624  09:30:00").unwrap(), 1656077400);
+        assert_eq!(
+            to_timestamp("20220624 09:30:00 US/Eastern").unwrap(),
+            1656077400
+        );
+        assert_eq!(to_timestamp("20220624 13:30:00 UTC").unwrap(), 1656077400);
+        // 9:30 EST
+        assert_eq!(to_timestamp("20221201  09:30:00").unwrap(), 1669905000);
+        assert!(to_timestamp("20220624 09:30").is_err());
+    }
+}
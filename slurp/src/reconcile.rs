@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use crate::calendar;
+use crate::quote::Quote;
+
+/// How an incremental update lined up with the candles we already had
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// Every candle we had cached in the update's window has the same close. `last` is the
+    /// timestamp of the newest of them
+    Verified { overlap: usize, last: i64 },
+    /// A cached close changed, usually because of a split since we fetched it
+    Mismatch {
+        timestamp: i64,
+        cached: f64,
+        fresh: f64,
+    },
+    /// Nothing cached in the update's window to check it against
+    NoOverlap,
+}
+
+impl Outcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Verified { .. } => "verified",
+            Outcome::Mismatch { .. } => "mismatch",
+            Outcome::NoOverlap => "no_overlap",
+        }
+    }
+
+    pub fn detail(&self) -> String {
+        match self {
+            Outcome::Verified { overlap, .. } => format!("{} overlapping candles", overlap),
+            Outcome::Mismatch {
+                timestamp,
+                cached,
+                fresh,
+            } => format!(
+                "cached close {} != {} on {}",
+                cached,
+                fresh,
+                calendar::ny_date(*timestamp)
+            ),
+            Outcome::NoOverlap => "no cached candles in the update".to_string(),
+        }
+    }
+}
+
+/// Check `fresh` candles against the `cached` ones in the same window, by timestamp
+pub fn reconcile(cached: &[Quote], fresh: &[Quote]) -> Outcome {
+    let cached: HashMap<i64, f64> = cached.iter().map(|q| (q.timestamp, q.close)).collect();
+    let mut overlap = 0;
+    let mut last = None;
+    for quote in fresh {
+        if let Some(close) = cached.get(&quote.timestamp) {
+            if *close != quote.close {
+                return Outcome::Mismatch {
+                    timestamp: quote.timestamp,
+                    cached: *close,
+                    fresh: quote.close,
+                };
+            }
+            overlap += 1;
+            last = last.max(Some(quote.timestamp));
+        }
+    }
+    match last {
+        Some(last) => Outcome::Verified { overlap, last },
+        None => Outcome::NoOverlap,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn quote(timestamp: i64, close: f64) -> Quote {
+        Quote {
+            timestamp,
+            close,
+            ..Quote::default()
+        }
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let cached = [quote(1, 10.0), quote(2, 11.0), quote(4, 12.0)];
+        // 3 isn't cached (i.e. a gap in the table), but 2 and 4 still check out
+        let fresh = [
+            quote(2, 11.0),
+            quote(3, 11.5),
+            quote(4, 12.0),
+            quote(5, 13.0),
+        ];
+        assert_eq!(
+            reconcile(&cached, &fresh),
+            Outcome::Verified {
+                overlap: 2,
+                last: 4
+            }
+        );
+        let fresh = [quote(3, 11.5), quote(4, 6.0), quote(5, 6.5)];
+        assert_eq!(
+            reconcile(&cached, &fresh),
+            Outcome::Mismatch {
+                timestamp: 4,
+                cached: 12.0,
+                fresh: 6.0
+            }
+        );
+        assert_eq!(reconcile(&cached, &[quote(6, 13.0)]), Outcome::NoOverlap);
+        assert_eq!(reconcile(&[], &fresh), Outcome::NoOverlap);
+    }
+}
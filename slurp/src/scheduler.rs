@@ -0,0 +1,371 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::bars::BarSize;
+
+/// IB allows no more than 60 historical data requests in any 10 minute window
+const PACING_WINDOW: Duration = Duration::from_secs(10 * 60);
+const PACING_WINDOW_LIMIT: usize = 60;
+
+/// ...and no identical requests within 15 seconds of each other
+const IDENTICAL_REQUEST_GAP: Duration = Duration::from_secs(15);
+
+/// First pause after a pacing violation (error 162). Doubles on each consecutive violation.
+const PACING_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_PACING_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// First wait before retrying a transient failure. Doubles on each attempt.
+const RETRY_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_RETRIES: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    /// 2 years of daily candles
+    Full,
+    /// Only the candles since the last row we have
+    Incremental,
+    /// Contract details (conId, primary exchange, ...) rather than candles
+    Contract,
+    /// One chunk of intraday bars ending at `end` (epoch seconds)
+    Bars { bar_size: BarSize, end: i64 },
+    /// The dividend and split adjusted daily closes (ADJUSTED_LAST) of the last `days`
+    Adjusted { days: i64 },
+}
+
+impl RequestKind {
+    /// Subject to IB's historical data pacing rules
+    pub fn is_historical(&self) -> bool {
+        !matches!(self, RequestKind::Contract)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Request {
+    pub ticker: String,
+    pub kind: RequestKind,
+}
+
+impl Request {
+    pub fn full(ticker: String) -> Self {
+        Request {
+            ticker,
+            kind: RequestKind::Full,
+        }
+    }
+
+    pub fn incremental(ticker: String) -> Self {
+        Request {
+            ticker,
+            kind: RequestKind::Incremental,
+        }
+    }
+
+    pub fn bars(ticker: String, bar_size: BarSize, end: i64) -> Self {
+        Request {
+            ticker,
+            kind: RequestKind::Bars { bar_size, end },
+        }
+    }
+
+    pub fn adjusted(ticker: String, days: i64) -> Self {
+        Request {
+            ticker,
+            kind: RequestKind::Adjusted { days },
+        }
+    }
+
+    pub fn contract(ticker: String) -> Self {
+        Request {
+            ticker,
+            kind: RequestKind::Contract,
+        }
+    }
+}
+
+/// Decides when queued historical data requests may be sent: caps the number in flight and
+/// follows IB's pacing rules, backing off and re-queueing after pacing violations.
+pub struct Scheduler {
+    pub max_in_flight: usize,
+    pub window: Duration,
+    pub window_limit: usize,
+    pub identical_request_gap: Duration,
+    pub pacing_backoff: Duration,
+    pub retry_backoff: Duration,
+    pub max_retries: u32,
+    pending: VecDeque<Request>,
+    /// Retries waiting out their backoff
+    delayed: Vec<(Instant, Request)>,
+    retries: HashMap<Request, u32>,
+    in_flight: HashMap<i32, Request>,
+    sent: VecDeque<Instant>,
+    last_sent: HashMap<Request, Instant>,
+    paused_until: Option<Instant>,
+    violations: u32,
+}
+
+impl Scheduler {
+    pub fn new(max_in_flight: usize) -> Self {
+        Scheduler {
+            max_in_flight,
+            window: PACING_WINDOW,
+            window_limit: PACING_WINDOW_LIMIT,
+            identical_request_gap: IDENTICAL_REQUEST_GAP,
+            pacing_backoff: PACING_BACKOFF,
+            retry_backoff: RETRY_BACKOFF,
+            max_retries: MAX_RETRIES,
+            pending: VecDeque::new(),
+            delayed: vec![],
+            retries: HashMap::new(),
+            in_flight: HashMap::new(),
+            sent: VecDeque::new(),
+            last_sent: HashMap::new(),
+            paused_until: None,
+            violations: 0,
+        }
+    }
+
+    pub fn push(&mut self, req: Request) {
+        self.pending.push_back(req);
+    }
+
+    /// Queue ahead of everything else (i.e. a retry or an escalation to a full re-fetch)
+    pub fn push_front(&mut self, req: Request) {
+        self.pending.push_front(req);
+    }
+
+    /// Nothing queued and nothing waiting on a response
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty() && self.delayed.is_empty() && self.in_flight.is_empty()
+    }
+
+    /// Queued requests, including retries still waiting out their backoff
+    pub fn pending_len(&self) -> usize {
+        self.pending.len() + self.delayed.len()
+    }
+
+    pub fn in_flight(&self, req_id: i32) -> Option<&Request> {
+        self.in_flight.get(&req_id)
+    }
+
+    pub fn in_flight_len(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    fn recently_sent(&self, req: &Request, now: Instant) -> bool {
+        match self.last_sent.get(req) {
+            Some(t) => now.duration_since(*t) < self.identical_request_gap,
+            None => false,
+        }
+    }
+
+    /// The next request that may be sent right now, if any. The caller must report it with
+    /// `sent` (or drop it, i.e. an incremental request that turned out to be up to date).
+    pub fn next_ready(&mut self, now: Instant) -> Option<Request> {
+        let (due, delayed): (Vec<_>, Vec<_>) =
+            self.delayed.drain(..).partition(|(at, _)| *at <= now);
+        self.delayed = delayed;
+        for (_, req) in due.into_iter().rev() {
+            self.pending.push_front(req);
+        }
+        if self.in_flight.len() >= self.max_in_flight {
+            return None;
+        }
+        if let Some(until) = self.paused_until {
+            if now < until {
+                return None;
+            }
+            self.paused_until = None;
+        }
+        while let Some(t) = self.sent.front() {
+            if now.duration_since(*t) < self.window {
+                break;
+            }
+            self.sent.pop_front();
+        }
+        let window_full = self.sent.len() >= self.window_limit;
+        let blocked = |req: &Request| {
+            (req.kind.is_historical() && window_full) || self.recently_sent(req, now)
+        };
+        let idx = self.pending.iter().position(|req| !blocked(req))?;
+        self.pending.remove(idx)
+    }
+
+    pub fn sent(&mut self, req_id: i32, req: Request, now: Instant) {
+        if req.kind.is_historical() {
+            self.sent.push_back(now);
+            self.last_sent.insert(req.clone(), now);
+        }
+        self.in_flight.insert(req_id, req);
+    }
+
+    /// The request got its response (or a fatal error)
+    pub fn complete(&mut self, req_id: i32) -> Option<Request> {
+        let req = self.in_flight.remove(&req_id);
+        if let Some(ref req) = req {
+            self.violations = 0;
+            self.retries.remove(req);
+        }
+        req
+    }
+
+    /// The request failed with a transient error: re-queue it after a backoff. Returns false
+    /// (and drops the request) once it has been retried `max_retries` times.
+    pub fn retry(&mut self, req_id: i32, now: Instant) -> bool {
+        let req = match self.in_flight.remove(&req_id) {
+            Some(req) => req,
+            None => return false,
+        };
+        let attempts = self.retries.entry(req.clone()).or_insert(0);
+        if *attempts >= self.max_retries {
+            self.retries.remove(&req);
+            return false;
+        }
+        let backoff = self.retry_backoff * 2u32.pow((*attempts).min(16));
+        *attempts += 1;
+        eprintln!(
+            "retrying {} in {:?} (attempt {})",
+            req.ticker, backoff, attempts
+        );
+        self.delayed.push((now + backoff, req));
+        true
+    }
+
+    /// IB rejected the request for pacing: pause everything and put it back at the front
+    pub fn pacing_violation(&mut self, req_id: i32, now: Instant) -> Option<Request> {
+        let req = self.in_flight.remove(&req_id)?;
+        let backoff = std::cmp::min(
+            self.pacing_backoff * 2u32.pow(self.violations.min(16)),
+            MAX_PACING_BACKOFF,
+        );
+        eprintln!(
+            "pacing violation for {}, backing off {:?}",
+            req.ticker, backoff
+        );
+        self.paused_until = Some(now + backoff);
+        self.violations += 1;
+        self.pending.push_front(req.clone());
+        Some(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tickers(n: usize) -> Scheduler {
+        let mut scheduler = Scheduler::new(3);
+        for i in 0..n {
+            scheduler.push(Request::full(format!("T{}", i)));
+        }
+        scheduler
+    }
+
+    fn send_all(scheduler: &mut Scheduler, now: Instant, req_id: &mut i32) -> usize {
+        let mut count = 0;
+        while let Some(req) = scheduler.next_ready(now) {
+            *req_id += 1;
+            scheduler.sent(*req_id, req, now);
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn test_caps_in_flight() {
+        let now = Instant::now();
+        let mut req_id = 0;
+        let mut scheduler = tickers(5);
+        assert_eq!(send_all(&mut scheduler, now, &mut req_id), 3);
+        assert_eq!(scheduler.complete(1).unwrap().ticker, "T0");
+        assert_eq!(send_all(&mut scheduler, now, &mut req_id), 1);
+        assert_eq!(scheduler.pending_len(), 1);
+        assert!(!scheduler.is_idle());
+    }
+
+    #[test]
+    fn test_pacing_window() {
+        let start = Instant::now();
+        let mut req_id = 0;
+        let mut scheduler = tickers(70);
+        scheduler.max_in_flight = 100;
+        assert_eq!(send_all(&mut scheduler, start, &mut req_id), 60);
+        for id in 1..=60 {
+            scheduler.complete(id);
+        }
+        let later = start + Duration::from_secs(9 * 60);
+        assert_eq!(send_all(&mut scheduler, later, &mut req_id), 0);
+        let window_end = start + Duration::from_secs(10 * 60);
+        assert_eq!(send_all(&mut scheduler, window_end, &mut req_id), 10);
+    }
+
+    #[test]
+    fn test_contract_requests_skip_pacing_window() {
+        let now = Instant::now();
+        let mut req_id = 0;
+        let mut scheduler = tickers(61);
+        scheduler.max_in_flight = 100;
+        scheduler.push(Request::contract("T0".to_string()));
+        scheduler.push(Request::contract("T0".to_string()));
+        assert_eq!(send_all(&mut scheduler, now, &mut req_id), 62);
+        assert_eq!(scheduler.pending_len(), 1);
+    }
+
+    #[test]
+    fn test_identical_requests_wait() {
+        let now = Instant::now();
+        let mut req_id = 0;
+        let mut scheduler = Scheduler::new(10);
+        scheduler.push(Request::full("AAPL".to_string()));
+        scheduler.push(Request::full("AAPL".to_string()));
+        scheduler.push(Request::incremental("AAPL".to_string()));
+        assert_eq!(send_all(&mut scheduler, now, &mut req_id), 2);
+        assert_eq!(scheduler.pending_len(), 1);
+        let later = now + Duration::from_secs(15);
+        assert_eq!(send_all(&mut scheduler, later, &mut req_id), 1);
+    }
+
+    #[test]
+    fn test_pacing_violation_backs_off_and_requeues() {
+        let now = Instant::now();
+        let mut req_id = 0;
+        let mut scheduler = tickers(2);
+        scheduler.identical_request_gap = Duration::from_secs(0);
+        assert_eq!(send_all(&mut scheduler, now, &mut req_id), 2);
+        assert_eq!(scheduler.pacing_violation(1, now).unwrap().ticker, "T0");
+        assert_eq!(scheduler.in_flight_len(), 1);
+
+        let before = now + Duration::from_secs(59);
+        assert_eq!(send_all(&mut scheduler, before, &mut req_id), 0);
+        let after = now + Duration::from_secs(60);
+        assert_eq!(scheduler.next_ready(after).unwrap().ticker, "T0");
+        scheduler.sent(3, Request::full("T0".to_string()), after);
+
+        // consecutive violations double the pause
+        scheduler.pacing_violation(3, after);
+        let before = after + Duration::from_secs(119);
+        assert_eq!(send_all(&mut scheduler, before, &mut req_id), 0);
+        let after = after + Duration::from_secs(120);
+        assert_eq!(send_all(&mut scheduler, after, &mut req_id), 1);
+    }
+
+    #[test]
+    fn test_retry_backs_off_then_gives_up() {
+        let now = Instant::now();
+        let mut req_id = 0;
+        let mut scheduler = tickers(1);
+        scheduler.identical_request_gap = Duration::from_secs(0);
+        scheduler.max_retries = 2;
+        assert_eq!(send_all(&mut scheduler, now, &mut req_id), 1);
+        assert!(scheduler.retry(1, now));
+        assert!(!scheduler.is_idle());
+        assert_eq!(send_all(&mut scheduler, now, &mut req_id), 0);
+
+        let later = now + Duration::from_secs(5);
+        assert_eq!(send_all(&mut scheduler, later, &mut req_id), 1);
+        assert!(scheduler.retry(2, later));
+        let later = later + Duration::from_secs(10);
+        assert_eq!(send_all(&mut scheduler, later, &mut req_id), 1);
+        assert!(!scheduler.retry(3, later));
+        assert!(scheduler.is_idle());
+    }
+}
@@ -0,0 +1,624 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+
+use crate::db::QuoteRow;
+use crate::indicators::{Indicator, Indicators};
+
+/// A setup to look for, i.e. screens/bounce.toml:
+///
+/// ```toml
+/// name = "Bounce 2.0"
+/// columns = ["slow_stoch(8,3,3)", "adx(13,1)"]
+///
+/// [[signal]]
+/// name = "long"
+/// when = "ema(8) > ema(21) for 42 bars and slow_stoch(8,3,3) <= 40"
+/// ```
+///
+/// A ticker passes with the first signal whose condition holds on its last candle, as long as the
+/// optional `filter` (i.e. `"close >= 5 and dollar_volume(20) >= 20000000"`) holds there too.
+#[derive(Debug, Deserialize)]
+pub struct ScreenFile {
+    pub name: Option<String>,
+    /// Must hold for any signal to count, i.e. a liquidity floor
+    pub filter: Option<String>,
+    /// Indicators printed alongside each ticker that passes
+    #[serde(default)]
+    pub columns: Vec<String>,
+    #[serde(rename = "signal", default)]
+    pub signals: Vec<SignalFile>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignalFile {
+    pub name: String,
+    #[serde(default)]
+    pub side: Side,
+    pub when: String,
+}
+
+/// Which way a signal trades (for `backtest`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    #[default]
+    Long,
+    Short,
+}
+
+impl Side {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Side::Long => "long",
+            Side::Short => "short",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Signal {
+    pub name: String,
+    pub side: Side,
+    pub when: Condition,
+}
+
+#[derive(Debug)]
+pub struct Screen {
+    pub name: String,
+    pub filter: Option<Condition>,
+    pub columns: Vec<Indicator>,
+    pub signals: Vec<Signal>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Indicator(Indicator),
+    Number(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cmp {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Compare(Operand, Cmp, Operand),
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+    /// Held on each of the last `n` candles
+    For(Box<Condition>, usize),
+}
+
+impl Operand {
+    fn value(&self, indicators: &Indicators, idx: usize) -> Option<f64> {
+        match self {
+            Operand::Indicator(i) => indicators.value(i, idx),
+            Operand::Number(n) => Some(*n),
+        }
+    }
+}
+
+impl Cmp {
+    fn holds(&self, a: f64, b: f64) -> bool {
+        match self {
+            Cmp::Gt => a > b,
+            Cmp::Ge => a >= b,
+            Cmp::Lt => a < b,
+            Cmp::Le => a <= b,
+            Cmp::Eq => a == b,
+            Cmp::Ne => a != b,
+        }
+    }
+}
+
+impl Condition {
+    /// Whether the condition holds at candle `idx`. Indicators that haven't warmed up by then
+    /// never satisfy a comparison.
+    pub fn eval(&self, indicators: &Indicators, idx: usize) -> bool {
+        match self {
+            Condition::Compare(a, cmp, b) => {
+                match (a.value(indicators, idx), b.value(indicators, idx)) {
+                    (Some(a), Some(b)) => cmp.holds(a, b),
+                    _ => false,
+                }
+            }
+            Condition::And(conditions) => conditions.iter().all(|c| c.eval(indicators, idx)),
+            Condition::Or(conditions) => conditions.iter().any(|c| c.eval(indicators, idx)),
+            Condition::For(condition, n) => {
+                *n > 0
+                    && idx + 1 >= *n
+                    && (idx + 1 - n..=idx).all(|i| condition.eval(indicators, i))
+            }
+        }
+    }
+
+    /// Every indicator the condition refers to
+    pub fn indicators(&self, out: &mut Vec<Indicator>) {
+        match self {
+            Condition::Compare(a, _, b) => {
+                for operand in [a, b] {
+                    if let Operand::Indicator(i) = operand {
+                        if !out.contains(i) {
+                            out.push(*i);
+                        }
+                    }
+                }
+            }
+            Condition::And(conditions) | Condition::Or(conditions) => {
+                conditions.iter().for_each(|c| c.indicators(out))
+            }
+            Condition::For(condition, _) => condition.indicators(out),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Indicator(i) => write!(f, "{}", i),
+            Operand::Number(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+impl fmt::Display for Cmp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Cmp::Gt => ">",
+            Cmp::Ge => ">=",
+            Cmp::Lt => "<",
+            Cmp::Le => "<=",
+            Cmp::Eq => "==",
+            Cmp::Ne => "!=",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let join = |f: &mut fmt::Formatter, conditions: &[Condition], sep: &str| {
+            for (i, c) in conditions.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " {} ", sep)?;
+                }
+                write!(f, "({})", c)?;
+            }
+            Ok(())
+        };
+        match self {
+            Condition::Compare(a, cmp, b) => write!(f, "{} {} {}", a, cmp, b),
+            Condition::And(conditions) => join(f, conditions, "and"),
+            Condition::Or(conditions) => join(f, conditions, "or"),
+            Condition::For(condition, n) => write!(f, "({}) for {} bars", condition, n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Indicator(Indicator),
+    Number(f64),
+    Cmp(Cmp),
+    LParen,
+    RParen,
+    And,
+    Or,
+    For,
+    Bars,
+}
+
+fn tokenize(s: &str) -> anyhow::Result<Vec<Token>> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' {
+            tokens.push(Token::LParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::RParen);
+            i += 1;
+        } else if "<>=!".contains(c) {
+            let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            let (cmp, len) = match two.as_str() {
+                ">=" => (Cmp::Ge, 2),
+                "<=" => (Cmp::Le, 2),
+                "==" => (Cmp::Eq, 2),
+                "!=" => (Cmp::Ne, 2),
+                _ if c == '>' => (Cmp::Gt, 1),
+                _ if c == '<' => (Cmp::Lt, 1),
+                _ => anyhow::bail!("unexpected '{}' in '{}'", two, s),
+            };
+            tokens.push(Token::Cmp(cmp));
+            i += len;
+        } else if c.is_ascii_digit() || c == '.' || c == '-' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            let number = number
+                .parse()
+                .with_context(|| format!("bad number '{}' in '{}'", number, s))?;
+            tokens.push(Token::Number(number));
+        } else if c.is_ascii_alphabetic() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect::<String>().to_lowercase();
+            match word.as_str() {
+                "and" => tokens.push(Token::And),
+                "or" => tokens.push(Token::Or),
+                "for" => tokens.push(Token::For),
+                "bar" | "bars" => tokens.push(Token::Bars),
+                _ => {
+                    // an indicator's periods are part of its name, i.e. "ema(8)"
+                    let mut name = word;
+                    if i < chars.len() && chars[i] == '(' {
+                        let close = chars[i..]
+                            .iter()
+                            .position(|c| *c == ')')
+                            .ok_or_else(|| anyhow::anyhow!("unclosed '(' in '{}'", s))?;
+                        name.extend(&chars[i..=i + close]);
+                        i += close + 1;
+                    }
+                    tokens.push(Token::Indicator(name.parse()?));
+                }
+            }
+        } else {
+            anyhow::bail!("unexpected '{}' in '{}'", c, s);
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over:
+///   or      := and ("or" and)*
+///   and     := term ("and" term)*
+///   term    := ("(" or ")" | operand cmp operand) ["for" N "bars"]
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> anyhow::Result<Token> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("unexpected end of '{}'", self.source))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn or(&mut self) -> anyhow::Result<Condition> {
+        let mut conditions = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            conditions.push(self.and()?);
+        }
+        Ok(if conditions.len() == 1 {
+            conditions.remove(0)
+        } else {
+            Condition::Or(conditions)
+        })
+    }
+
+    fn and(&mut self) -> anyhow::Result<Condition> {
+        let mut conditions = vec![self.term()?];
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            conditions.push(self.term()?);
+        }
+        Ok(if conditions.len() == 1 {
+            conditions.remove(0)
+        } else {
+            Condition::And(conditions)
+        })
+    }
+
+    fn term(&mut self) -> anyhow::Result<Condition> {
+        let condition = if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let condition = self.or()?;
+            match self.next()? {
+                Token::RParen => condition,
+                t => anyhow::bail!("expected ')' but got {:?} in '{}'", t, self.source),
+            }
+        } else {
+            let a = self.operand()?;
+            let cmp = match self.next()? {
+                Token::Cmp(cmp) => cmp,
+                t => anyhow::bail!("expected a comparison but got {:?} in '{}'", t, self.source),
+            };
+            Condition::Compare(a, cmp, self.operand()?)
+        };
+        if self.peek() != Some(&Token::For) {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let n = match self.next()? {
+            Token::Number(n) if n >= 1.0 && n.fract() == 0.0 => n as usize,
+            t => anyhow::bail!("expected a bar count but got {:?} in '{}'", t, self.source),
+        };
+        if self.peek() == Some(&Token::Bars) {
+            self.pos += 1;
+        }
+        Ok(Condition::For(Box::new(condition), n))
+    }
+
+    fn operand(&mut self) -> anyhow::Result<Operand> {
+        match self.next()? {
+            Token::Indicator(i) => Ok(Operand::Indicator(i)),
+            Token::Number(n) => Ok(Operand::Number(n)),
+            t => anyhow::bail!(
+                "expected an indicator or number but got {:?} in '{}'",
+                t,
+                self.source
+            ),
+        }
+    }
+}
+
+impl std::str::FromStr for Condition {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser {
+            source: s,
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let condition = parser.or()?;
+        if let Some(t) = parser.peek() {
+            anyhow::bail!("unexpected {:?} in '{}'", t, s);
+        }
+        Ok(condition)
+    }
+}
+
+impl Screen {
+    pub fn parse(name: &str, toml_str: &str) -> anyhow::Result<Self> {
+        let file: ScreenFile = toml::from_str(toml_str)?;
+        let columns = file
+            .columns
+            .iter()
+            .map(|c| c.parse())
+            .collect::<anyhow::Result<Vec<Indicator>>>()?;
+        let signals = file
+            .signals
+            .into_iter()
+            .map(|s| {
+                let when = s
+                    .when
+                    .parse()
+                    .with_context(|| format!("signal '{}'", s.name))?;
+                Ok(Signal {
+                    name: s.name,
+                    side: s.side,
+                    when,
+                })
+            })
+            .collect::<anyhow::Result<Vec<Signal>>>()?;
+        if signals.is_empty() {
+            anyhow::bail!("{} has no [[signal]]s", name);
+        }
+        let filter = match file.filter {
+            Some(filter) => Some(filter.parse().context("filter")?),
+            None => None,
+        };
+        Ok(Screen {
+            name: file.name.unwrap_or_else(|| name.to_string()),
+            filter,
+            columns,
+            signals,
+        })
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Screen::parse(&path.display().to_string(), &contents)
+            .with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Require `condition` on top of any `filter` the screen already has
+    pub fn add_filter(&mut self, condition: Condition) {
+        self.filter = Some(match self.filter.take() {
+            Some(Condition::And(mut conditions)) => {
+                conditions.push(condition);
+                Condition::And(conditions)
+            }
+            Some(filter) => Condition::And(vec![filter, condition]),
+            None => condition,
+        });
+    }
+
+    /// Every indicator the screen needs computed, signals first
+    pub fn indicators(&self) -> Vec<Indicator> {
+        let mut indicators = vec![];
+        if let Some(filter) = self.filter.as_ref() {
+            filter.indicators(&mut indicators);
+        }
+        for signal in self.signals.iter() {
+            signal.when.indicators(&mut indicators);
+        }
+        for column in self.columns.iter() {
+            if !indicators.contains(column) {
+                indicators.push(*column);
+            }
+        }
+        indicators
+    }
+
+    /// The first signal that holds at candle `idx`, if the filter does
+    pub fn signal_at(&self, indicators: &Indicators, idx: usize) -> Option<&Signal> {
+        if let Some(filter) = self.filter.as_ref() {
+            if !filter.eval(indicators, idx) {
+                return None;
+            }
+        }
+        self.signals.iter().find(|s| s.when.eval(indicators, idx))
+    }
+
+    /// The first signal that holds on the last of `rows`, along with the column values there
+    pub fn evaluate(&self, rows: &[QuoteRow]) -> Option<(&Signal, Vec<Option<f64>>)> {
+        let idx = rows.len().checked_sub(1)?;
+        let indicators = Indicators::compute(rows, &self.indicators());
+        let signal = self.signal_at(&indicators, idx)?;
+        let columns = self
+            .columns
+            .iter()
+            .map(|c| indicators.value(c, idx))
+            .collect();
+        Some((signal, columns))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::quote::Quote;
+
+    fn rows(closes: &[f64]) -> Vec<QuoteRow> {
+        closes
+            .iter()
+            .enumerate()
+            .map(|(id, close)| QuoteRow {
+                id: id as i32,
+                quote: Quote {
+                    close: *close,
+                    high: close + 1.0,
+                    low: close - 1.0,
+                    ..Quote::default()
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_condition() {
+        let c: Condition = "ema(8) > ema(21) and close >= 10 or rsi(2) < 5"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            c,
+            Condition::Or(vec![
+                Condition::And(vec![
+                    Condition::Compare(
+                        Operand::Indicator(Indicator::Ema(8)),
+                        Cmp::Gt,
+                        Operand::Indicator(Indicator::Ema(21))
+                    ),
+                    Condition::Compare(
+                        Operand::Indicator(Indicator::Close),
+                        Cmp::Ge,
+                        Operand::Number(10.0)
+                    ),
+                ]),
+                Condition::Compare(
+                    Operand::Indicator(Indicator::Rsi(2)),
+                    Cmp::Lt,
+                    Operand::Number(5.0)
+                ),
+            ])
+        );
+
+        let c: Condition = "(ema(8) > ema(21) and ema(21) > ema(34)) for 42 bars"
+            .parse()
+            .unwrap();
+        assert!(matches!(c, Condition::For(_, 42)));
+        let c: Condition = "slow_stoch(8, 3, 3) < 40 for 1 bar".parse().unwrap();
+        assert_eq!(c.to_string(), "(slow_stoch(8,3,3) < 40) for 1 bars");
+
+        for bad in [
+            "ema(8) >",
+            "ema(8) ema(21)",
+            "(close > 1",
+            "close > 1 for 0 bars",
+            "macd > 1",
+        ] {
+            assert!(bad.parse::<Condition>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_eval() {
+        let rows = rows(&[1.0, 2.0, 3.0, 4.0, 3.0, 2.0]);
+        let c: Condition = "close > sma(2) for 2 bars".parse().unwrap();
+        let mut wanted = vec![];
+        c.indicators(&mut wanted);
+        assert_eq!(wanted, vec![Indicator::Close, Indicator::Sma(2)]);
+        let indicators = Indicators::compute(&rows, &wanted);
+        // sma(2) isn't there for the first candle
+        assert!(!c.eval(&indicators, 1));
+        assert!(c.eval(&indicators, 2));
+        assert!(c.eval(&indicators, 3));
+        assert!(!c.eval(&indicators, 4));
+    }
+
+    #[test]
+    fn test_bounce_screen() {
+        let screen = Screen::parse("bounce.toml", include_str!("../screens/bounce.toml")).unwrap();
+        assert_eq!(screen.name, "Bounce 2.0");
+        assert_eq!(screen.signals.len(), 4);
+        assert_eq!(screen.signals[1].side, Side::Short);
+        assert_eq!(screen.signals[2].side, Side::Long);
+
+        let screen = Screen::parse(
+            "up.toml",
+            r#"
+            columns = ["close"]
+            [[signal]]
+            name = "up"
+            when = "close > sma(3) for 3 bars"
+            "#,
+        )
+        .unwrap();
+        let (signal, columns) = screen.evaluate(&rows(&[1.0, 2.0, 3.0, 4.0, 5.0])).unwrap();
+        assert_eq!(signal.name, "up");
+        assert_eq!(columns, vec![Some(5.0)]);
+        assert!(screen.evaluate(&rows(&[1.0, 2.0, 3.0, 4.0, 1.0])).is_none());
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut screen = Screen::parse(
+            "up.toml",
+            r#"
+            filter = "close >= 5"
+            [[signal]]
+            name = "up"
+            when = "close > sma(3)"
+            "#,
+        )
+        .unwrap();
+        let up = rows(&[1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert!(screen.evaluate(&up).is_some());
+        assert!(screen.evaluate(&up[..4]).is_none());
+
+        screen.add_filter("dollar_volume(2) > 0".parse().unwrap());
+        assert!(screen.indicators().contains(&Indicator::DollarVolume(2)));
+        // no volume at all
+        assert!(screen.evaluate(&up).is_none());
+    }
+}
@@ -0,0 +1,114 @@
+use chrono::prelude::*;
+use chrono_tz::America::New_York;
+
+use crate::bars::BarSize;
+use crate::calendar;
+use crate::db::QuoteRow;
+use crate::indicators::{Indicator, Indicators};
+use crate::output::{Field, Record};
+
+/// What `show` prints without any `--indicator`: the columns of the old metric tables
+pub const DEFAULT_INDICATORS: [Indicator; 6] = [
+    Indicator::Ema(8),
+    Indicator::Ema(21),
+    Indicator::Ema(34),
+    Indicator::Ema(89),
+    Indicator::Sma(50),
+    Indicator::Sma(200),
+];
+
+/// The session date of daily candles, New York time of intraday ones
+fn time(timestamp: i64, bar_size: BarSize) -> String {
+    if bar_size.is_intraday() {
+        let time = New_York.timestamp(timestamp, 0);
+        time.format("%Y-%m-%d %H:%M").to_string()
+    } else {
+        calendar::ny_date(timestamp).to_string()
+    }
+}
+
+/// The fields of `records`, in order
+pub fn columns(indicators: &[Indicator]) -> Vec<String> {
+    let mut columns: Vec<String> = ["time", "open", "high", "low", "close", "volume"]
+        .map(String::from)
+        .to_vec();
+    columns.extend(indicators.iter().map(|i| i.to_string()));
+    columns
+}
+
+/// One record per candle of `rows` (oldest first) keyed at or after `start`, with OHLCV and
+/// `indicators`. Earlier rows only warm the indicators up.
+pub fn records(
+    ticker: &str,
+    rows: &[QuoteRow],
+    bar_size: BarSize,
+    indicators: &[Indicator],
+    start: Option<i64>,
+) -> Vec<Record> {
+    let columns = columns(indicators);
+    let computed = Indicators::compute(rows, indicators);
+    let first = start.map_or(0, |start| {
+        rows.partition_point(|row| row.quote.timestamp < start)
+    });
+    (first..rows.len())
+        .map(|i| {
+            let q = &rows[i].quote;
+            let mut values = vec![
+                Field::Text(time(q.timestamp, bar_size)),
+                Field::Number(Some(q.open)),
+                Field::Number(Some(q.high)),
+                Field::Number(Some(q.low)),
+                Field::Number(Some(q.close)),
+                Field::Number(Some(q.volume as f64)),
+            ];
+            for indicator in indicators {
+                values.push(Field::Number(computed.value(indicator, i)));
+            }
+            Record {
+                ticker: ticker.to_string(),
+                section: ticker.to_string(),
+                fields: columns.iter().cloned().zip(values).collect(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::{self, Format};
+    use crate::quote::Quote;
+
+    #[test]
+    fn test_records() {
+        let ymd = NaiveDate::from_ymd;
+        let rows: Vec<QuoteRow> = [(21, 10.0), (22, 11.0), (23, 12.0), (24, 13.0)]
+            .iter()
+            .map(|(day, close)| QuoteRow {
+                id: 0,
+                quote: Quote {
+                    timestamp: calendar::close_timestamp(ymd(2022, 6, *day)),
+                    open: *close,
+                    high: close + 1.0,
+                    low: close - 1.0,
+                    close: *close,
+                    volume: 100,
+                    ..Quote::default()
+                },
+            })
+            .collect();
+        let (start, _) = calendar::day_bounds(ymd(2022, 6, 23));
+        let indicators = [Indicator::Sma(3)];
+        let records = records("AAPL", &rows, BarSize::Day1, &indicators, Some(start));
+        let mut out = vec![];
+        output::write(&mut out, Format::Tsv, &columns(&indicators), &records).unwrap();
+        // the SMA warmed up on the days before --from
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "ticker\ttime\topen\thigh\tlow\tclose\tvolume\tsma(3)\n\
+             AAPL\t2022-06-23\t12\t13\t11\t12\t100\t11\n\
+             AAPL\t2022-06-24\t13\t14\t12\t13\t100\t12\n"
+        );
+        assert_eq!(time(1656077400, BarSize::Hour1), "2022-06-24 09:30");
+    }
+}
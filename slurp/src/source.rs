@@ -0,0 +1,41 @@
+use std::str::FromStr;
+
+/// Anything that can fill the `daily` table for a queue of tickers - i.e. the IBKR `App` or
+/// Yahoo Finance
+pub trait QuoteSource {
+    /// Queue a ticker for a full (2 year) backfill
+    fn add_ticker_to_request_queue(&mut self, ticker: String);
+
+    /// Queue a ticker to only append the candles since the last row we have for it
+    fn add_incremental_ticker(&mut self, ticker: String);
+
+    /// Drain both queues, inserting everything fetched into the DB
+    fn run(&mut self) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Ibkr,
+    Yahoo,
+}
+
+impl Source {
+    /// How the source is recorded in the DB (i.e. `fetch_failures.source`)
+    pub fn name(&self) -> &'static str {
+        match self {
+            Source::Ibkr => "ibkr",
+            Source::Yahoo => "yahoo",
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "ibkr" | "ib" => Ok(Source::Ibkr),
+            "yahoo" => Ok(Source::Yahoo),
+            _ => Err(anyhow::anyhow!("unknown quote source '{}' (ibkr|yahoo)", s)),
+        }
+    }
+}
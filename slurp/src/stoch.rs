@@ -94,15 +94,6 @@ pub fn get_adxs(quotes: &[Quote], period: usize) -> Vec<f64> {
         .collect()
 }
 
-pub fn get_adxr(quotes: &[Quote], dilen: usize, adxlen: usize) -> f64 {
-    if quotes.len() < dilen {
-        return -1.0;
-    }
-    let adxs = get_adxs(quotes, dilen);
-    let rma_adxs = get_rmas(&adxs, adxlen);
-    rma_adxs.last().map(|adx: &f64| adx * 100.0).unwrap_or(-1.0)
-}
-
 pub fn get_rsis(quotes: &[Quote], period: usize) -> Vec<f64> {
     // up = ta.rma(math.max(ta.change(close), 0), period)
     // down = ta.rma(-math.min(ta.change(close), 0), period)
@@ -129,10 +120,6 @@ pub fn get_rsis(quotes: &[Quote], period: usize) -> Vec<f64> {
         .collect()
 }
 
-pub fn get_last_rsi(quotes: &[Quote], period: usize) -> f64 {
-    *get_rsis(quotes, period).last().unwrap()
-}
-
 pub fn get_stochastics(quotes: &[QuoteRow], k_len: usize) -> Vec<f64> {
     let mut stochs = Vec::with_capacity(quotes.len() - k_len + 1);
     for (idx, row) in quotes[(k_len - 1)..].iter().enumerate() {
@@ -151,17 +138,18 @@ pub fn get_stochastics(quotes: &[QuoteRow], k_len: usize) -> Vec<f64> {
     stochs
 }
 
-pub fn get_slow_stoch(k_len: usize, k_smooth: usize, d_smooth: usize, quotes: &[QuoteRow]) -> f64 {
-    let stochs = get_stochastics(quotes, k_len);
-    let ks = get_smas(&stochs, k_smooth);
-    let ds = get_smas(&ks, d_smooth);
-    return ds[ds.len() - 1];
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_moving_avgs() {
+        let vals = [2.0, 3.0, 4.0, 5.5, 6.0, 7.0];
+        let smas = get_smas(&vals, 3);
+        assert_eq!(smas, vec![3.0, 12.5 / 3.0, 15.5 / 3.0, 18.5 / 3.0]);
+        assert!(get_smas(&vals[..2], 3).is_empty());
+    }
+
     #[test]
     fn test_relative_moving_avgs() {
         let vals = [1.0, 2.0, 3.0, 4.0, 5.0];
@@ -0,0 +1,159 @@
+use chrono::NaiveDate;
+use ibtwsapi::core::common::{RealTimeBar, TickType};
+
+use crate::calendar;
+use crate::quote::Quote;
+
+/// IB reports the day's volume of US stocks in round lots
+const VOLUME_MULTIPLIER: i64 = 100;
+
+/// The in-progress daily candle of a streamed ticker, built from market data ticks (live or
+/// delayed) and any 5 second real-time bars. Keyed at the session's close like completed candles.
+#[derive(Debug, Clone)]
+pub struct LiveBar {
+    pub quote: Quote,
+    changed: bool,
+}
+
+impl LiveBar {
+    pub fn new(session: NaiveDate) -> Self {
+        LiveBar::resume(Quote {
+            timestamp: calendar::close_timestamp(session),
+            ..Quote::default()
+        })
+    }
+
+    /// Pick up a bar persisted earlier in the session, i.e. before a restart
+    pub fn resume(quote: Quote) -> Self {
+        LiveBar {
+            quote,
+            changed: false,
+        }
+    }
+
+    /// Stretch the range over a traded price
+    fn trade(&mut self, price: f64) {
+        let q = &mut self.quote;
+        if q.open == 0.0 {
+            q.open = price;
+        }
+        q.high = q.high.max(price);
+        q.low = if q.low == 0.0 {
+            price
+        } else {
+            q.low.min(price)
+        };
+        q.close = price;
+        q.adjclose = price;
+    }
+
+    pub fn tick_price(&mut self, tick_type: TickType, price: f64) {
+        // -1 when IB has no price for the tick yet
+        if price <= 0.0 {
+            return;
+        }
+        let q = &mut self.quote;
+        match tick_type {
+            TickType::Last | TickType::DelayedLast => self.trade(price),
+            TickType::Open | TickType::DelayedOpen => {
+                q.open = price;
+                q.high = q.high.max(price);
+                q.low = if q.low == 0.0 {
+                    price
+                } else {
+                    q.low.min(price)
+                };
+            }
+            TickType::High | TickType::DelayedHigh => q.high = price,
+            TickType::Low | TickType::DelayedLow => q.low = price,
+            // the previous session's close, the bid, the ask...
+            _ => return,
+        }
+        self.changed = true;
+    }
+
+    pub fn tick_size(&mut self, tick_type: TickType, size: i32) {
+        if matches!(tick_type, TickType::Volume | TickType::DelayedVolume) && size >= 0 {
+            self.quote.volume = size as i64 * VOLUME_MULTIPLIER;
+            self.changed = true;
+        }
+    }
+
+    /// Volume still comes from the ticks, which count the whole day rather than just the bars
+    /// since we subscribed
+    pub fn real_time_bar(&mut self, bar: &RealTimeBar) {
+        if bar.close <= 0.0 {
+            return;
+        }
+        let q = &mut self.quote;
+        if q.open == 0.0 {
+            q.open = bar.open;
+        }
+        q.high = q.high.max(bar.high);
+        q.low = if q.low == 0.0 {
+            bar.low
+        } else {
+            q.low.min(bar.low)
+        };
+        self.trade(bar.close);
+        self.changed = true;
+    }
+
+    /// Whether there's been a trade to build a candle from
+    pub fn is_ready(&self) -> bool {
+        self.quote.open > 0.0 && self.quote.close > 0.0
+    }
+
+    /// Whether the bar changed since the last call
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_live_bar() {
+        let session = NaiveDate::from_ymd(2022, 6, 24);
+        let mut bar = LiveBar::new(session);
+        bar.tick_price(TickType::DelayedClose, 140.0);
+        bar.tick_price(TickType::DelayedLast, -1.0);
+        assert!(!bar.take_changed());
+        assert!(!bar.is_ready());
+
+        bar.tick_price(TickType::DelayedOpen, 142.0);
+        bar.tick_price(TickType::DelayedHigh, 143.5);
+        bar.tick_price(TickType::DelayedLow, 141.0);
+        bar.tick_price(TickType::DelayedLast, 143.0);
+        bar.tick_size(TickType::DelayedVolume, 1200);
+        assert!(bar.take_changed());
+        assert!(bar.is_ready());
+        // a print outside the range IB last reported widens it
+        bar.tick_price(TickType::Last, 144.0);
+        let bars = [
+            (144.0, 145.0, 143.5, 144.5, 3),
+            (144.5, 144.6, 140.5, 141.0, 2),
+        ];
+        for (open, high, low, close, volume) in bars {
+            bar.real_time_bar(&RealTimeBar {
+                open,
+                high,
+                low,
+                close,
+                volume,
+                ..RealTimeBar::default()
+            });
+        }
+        let q = &bar.quote;
+        assert_eq!(q.timestamp, calendar::close_timestamp(session));
+        assert_eq!(
+            (q.open, q.high, q.low, q.close),
+            (142.0, 145.0, 140.5, 141.0)
+        );
+        assert_eq!(q.volume, 120_000);
+        assert!(bar.take_changed());
+        assert!(!bar.take_changed());
+    }
+}
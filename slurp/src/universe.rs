@@ -0,0 +1,199 @@
+use std::collections::{HashMap, HashSet};
+
+/// Vendor whose symbols `universe import` expects unless told otherwise
+pub const DEFAULT_VENDOR: &str = "companiesmarketcap";
+
+/// Renames and exclusions we know `DEFAULT_VENDOR` needs, as (vendor symbol, IB ticker). A
+/// `None` ticker means IB doesn't have it.
+pub const DEFAULT_RULES: [(&str, Option<&str>); 2] = [("ACC", None), ("TVTY", Some("NLSN"))];
+
+/// `vendor`'s rules: the defaults we know of, overridden by any `stored` with `universe map`
+pub fn rules(
+    vendor: &str,
+    stored: HashMap<String, Option<String>>,
+) -> HashMap<String, Option<String>> {
+    let mut rules: HashMap<String, Option<String>> = HashMap::new();
+    if vendor == DEFAULT_VENDOR {
+        rules.extend(
+            DEFAULT_RULES
+                .iter()
+                .map(|(symbol, ticker)| (symbol.to_string(), ticker.map(str::to_string))),
+        );
+    }
+    rules.extend(stored);
+    rules
+}
+
+/// A ticker in a named universe
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub ticker: String,
+    pub market_cap: Option<f64>,
+}
+
+/// Split a CSV line, honoring double quotes (i.e. `1,"Apple, Inc.",AAPL`)
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// (symbol, market cap) rows of either a vendor CSV with `Symbol` and `marketcap` columns (the
+/// companiesmarketcap.com download) or a headerless `ticker\tmarket_cap` TSV like
+/// `ticker_mkt_cap.tsv`
+pub fn parse(contents: &str) -> anyhow::Result<Vec<(String, Option<f64>)>> {
+    let mut lines = contents.lines().filter(|l| !l.trim().is_empty()).peekable();
+    let header = match lines.peek() {
+        Some(first) if !first.contains('\t') => split_csv(first),
+        Some(_) => {
+            return Ok(lines
+                .map(|line| {
+                    let mut cols = line.split('\t');
+                    let symbol = cols.next().unwrap_or_default().trim().to_string();
+                    let cap = cols.next().and_then(|c| c.trim().parse::<f64>().ok());
+                    (symbol, cap)
+                })
+                .collect())
+        }
+        None => return Ok(vec![]),
+    };
+    let column = |names: &[&str]| {
+        header
+            .iter()
+            .position(|h| names.contains(&h.trim().to_lowercase().as_str()))
+    };
+    let symbol_col = column(&["symbol", "ticker"])
+        .ok_or_else(|| anyhow::anyhow!("no Symbol column in {:?}", header))?;
+    let cap_col = column(&["marketcap", "market_cap", "market cap"]);
+    Ok(lines
+        .skip(1)
+        .filter_map(|line| {
+            let fields = split_csv(line);
+            let symbol = fields.get(symbol_col)?.trim().to_string();
+            let cap = cap_col
+                .and_then(|c| fields.get(c))
+                .and_then(|c| c.trim().parse::<f64>().ok());
+            Some((symbol, cap))
+        })
+        .collect())
+}
+
+/// The IB ticker for a vendor's `symbol`, per the vendor's `rules` (`None` to skip it). Share
+/// classes are spelled with a space on IB, i.e. BRK-B or BRK.B => BRK B
+pub fn to_ibkr(symbol: &str, rules: &HashMap<String, Option<String>>) -> Option<String> {
+    if let Some(mapped) = rules.get(symbol) {
+        return mapped.clone();
+    }
+    let symbol = symbol.trim().to_uppercase();
+    if symbol.is_empty() {
+        return None;
+    }
+    Some(symbol.replace(['-', '.'], " "))
+}
+
+/// Members for the parsed `rows`, mapped to IB tickers, bigger than `min_cap` and largest first
+pub fn members(
+    rows: Vec<(String, Option<f64>)>,
+    rules: &HashMap<String, Option<String>>,
+    min_cap: Option<f64>,
+) -> Vec<Member> {
+    let mut members: Vec<Member> = rows
+        .into_iter()
+        .filter(|(_, cap)| match min_cap {
+            Some(min_cap) => matches!(cap, Some(cap) if *cap > min_cap),
+            None => true,
+        })
+        .filter_map(|(symbol, market_cap)| {
+            to_ibkr(&symbol, rules).map(|ticker| Member { ticker, market_cap })
+        })
+        .collect();
+    members.sort_by(|a, b| {
+        b.market_cap
+            .unwrap_or(0.0)
+            .partial_cmp(&a.market_cap.unwrap_or(0.0))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    // two symbols that map to the same IB ticker, i.e. BRK-B and BRK.B, or one `universe map`
+    // points at a ticker that's listed too; keep the first (largest)
+    let mut seen = HashSet::new();
+    members.retain(|m| seen.insert(m.ticker.clone()));
+    members
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn default_rules() -> HashMap<String, Option<String>> {
+        rules(DEFAULT_VENDOR, HashMap::new())
+    }
+
+    #[test]
+    fn test_parse() {
+        let csv = "Rank,Name,Symbol,marketcap,price (USD),country\n\
+                   1,\"Apple, Inc.\",AAPL,2500000000000,150.1,United States\n\
+                   2,Berkshire Hathaway,BRK-B,646575620096,290,United States\n\
+                   3,Tiny Co,TINY,900000000,3,United States\n\
+                   4,ACC Corp,ACC,5000000000,30,United States\n\
+                   5,Nielsen,TVTY,8000000000,22,United States\n";
+        let rows = parse(csv).unwrap();
+        assert_eq!(rows[0], ("AAPL".to_string(), Some(2500000000000.0)));
+        let members = members(rows, &default_rules(), Some(1e9));
+        let tickers: Vec<&str> = members.iter().map(|m| m.ticker.as_str()).collect();
+        assert_eq!(tickers, vec!["AAPL", "BRK B", "NLSN"]);
+
+        let tsv = "MSFT\t2080006078464\nBRK B\t646575620096\n";
+        let rows = parse(tsv).unwrap();
+        assert_eq!(rows[1], ("BRK B".to_string(), Some(646575620096.0)));
+        assert!(parse("Name,Price\nfoo,1\n").is_err());
+
+        // the same share class under both spellings
+        let rows = vec![
+            ("BRK.B".to_string(), Some(600e9)),
+            ("BRK-B".to_string(), Some(646e9)),
+        ];
+        let deduped = super::members(rows, &default_rules(), None);
+        assert_eq!(deduped.len(), 1);
+        assert_eq!(deduped[0].market_cap, Some(646e9));
+
+        // stored rules win over the defaults
+        let stored = [("ACC".to_string(), Some("ACC".to_string()))];
+        let rules = rules(DEFAULT_VENDOR, stored.into_iter().collect());
+        assert_eq!(to_ibkr("ACC", &rules), Some("ACC".to_string()));
+        assert_eq!(to_ibkr("BF.A", &rules), Some("BF A".to_string()));
+    }
+
+    #[test]
+    fn test_min_cap_is_exclusive() {
+        let rows = vec![
+            ("AT".to_string(), Some(1e9)),
+            ("OVER".to_string(), Some(1e9 + 1.0)),
+            ("UNKNOWN".to_string(), None),
+        ];
+        let members = members(rows, &default_rules(), Some(1e9));
+        let tickers: Vec<&str> = members.iter().map(|m| m.ticker.as_str()).collect();
+        assert_eq!(tickers, vec!["OVER"]);
+    }
+
+    #[test]
+    fn test_split_csv() {
+        assert_eq!(
+            split_csv(r#"1,"Say ""hi"", ok",X"#),
+            vec!["1", "Say \"hi\", ok", "X"]
+        );
+    }
+}
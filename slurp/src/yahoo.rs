@@ -0,0 +1,366 @@
+use anyhow::Context;
+use chrono::prelude::*;
+use chrono::Duration;
+use std::collections::VecDeque;
+use yahoo_finance_api::{YResponse, YahooError};
+
+use crate::bars::BarSize;
+use crate::calendar;
+use crate::corporate::{ActionKind, CorporateAction};
+use crate::db::{Db, Range};
+use crate::quote::{daily_close_timestamp, Quote};
+use crate::reconcile::{reconcile, Outcome};
+use crate::source::{QuoteSource, Source};
+
+const YCHART_URL: &str = "https://query1.finance.yahoo.com/v8/finance/chart";
+
+/// Same depth as the "2 Y" IBKR full request
+const FULL_HISTORY_DAYS: i64 = 2 * 365;
+
+/// Fills the `daily` table from Yahoo Finance's chart API. Doesn't need a TWS/Gateway session.
+pub struct YahooSource {
+    pub db: Db,
+    pub force: bool,
+    base_url: String,
+    full_ticker_queue: VecDeque<String>,
+    incremental_ticker_queue: VecDeque<String>,
+}
+
+/// Yahoo answers unknown symbols with a 404 and delisted ones with an empty chart
+fn is_permanent(e: &anyhow::Error) -> bool {
+    let not_found = e.downcast_ref::<reqwest::Error>().and_then(|e| e.status())
+        == Some(reqwest::StatusCode::NOT_FOUND);
+    not_found
+        || matches!(
+            e.downcast_ref::<YahooError>(),
+            Some(YahooError::EmptyDataSet)
+        )
+}
+
+/// IBKR separates share classes with a space ("BRK B") where Yahoo uses a dash ("BRK-B")
+fn yahoo_symbol(ticker: &str) -> String {
+    ticker.replace(' ', "-")
+}
+
+impl YahooSource {
+    pub fn new(db: Db, force: bool) -> Self {
+        YahooSource::with_base_url(db, force, YCHART_URL)
+    }
+
+    /// Point the source at a different chart endpoint (i.e. a local stand-in for tests)
+    pub fn with_base_url(db: Db, force: bool, base_url: &str) -> Self {
+        YahooSource {
+            db,
+            force,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            full_ticker_queue: VecDeque::new(),
+            incremental_ticker_queue: VecDeque::new(),
+        }
+    }
+
+    /// Daily candles in [start, end], skipping any candle whose session hasn't closed yet, along
+    /// with the splits and dividends that went ex in that range
+    pub fn fetch(
+        &self,
+        ticker: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<(Vec<Quote>, Vec<CorporateAction>)> {
+        let url = format!(
+            "{}/{}?period1={}&period2={}&interval=1d&events=div|split",
+            self.base_url,
+            yahoo_symbol(ticker),
+            start.timestamp(),
+            end.timestamp(),
+        );
+        let json: serde_json::Value = reqwest::blocking::get(&url)
+            .and_then(|response| response.error_for_status()?.json())
+            .with_context(|| format!("fetching {}", url))?;
+        let response = YResponse::from_json(json)?;
+        let now = Utc::now().timestamp();
+        let quotes = response
+            .quotes()?
+            .into_iter()
+            .map(Quote::from)
+            .filter(|q| q.timestamp <= now)
+            .collect();
+        let splits = response.splits()?.into_iter().map(|s| CorporateAction {
+            timestamp: daily_close_timestamp(s.date as i64),
+            kind: ActionKind::Split,
+            value: s.numerator as f64 / s.denominator as f64,
+        });
+        let dividends = response.dividends()?.into_iter().map(|d| CorporateAction {
+            timestamp: daily_close_timestamp(d.date as i64),
+            kind: ActionKind::Dividend,
+            value: d.amount,
+        });
+        Ok((quotes, splits.chain(dividends).collect()))
+    }
+
+    fn fetch_full(&mut self, ticker: &str) -> anyhow::Result<()> {
+        let end = Utc::now();
+        let (quotes, actions) = self.fetch(ticker, end - Duration::days(FULL_HISTORY_DAYS), end)?;
+        eprintln!("{} - {} quotes", ticker, quotes.len());
+        self.db.replace_daily_quotes(ticker, &quotes)?;
+        self.db.clear_refetch(ticker)?;
+        self.db
+            .insert_corporate_actions(ticker, Source::Yahoo.name(), &actions)?;
+        self.calculate_metrics(ticker);
+        self.db.clear_fetch_failure(ticker, Source::Yahoo.name())
+    }
+
+    fn calculate_metrics(&mut self, ticker: &str) {
+        if let Err(e) = self.db.calculate_and_insert_metrics(ticker) {
+            eprintln!("failed to calculate metrics for {}: {}", ticker, e);
+        }
+    }
+
+    fn record_failure(&self, ticker: &str, e: &anyhow::Error) {
+        eprintln!("{} => {:#}", ticker, e);
+        let recorded = self.db.record_fetch_failure(
+            ticker,
+            Source::Yahoo.name(),
+            None,
+            &format!("{:#}", e),
+            is_permanent(e),
+        );
+        if let Err(e) = recorded {
+            eprintln!("failed to record failure for {}: {}", ticker, e);
+        }
+    }
+
+    fn fetch_incremental(&mut self, ticker: &str) -> anyhow::Result<()> {
+        let last_quote = self.db.get_last_quote(ticker)?;
+        let session = calendar::last_completed_session(Utc::now());
+        let last_date = calendar::ny_date(last_quote.quote.timestamp);
+        let last = Utc.timestamp(last_quote.quote.timestamp, 0);
+        if calendar::sessions_between(last_date, session) == 0 && !self.force {
+            eprintln!("skipping up-to-date {}", ticker);
+            return Ok(());
+        }
+        // Re-fetch the last candle we have so we can check it against the cache
+        let (quotes, actions) = self.fetch(ticker, last - Duration::days(1), Utc::now())?;
+        let cached: Vec<Quote> = match (quotes.first(), quotes.last()) {
+            (Some(first), Some(last)) => self
+                .db
+                .get_candles(
+                    ticker,
+                    BarSize::Day1,
+                    Range::between(first.timestamp, last.timestamp),
+                )?
+                .into_iter()
+                .map(|row| row.quote)
+                .collect(),
+            _ => vec![],
+        };
+        let outcome = reconcile(&cached, &quotes);
+        eprintln!("{}: {} ({})", ticker, outcome.name(), outcome.detail());
+        self.db
+            .record_reconciliation(ticker, Source::Yahoo.name(), &outcome)?;
+        match outcome {
+            Outcome::Verified { last, .. } => {
+                eprintln!("{} - {} quotes", ticker, quotes.len());
+                // a dividend since the last candle lowers every adjusted close before it
+                if let Some(q) = quotes.iter().find(|q| q.timestamp == last) {
+                    self.db.rescale_adjcloses(ticker, q.timestamp, q.adjclose)?;
+                }
+                self.db.insert_daily_quotes(ticker, &quotes)?;
+                self.db
+                    .insert_corporate_actions(ticker, Source::Yahoo.name(), &actions)?;
+                self.calculate_metrics(ticker);
+                self.db.clear_fetch_failure(ticker, Source::Yahoo.name())
+            }
+            // the split itself comes back with the full history
+            Outcome::Mismatch { .. } | Outcome::NoOverlap => self.fetch_full(ticker),
+        }
+    }
+}
+
+impl QuoteSource for YahooSource {
+    fn add_ticker_to_request_queue(&mut self, ticker: String) {
+        self.full_ticker_queue.push_back(ticker);
+    }
+
+    fn add_incremental_ticker(&mut self, ticker: String) {
+        self.incremental_ticker_queue.push_back(ticker);
+    }
+
+    fn run(&mut self) -> anyhow::Result<()> {
+        while let Some(ticker) = self.full_ticker_queue.pop_front() {
+            eprintln!("requesting {}", ticker);
+            if let Err(e) = self.fetch_full(&ticker) {
+                self.record_failure(&ticker, &e);
+            }
+        }
+        while let Some(ticker) = self.incremental_ticker_queue.pop_front() {
+            if let Err(e) = self.fetch_incremental(&ticker) {
+                self.record_failure(&ticker, &e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    /// 9:30AM EDT, which is how Yahoo stamps daily candles
+    fn open_ts(day: u32) -> i64 {
+        Utc.ymd(2022, 6, day).and_hms(13, 30, 0).timestamp()
+    }
+
+    fn chart(days: &[u32], closes: &[f64]) -> serde_json::Value {
+        let period = json!({"timezone": "EDT", "start": 0, "end": 0, "gmtoffset": -14400});
+        json!({
+            "chart": {
+                "result": [{
+                    "meta": {
+                        "currency": "USD",
+                        "symbol": "BRK-B",
+                        "exchangeName": "NYQ",
+                        "instrumentType": "EQUITY",
+                        "firstTradeDate": 0,
+                        "regularMarketTime": 0,
+                        "gmtoffset": -14400,
+                        "timezone": "EDT",
+                        "exchangeTimezoneName": "America/New_York",
+                        "regularMarketPrice": 0.0,
+                        "chartPreviousClose": 0.0,
+                        "priceHint": 2,
+                        "currentTradingPeriod": {"pre": period, "regular": period, "post": period},
+                        "dataGranularity": "1d",
+                        "range": "",
+                        "validRanges": ["1d"]
+                    },
+                    "timestamp": days.iter().map(|d| open_ts(*d)).collect::<Vec<i64>>(),
+                    "indicators": {
+                        "quote": [{
+                            "open": closes,
+                            "high": closes.iter().map(|c| c + 1.0).collect::<Vec<f64>>(),
+                            "low": closes.iter().map(|c| c - 1.0).collect::<Vec<f64>>(),
+                            "close": closes,
+                            "volume": closes.iter().map(|_| 1000).collect::<Vec<u64>>()
+                        }],
+                        "adjclose": [{"adjclose": closes}]
+                    }
+                }],
+                "error": null
+            }
+        })
+    }
+
+    /// Minimal HTTP stand-in for the chart endpoint. Answers each connection with the next body
+    /// and hands back the request lines it saw.
+    fn serve(bodies: Vec<serde_json::Value>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/chart", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut request_lines = vec![];
+            for body in bodies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                request_lines.push(line.trim().to_string());
+                while line != "\r\n" {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                }
+                let body = body.to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+            request_lines
+        });
+        (url, handle)
+    }
+
+    fn closes(db: &Db, ticker: &str) -> Vec<(i64, f64)> {
+        db.get_candles(ticker, BarSize::Day1, Range::default())
+            .unwrap()
+            .into_iter()
+            .map(|row| (row.quote.timestamp, row.quote.close))
+            .collect()
+    }
+
+    #[test]
+    fn test_full_backfill() {
+        let (url, server) = serve(vec![chart(&[21, 22, 23], &[10.0, 11.0, 12.0])]);
+        let db = Db::init(Some(PathBuf::from(":memory:"))).unwrap();
+        let mut source = YahooSource::with_base_url(db, false, &url);
+        source.add_ticker_to_request_queue("BRK B".to_string());
+        source.run().unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(
+            requests[0].starts_with("GET /chart/BRK-B?"),
+            "{}",
+            requests[0]
+        );
+        // candles are re-keyed to the 4PM EDT close, same as IBKR's
+        assert_eq!(
+            closes(&source.db, "BRK B"),
+            vec![
+                (open_ts(21) + 6 * 3600 + 1800, 10.0),
+                (open_ts(22) + 6 * 3600 + 1800, 11.0),
+                (open_ts(23) + 6 * 3600 + 1800, 12.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_incremental_checks_cached_close() {
+        let (url, server) = serve(vec![
+            chart(&[22, 23, 24], &[11.0, 12.0, 13.0]),
+            chart(&[22, 23, 24], &[20.0, 21.0, 22.0]),
+            chart(&[21, 22, 23, 24], &[19.0, 20.0, 21.0, 22.0]),
+        ]);
+        let db = Db::init(Some(PathBuf::from(":memory:"))).unwrap();
+        let mut source = YahooSource::with_base_url(db, false, &url);
+        let seed = Quote {
+            timestamp: daily_close(22),
+            close: 11.0,
+            ..Quote::default()
+        };
+        source.db.insert_daily_quotes("AAPL", &[seed]).unwrap();
+
+        // cached close matches => append
+        source.add_incremental_ticker("AAPL".to_string());
+        source.run().unwrap();
+        assert_eq!(
+            closes(&source.db, "AAPL"),
+            vec![
+                (daily_close(22), 11.0),
+                (daily_close(23), 12.0),
+                (daily_close(24), 13.0)
+            ]
+        );
+
+        // cached close changed (i.e. split) => full re-fetch
+        let seed = Quote {
+            timestamp: daily_close(24),
+            close: 13.0,
+            ..Quote::default()
+        };
+        source.db.insert_daily_quotes("MSFT", &[seed]).unwrap();
+        source.add_incremental_ticker("MSFT".to_string());
+        source.run().unwrap();
+        assert_eq!(server.join().unwrap().len(), 3);
+        assert_eq!(closes(&source.db, "MSFT").len(), 4);
+    }
+
+    fn daily_close(day: u32) -> i64 {
+        crate::quote::daily_close_timestamp(open_ts(day))
+    }
+}